@@ -0,0 +1,114 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// the volume envelope of the square and noise channels, set by NRx2
+#[derive(Default)]
+pub(super) struct Envelope {
+    /// NRx2 as written
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub(super) fn rb(&self) -> u8 {
+        self.register
+    }
+
+    pub(super) fn wb(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    /// the channel DAC is off when the initial volume is 0 and the envelope goes down
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    /// clocked at 64 Hz by the frame sequencer
+    pub(super) fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.register & 0x08 != 0 {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_tests() {
+        let mut envelope = Envelope::default();
+        envelope.wb(0xF2);
+        envelope.trigger();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 14);
+        for _ in 0..40 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume(), 0);
+
+        // going up stops at 15, a period of 0 freezes the volume
+        envelope.wb(0xE9);
+        envelope.trigger();
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.wb(0x58);
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 5);
+    }
+
+    #[test]
+    fn dac_tests() {
+        let mut envelope = Envelope::default();
+        assert!(!envelope.dac_enabled());
+        envelope.wb(0x08);
+        assert!(envelope.dac_enabled());
+        envelope.wb(0x10);
+        assert!(envelope.dac_enabled());
+        envelope.wb(0x07);
+        assert!(!envelope.dac_enabled());
+    }
+}
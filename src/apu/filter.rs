@@ -0,0 +1,75 @@
+use crate::cpu::CLOCK_SPEED;
+
+/// the capacitors between the mixer and the headphone jack, which remove the DC offset of the
+/// channel DACs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HighPass {
+    Off,
+    Dmg,
+    /// the CGB capacitors, which discharge faster
+    Cgb,
+}
+
+impl HighPass {
+    /// the charge left after an output sample at `sample_rate`
+    fn charge_factor(self, sample_rate: u32) -> f32 {
+        let per_cycle: f64 = match self {
+            HighPass::Off => return 1.0,
+            HighPass::Dmg => 0.999958,
+            HighPass::Cgb => 0.998943,
+        };
+        per_cycle.powf(CLOCK_SPEED as f64 / sample_rate as f64) as f32
+    }
+}
+
+/// one side of the high-pass filter
+pub(super) struct Capacitor {
+    charge: f32,
+    factor: f32,
+    enabled: bool,
+}
+
+impl Capacitor {
+    pub(super) fn new(high_pass: HighPass, sample_rate: u32) -> Self {
+        Self {
+            charge: 0.0,
+            factor: high_pass.charge_factor(sample_rate),
+            enabled: high_pass != HighPass::Off,
+        }
+    }
+
+    pub(super) fn filter(&mut self, input: f32) -> f32 {
+        if !self.enabled {
+            return input;
+        }
+        let output = input - self.charge;
+        self.charge = input - output * self.factor;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_pass_tests() {
+        // a constant level fades away, faster on CGB
+        let mut dmg = Capacitor::new(HighPass::Dmg, 48000);
+        let mut cgb = Capacitor::new(HighPass::Cgb, 48000);
+        assert_eq!(dmg.filter(1.0), 1.0);
+        assert_eq!(cgb.filter(1.0), 1.0);
+        for _ in 0..100 {
+            dmg.filter(1.0);
+            cgb.filter(1.0);
+        }
+        let (dmg, cgb) = (dmg.filter(1.0), cgb.filter(1.0));
+        assert!(dmg > 0.5 && dmg < 0.9);
+        assert!(cgb.abs() < 0.01);
+
+        let mut off = Capacitor::new(HighPass::Off, 48000);
+        for _ in 0..4800 {
+            assert_eq!(off.filter(1.0), 1.0);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// the length counter of a channel, which turns the channel off once it reaches 0 when enabled
+pub(super) struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+    /// 64, or 256 for the wave channel
+    max: u16,
+}
+
+impl LengthCounter {
+    pub(super) fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            enabled: false,
+            max,
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// the length field of NRx1 (NR31 for the wave channel)
+    pub(super) fn set_length(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// clocked at 256 Hz by the frame sequencer, returns false once the channel must be turned
+    /// off
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter > 0;
+        }
+        true
+    }
+
+    /// a write to NRx4. `extra_clock` tells if the next step of the frame sequencer will not
+    /// clock the length: enabling the counter then clocks it once right away. Returns false if
+    /// the channel must be turned off.
+    pub(super) fn write_control(
+        &mut self,
+        enabled: bool,
+        trigger: bool,
+        extra_clock: bool,
+    ) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        let mut alive = true;
+        if extra_clock && enabled && !was_enabled && self.counter > 0 {
+            self.counter -= 1;
+            alive = self.counter > 0;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if extra_clock && enabled {
+                self.counter -= 1;
+            }
+            alive = true;
+        }
+        alive
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_tests() {
+        let mut length = LengthCounter::new(64);
+        length.set_length(62);
+        assert!(length.write_control(true, false, false));
+        assert!(length.clock());
+        assert!(!length.clock());
+
+        // triggering with an empty counter reloads it with the maximum
+        assert!(length.write_control(true, true, false));
+        for _ in 0..63 {
+            assert!(length.clock());
+        }
+        assert!(!length.clock());
+
+        // disabled, the counter does not move
+        length.set_length(63);
+        length.write_control(false, false, false);
+        for _ in 0..10 {
+            assert!(length.clock());
+        }
+    }
+
+    #[test]
+    fn extra_clock_tests() {
+        let mut length = LengthCounter::new(64);
+        length.set_length(62);
+        assert!(length.write_control(true, false, true));
+        assert!(length.write_control(false, false, true));
+        assert!(!length.write_control(true, false, true));
+
+        // an empty counter reloaded by a trigger loses one
+        let mut length = LengthCounter::new(256);
+        assert!(length.write_control(true, true, true));
+        for _ in 0..254 {
+            assert!(length.clock());
+        }
+        assert!(!length.clock());
+    }
+}
@@ -0,0 +1,608 @@
+mod envelope;
+mod filter;
+mod length;
+mod noise;
+mod recording;
+mod resampler;
+mod square;
+mod wav;
+mod wave;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use filter::Capacitor;
+pub use filter::HighPass;
+use noise::Noise;
+pub use recording::Recording;
+use resampler::Resampler;
+use square::Square;
+use wave::Wave;
+
+/// rate of the samples handed out until told otherwise
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// a channel of the APU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+/// the sound hardware, registers 0xFF10-0xFF26 and the wave RAM, 0xFF30-0xFF3F.
+///
+/// The channels are clocked by the cpu clock for their frequency and by the frame sequencer,
+/// itself clocked at 512 Hz by the falling edges of bit 4 of DIV. Over its 8 steps the frame
+/// sequencer clocks the length counters at 256 Hz (steps 0, 2, 4 and 6), the sweep at 128 Hz
+/// (steps 2 and 6) and the envelopes at 64 Hz (step 7).
+///
+/// Every 4 clock cycles the DAC outputs are panned by NR51, scaled by the master volumes of
+/// NR50 and resampled to the host rate. The samples go through the high-pass filter and are
+/// handed out by `take_samples_f32` or `take_samples_i16`, which are meant to be called after
+/// every frame.
+pub struct APU {
+    /// bit 7 of NR52, every register but NR52 is cleared and read-only while the APU is off
+    enabled: bool,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    nr50: u8,
+    nr51: u8,
+    /// next step of the frame sequencer
+    step: u8,
+    sample_rate: u32,
+    high_pass: HighPass,
+    resampler: Resampler,
+    capacitors: [Capacitor; 2],
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    recording: Option<Recording>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            nr50: 0,
+            nr51: 0,
+            step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            high_pass: HighPass::Dmg,
+            resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            capacitors: [
+                Capacitor::new(HighPass::Dmg, DEFAULT_SAMPLE_RATE),
+                Capacitor::new(HighPass::Dmg, DEFAULT_SAMPLE_RATE),
+            ],
+            muted: [false; 4],
+            soloed: [false; 4],
+            recording: None,
+        }
+    }
+}
+
+impl APU {
+    /// the rate of the samples handed out, 48 kHz by default
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.resampler = Resampler::new(sample_rate);
+        self.set_high_pass(self.high_pass);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_high_pass(&mut self, high_pass: HighPass) {
+        self.high_pass = high_pass;
+        self.capacitors = [
+            Capacitor::new(high_pass, self.sample_rate),
+            Capacitor::new(high_pass, self.sample_rate),
+        ];
+    }
+
+    pub fn high_pass(&self) -> HighPass {
+        self.high_pass
+    }
+
+    /// silence a channel in the mix handed out
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    /// once a channel is soloed, only the soloed channels are heard
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.soloed[channel as usize] = soloed;
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.soloed[channel as usize]
+    }
+
+    /// record the samples handed out from now on, along with every channel on its own if
+    /// `channels` is set. Samples are recorded as they are taken.
+    pub fn start_recording(&mut self, channels: bool) {
+        self.recording = Some(Recording::new(self.sample_rate, self.high_pass, channels));
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    /// carry on with a recording stopped on this or another APU, at the same sample rate
+    pub fn resume_recording(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    /// advance the channels by a number of clock cycles
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            if self.enabled {
+                self.square1.tick(4);
+                self.square2.tick(4);
+                self.wave.tick(4);
+                self.noise.tick(4);
+            }
+            let analog = self.analog_outputs();
+            let sample = self.mix(&analog, self.audible());
+            self.resampler.push(sample);
+            if self
+                .recording
+                .as_ref()
+                .is_some_and(Recording::records_channels)
+            {
+                let channels = [0, 1, 2, 3].map(|channel| {
+                    let mut included = [false; 4];
+                    included[channel] = true;
+                    self.mix(&analog, included)
+                });
+                if let Some(recording) = &mut self.recording {
+                    recording.push_channels(channels);
+                }
+            }
+        }
+    }
+
+    /// a falling edge of bit 4 of DIV
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.step % 2 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.step == 2 || self.step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.step = (self.step + 1) % 8;
+    }
+
+    /// the digital output of every channel, from 0 to 15
+    pub fn digital_outputs(&self) -> [u8; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+
+    /// the output of every channel DAC, from -1 to 1, or 0 when the DAC is off
+    fn analog_outputs(&self) -> [f32; 4] {
+        let dacs = [
+            self.square1.dac_enabled(),
+            self.square2.dac_enabled(),
+            self.wave.dac_enabled(),
+            self.noise.dac_enabled(),
+        ];
+        let mut outputs = [0.0; 4];
+        for ((output, digital), dac) in outputs
+            .iter_mut()
+            .zip(self.digital_outputs().iter())
+            .zip(dacs.iter())
+        {
+            if *dac {
+                *output = *digital as f32 / 7.5 - 1.0;
+            }
+        }
+        outputs
+    }
+
+    /// the channels neither muted nor left out by a solo
+    fn audible(&self) -> [bool; 4] {
+        let solo = self.soloed.contains(&true);
+        let mut audible = [false; 4];
+        for (channel, audible) in audible.iter_mut().enumerate() {
+            *audible = !self.muted[channel] && (!solo || self.soloed[channel]);
+        }
+        audible
+    }
+
+    /// the left and right outputs of the mixer with only the `included` channels, from -1 to 1
+    fn mix(&self, analog: &[f32; 4], included: [bool; 4]) -> [f32; 2] {
+        let mut mixed = [0.0; 2];
+        for (channel, output) in analog.iter().enumerate() {
+            if !included[channel] {
+                continue;
+            }
+            if self.nr51 & (0x10 << channel) != 0 {
+                mixed[0] += output;
+            }
+            if self.nr51 & (0x01 << channel) != 0 {
+                mixed[1] += output;
+            }
+        }
+        let left_volume = ((self.nr50 >> 4) & 0x07) + 1;
+        let right_volume = (self.nr50 & 0x07) + 1;
+        [
+            mixed[0] / 4.0 * left_volume as f32 / 8.0,
+            mixed[1] / 4.0 * right_volume as f32 / 8.0,
+        ]
+    }
+
+    /// the samples produced since the last call, left and right interleaved, from -1 to 1
+    pub fn take_samples_f32(&mut self) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for sample in self.resampler.take() {
+            samples.push(self.capacitors[0].filter(sample[0]));
+            samples.push(self.capacitors[1].filter(sample[1]));
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.record(&samples);
+        }
+        samples
+    }
+
+    /// the samples produced since the last call, left and right interleaved
+    pub fn take_samples_i16(&mut self) -> Vec<i16> {
+        self.take_samples_f32()
+            .iter()
+            .map(|sample| to_i16(*sample))
+            .collect()
+    }
+
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.square1.rb(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.square2.rb(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.wave.rb(addr - 0xFF1A),
+            0xFF1F..=0xFF23 => self.noise.rb(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                ((self.enabled as u8) << 7)
+                    | 0x70
+                    | self.square1.enabled() as u8
+                    | (self.square2.enabled() as u8) << 1
+                    | (self.wave.enabled() as u8) << 2
+                    | (self.noise.enabled() as u8) << 3
+            }
+            0xFF30..=0xFF3F => self.wave.rb_ram(addr),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => return self.set_enabled(value & 0x80 != 0),
+            // the wave RAM does not depend on the power of the APU
+            0xFF30..=0xFF3F => return self.wave.wb_ram(addr, value),
+            _ => {}
+        }
+        if !self.enabled {
+            // the length counters stay writable on DMG
+            match addr {
+                0xFF11 => self.square1.load_length(value),
+                0xFF16 => self.square2.load_length(value),
+                0xFF1B => self.wave.load_length(value),
+                0xFF20 => self.noise.load_length(value),
+                _ => {}
+            }
+            return;
+        }
+        // the length counters get an extra clock when the next step does not clock them
+        let extra_length_clock = self.step % 2 == 1;
+        match addr {
+            0xFF10..=0xFF14 => self.square1.wb(addr - 0xFF10, value, extra_length_clock),
+            0xFF15..=0xFF19 => self.square2.wb(addr - 0xFF15, value, extra_length_clock),
+            0xFF1A..=0xFF1E => self.wave.wb(addr - 0xFF1A, value, extra_length_clock),
+            0xFF1F..=0xFF23 => self.noise.wb(addr - 0xFF1F, value, extra_length_clock),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            self.step = 0;
+        } else if !enabled && self.enabled {
+            let wave = std::mem::take(&mut self.wave);
+            self.wave.keep_ram(wave);
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.noise = Noise::default();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        }
+        self.enabled = enabled;
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+impl Snapshot for APU {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.square1.save(state);
+        self.square2.save(state);
+        self.wave.save(state);
+        self.noise.save(state);
+        state.write_u8(self.nr50);
+        state.write_u8(self.nr51);
+        state.write_u8(self.step);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.square1.load(state)?;
+        self.square2.load(state)?;
+        self.wave.load(state)?;
+        self.noise.load(state)?;
+        self.nr50 = state.read_u8()?;
+        self.nr51 = state.read_u8()?;
+        self.step = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> APU {
+        let mut apu = APU::default();
+        apu.wb(0xFF26, 0x80);
+        apu
+    }
+
+    /// what the mixer outputs at the moment
+    fn mixed(apu: &APU) -> [f32; 2] {
+        apu.mix(&apu.analog_outputs(), apu.audible())
+    }
+
+    #[test]
+    fn power_tests() {
+        let mut apu = APU::default();
+        assert_eq!(apu.rb(0xFF26), 0x70);
+        apu.wb(0xFF12, 0xF0);
+        assert_eq!(apu.rb(0xFF12), 0x00);
+
+        apu.wb(0xFF26, 0x80);
+        apu.wb(0xFF12, 0xF0);
+        apu.wb(0xFF14, 0x80);
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF19, 0x80);
+        apu.wb(0xFF25, 0x12);
+        assert_eq!(apu.rb(0xFF26), 0xF3);
+
+        // turning the APU off clears every register
+        apu.wb(0xFF26, 0x00);
+        assert_eq!(apu.rb(0xFF26), 0x70);
+        assert_eq!(apu.rb(0xFF12), 0x00);
+        assert_eq!(apu.rb(0xFF25), 0x00);
+        assert_eq!(apu.rb(0xFF15), 0xFF);
+
+        // except the wave RAM, which stays accessible
+        apu.wb(0xFF30, 0x12);
+        apu.wb(0xFF26, 0x80);
+        apu.wb(0xFF26, 0x00);
+        assert_eq!(apu.rb(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn channels_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF1A, 0x80);
+        apu.wb(0xFF1E, 0x80);
+        apu.wb(0xFF21, 0xF0);
+        apu.wb(0xFF23, 0x80);
+        assert_eq!(apu.rb(0xFF26), 0xFC);
+        assert_eq!(apu.rb(0xFF1A), 0xFF);
+        assert_eq!(apu.rb(0xFF1F), 0xFF);
+
+        // all four length counters are clocked
+        apu.wb(0xFF1B, 0xFF);
+        apu.wb(0xFF1E, 0x40);
+        apu.wb(0xFF20, 0x3F);
+        apu.wb(0xFF23, 0x40);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.rb(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn frame_sequencer_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF17, 0xF1);
+        apu.wb(0xFF16, 0xFE);
+        apu.wb(0xFF19, 0xC0);
+
+        // the length is clocked on the even steps
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.rb(0xFF26) & 0x02, 0x02);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.rb(0xFF26) & 0x02, 0x00);
+
+        // the envelope on step 7, the 75% duty is high from the second step of the pattern
+        let mut apu = powered();
+        apu.wb(0xFF16, 0xC0);
+        apu.wb(0xFF17, 0xF1);
+        apu.wb(0xFF19, 0x80);
+        apu.tick(8192);
+        for _ in 0..7 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.digital_outputs(), [0, 15, 0, 0]);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.digital_outputs(), [0, 14, 0, 0]);
+    }
+
+    #[test]
+    fn sweep_clock_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF10, 0x11);
+        apu.wb(0xFF12, 0xF0);
+        apu.wb(0xFF13, 0x00);
+        apu.wb(0xFF14, 0x85);
+
+        // 0x500 + 0x280 on step 2, then 0x780 + 0x3C0 overflows
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.rb(0xFF26) & 0x01, 0x01);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.rb(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn mix_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF19, 0x80);
+        assert_eq!(mixed(&apu), [0.0, 0.0]);
+
+        // channel 2 at digital 0 is -1 out of its DAC, on the right only
+        apu.wb(0xFF25, 0x02);
+        apu.wb(0xFF24, 0x07);
+        assert_eq!(mixed(&apu), [0.0, -0.25]);
+        apu.wb(0xFF24, 0x03);
+        assert_eq!(mixed(&apu), [0.0, -0.125]);
+
+        // both sides, with channel 1 and its DAC off on the left
+        apu.wb(0xFF25, 0x32);
+        apu.wb(0xFF24, 0x77);
+        assert_eq!(mixed(&apu), [-0.25, -0.25]);
+    }
+
+    #[test]
+    fn samples_tests() {
+        let mut apu = powered();
+        apu.set_sample_rate(44100);
+        apu.wb(0xFF24, 0x77);
+        apu.wb(0xFF25, 0x22);
+        apu.wb(0xFF16, 0x80);
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF18, 0x00);
+        apu.wb(0xFF19, 0x87);
+
+        // a frame gives 70224 / 4194304 * 44100 samples, left and right interleaved
+        apu.tick(70224);
+        let samples = apu.take_samples_f32();
+        assert_eq!(samples.len() / 2, 738);
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        assert!(samples.chunks(2).all(|pair| pair[0] == pair[1]));
+        assert!(samples.iter().any(|sample| *sample > 0.1));
+        assert!(apu.take_samples_f32().is_empty());
+
+        apu.tick(70224);
+        let samples = apu.take_samples_i16();
+        assert_eq!(samples.len() / 2, 738);
+        assert!(samples.iter().any(|sample| *sample > 3000));
+    }
+
+    #[test]
+    fn mute_solo_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF24, 0x77);
+        apu.wb(0xFF25, 0xFF);
+        apu.wb(0xFF12, 0xF0);
+        apu.wb(0xFF14, 0x80);
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF19, 0x80);
+        assert_eq!(mixed(&apu), [-0.5, -0.5]);
+
+        apu.set_muted(Channel::Square1, true);
+        assert!(apu.is_muted(Channel::Square1));
+        assert_eq!(mixed(&apu), [-0.25, -0.25]);
+
+        // a solo wins over the other channels, not over a mute
+        apu.set_soloed(Channel::Square1, true);
+        assert_eq!(mixed(&apu), [0.0, 0.0]);
+        apu.set_muted(Channel::Square1, false);
+        assert_eq!(mixed(&apu), [-0.25, -0.25]);
+        apu.set_soloed(Channel::Square2, true);
+        assert_eq!(mixed(&apu), [-0.5, -0.5]);
+        apu.set_soloed(Channel::Square1, false);
+        apu.set_soloed(Channel::Square2, false);
+        assert!(!apu.is_soloed(Channel::Square2));
+        assert_eq!(mixed(&apu), [-0.5, -0.5]);
+    }
+
+    #[test]
+    fn recording_tests() {
+        let mut apu = powered();
+        apu.wb(0xFF24, 0x77);
+        apu.wb(0xFF25, 0x22);
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF18, 0x00);
+        apu.wb(0xFF19, 0x87);
+        apu.tick(70224);
+        apu.take_samples_i16();
+
+        apu.start_recording(true);
+        apu.set_muted(Channel::Square2, true);
+        apu.tick(70224);
+        let taken = apu.take_samples_i16();
+        apu.set_muted(Channel::Square2, false);
+        apu.tick(70224);
+        let taken = [taken, apu.take_samples_i16()].concat();
+        let recording = apu.stop_recording().unwrap();
+        assert!(apu.recording().is_none());
+
+        // the mix as it was handed out, the channels whatever their mute
+        assert_eq!(recording.sample_rate(), 48000);
+        assert_eq!(recording.samples(), &taken[..]);
+        let square2 = recording.channel_samples(Channel::Square2).unwrap();
+        let square1 = recording.channel_samples(Channel::Square1).unwrap();
+        assert_eq!(square2.len(), taken.len());
+        assert!(square2[..square2.len() / 2]
+            .iter()
+            .any(|sample| *sample > 3000));
+        assert!(square1.iter().all(|sample| *sample == 0));
+
+        assert_eq!(recording.to_wav().len(), 44 + taken.len() * 2);
+        assert_eq!(
+            recording.channel_to_wav(Channel::Noise).unwrap().len(),
+            44 + taken.len() * 2
+        );
+
+        apu.start_recording(false);
+        let recording = apu.stop_recording().unwrap();
+        assert!(recording.channel_samples(Channel::Wave).is_none());
+        assert!(recording
+            .write_channel_wav(Channel::Wave, "unused.wav")
+            .is_err());
+    }
+}
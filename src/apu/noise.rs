@@ -0,0 +1,235 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// clock cycles for each divider code of NR43, before the shift is applied
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// the noise channel, channel 4.
+///
+/// The registers are numbered from NR40 to NR44, there is no NR40. The frequency timer counts
+/// divisor << shift clock cycles before shifting the linear feedback shift register, whose
+/// lowest bit, inverted, is the output. In 7 bit mode the feedback is copied into bit 6 as well,
+/// which gives a shorter and more metallic sequence.
+pub(super) struct Noise {
+    enabled: bool,
+    /// NR43 as written
+    polynomial: u8,
+    lfsr: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            polynomial: 0,
+            lfsr: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Noise {
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// the digital output of the channel, from 0 to 15
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+
+    pub(super) fn rb(&self, register: u16) -> u8 {
+        match register {
+            0 | 1 => 0xFF,
+            2 => self.envelope.rb(),
+            3 => self.polynomial,
+            4 => ((self.length.enabled() as u8) << 6) | 0xBF,
+            _ => unreachable!(),
+        }
+    }
+
+    /// `extra_length_clock` tells if the next step of the frame sequencer does not clock the
+    /// length counters
+    pub(super) fn wb(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {}
+            1 => self.length.set_length(value & 0x3F),
+            2 => {
+                self.envelope.wb(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = value,
+            4 => {
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_control(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// load the length field of NR41 alone, the only write that goes through while the APU is
+    /// off
+    pub(super) fn load_length(&mut self, value: u8) {
+        self.length.set_length(value & 0x3F);
+    }
+
+    fn shift(&self) -> u8 {
+        self.polynomial >> 4
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.polynomial & 0x07) as usize] << self.shift()
+    }
+
+    /// advance the frequency timer by a number of clock cycles
+    pub(super) fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            // the shift register is not clocked at all with a shift of 14 or 15
+            if self.shift() < 14 {
+                self.clock_lfsr();
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.polynomial & 0x08 != 0 {
+            self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+impl Snapshot for Noise {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.polynomial);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.polynomial = state.read_u8()?;
+        self.lfsr = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.length.load(state)?;
+        self.envelope.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(polynomial: u8) -> Noise {
+        let mut noise = Noise::default();
+        noise.wb(2, 0xF0, false);
+        noise.wb(3, polynomial, false);
+        noise.wb(4, 0x80, false);
+        noise
+    }
+
+    /// the outputs of the channel over `count` clocks of the shift register
+    fn sequence(noise: &mut Noise, period: u32, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                noise.tick(period);
+                noise.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lfsr_tests() {
+        // the register starts all ones, zeros come in from the top and take 14 clocks to reach
+        // the output
+        let mut noise = playing(0x00);
+        assert_eq!(noise.output(), 0);
+        let outputs = sequence(&mut noise, 8, 15);
+        assert_eq!(outputs[..14], [0; 14]);
+        assert_eq!(outputs[14], 15);
+    }
+
+    #[test]
+    fn width_tests() {
+        // the 7 bit sequence repeats every 127 clocks, the 15 bit one does not
+        let mut noise = playing(0x08);
+        let outputs = sequence(&mut noise, 8, 254);
+        assert_eq!(outputs[..127], outputs[127..]);
+
+        let mut noise = playing(0x00);
+        let outputs = sequence(&mut noise, 8, 254);
+        assert_ne!(outputs[..127], outputs[127..]);
+    }
+
+    #[test]
+    fn divider_tests() {
+        // divider 3 and shift 2 clock the register every 48 << 2 cycles
+        let mut noise = playing(0x23);
+        noise.tick(191);
+        assert_eq!(noise.lfsr, 0x7FFF);
+        noise.tick(1);
+        assert_eq!(noise.lfsr, 0x3FFF);
+
+        // a shift of 14 stops it
+        let mut noise = playing(0xE0);
+        noise.tick(8 << 14);
+        assert_eq!(noise.lfsr, 0x7FFF);
+    }
+
+    #[test]
+    fn registers_tests() {
+        let mut noise = playing(0x5B);
+        assert_eq!(noise.rb(0), 0xFF);
+        assert_eq!(noise.rb(1), 0xFF);
+        assert_eq!(noise.rb(2), 0xF0);
+        assert_eq!(noise.rb(3), 0x5B);
+        assert_eq!(noise.rb(4), 0xBF);
+        assert!(noise.enabled());
+        noise.wb(2, 0x00, false);
+        assert!(!noise.enabled());
+    }
+}
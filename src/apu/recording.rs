@@ -0,0 +1,118 @@
+use super::filter::{Capacitor, HighPass};
+use super::resampler::Resampler;
+use super::{to_i16, wav, Channel};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// each channel on its own, resampled and filtered like the mix
+struct Tracks {
+    resamplers: Vec<Resampler>,
+    capacitors: Vec<[Capacitor; 2]>,
+    samples: [Vec<i16>; 4],
+}
+
+/// the audio handed out by the APU since the recording started, and optionally every channel
+/// on its own, ignoring mute and solo
+pub struct Recording {
+    sample_rate: u32,
+    samples: Vec<i16>,
+    tracks: Option<Tracks>,
+}
+
+impl Recording {
+    pub(super) fn new(sample_rate: u32, high_pass: HighPass, channels: bool) -> Self {
+        let tracks = if channels {
+            Some(Tracks {
+                resamplers: (0..4).map(|_| Resampler::new(sample_rate)).collect(),
+                capacitors: (0..4)
+                    .map(|_| {
+                        [
+                            Capacitor::new(high_pass, sample_rate),
+                            Capacitor::new(high_pass, sample_rate),
+                        ]
+                    })
+                    .collect(),
+                samples: Default::default(),
+            })
+        } else {
+            None
+        };
+        Self {
+            sample_rate,
+            samples: Vec::new(),
+            tracks,
+        }
+    }
+
+    pub(super) fn records_channels(&self) -> bool {
+        self.tracks.is_some()
+    }
+
+    /// the next mixed sample of every channel on its own
+    pub(super) fn push_channels(&mut self, channels: [[f32; 2]; 4]) {
+        if let Some(tracks) = &mut self.tracks {
+            for (resampler, sample) in tracks.resamplers.iter_mut().zip(channels.iter()) {
+                resampler.push(*sample);
+            }
+        }
+    }
+
+    /// samples of the mix just handed out, along with the matching samples of the channels
+    pub(super) fn record(&mut self, samples: &[f32]) {
+        self.samples
+            .extend(samples.iter().map(|sample| to_i16(*sample)));
+        if let Some(tracks) = &mut self.tracks {
+            for ((resampler, capacitors), track) in tracks
+                .resamplers
+                .iter_mut()
+                .zip(tracks.capacitors.iter_mut())
+                .zip(tracks.samples.iter_mut())
+            {
+                for sample in resampler.take() {
+                    track.push(to_i16(capacitors[0].filter(sample[0])));
+                    track.push(to_i16(capacitors[1].filter(sample[1])));
+                }
+            }
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// the recorded mix, left and right interleaved
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    /// a channel on its own, left and right interleaved, if the channels were recorded
+    pub fn channel_samples(&self, channel: Channel) -> Option<&[i16]> {
+        self.tracks
+            .as_ref()
+            .map(|tracks| &tracks.samples[channel as usize][..])
+    }
+
+    pub fn to_wav(&self) -> Vec<u8> {
+        wav::encode_stereo(self.sample_rate, &self.samples)
+    }
+
+    pub fn write_wav<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_wav())
+    }
+
+    pub fn channel_to_wav(&self, channel: Channel) -> Option<Vec<u8>> {
+        self.channel_samples(channel)
+            .map(|samples| wav::encode_stereo(self.sample_rate, samples))
+    }
+
+    pub fn write_channel_wav<P: AsRef<Path>>(&self, channel: Channel, path: P) -> io::Result<()> {
+        match self.channel_to_wav(channel) {
+            Some(wav) => fs::write(path, wav),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the channels were not recorded",
+            )),
+        }
+    }
+}
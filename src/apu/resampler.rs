@@ -0,0 +1,149 @@
+use std::f64::consts::PI;
+
+/// rate at which the channels are mixed, one sample every 4 clock cycles
+const MIX_RATE: u32 = 1 << 20;
+/// taps of the band-limited step, the output lags behind by half of it
+const WIDTH: usize = 16;
+/// fractional positions of a step between two output samples
+const PHASES: usize = 32;
+/// cutoff of the low-pass filter, relative to the output rate
+const CUTOFF: f64 = 0.45;
+
+/// turns the mix at `MIX_RATE` into stereo samples at the host rate.
+///
+/// The mix is a staircase, so instead of filtering every input sample, each change of level is
+/// added to the output as a band-limited step: a windowed sinc impulse scaled by the change,
+/// the output being the running sum of these impulses. Only the changes cost anything, and
+/// nothing above the cutoff is left to alias.
+pub(super) struct Resampler {
+    /// impulse for each phase, the taps of a phase add up to 1
+    kernel: Vec<[f32; WIDTH]>,
+    /// output samples per mixed sample
+    ratio: f64,
+    /// position of the next mixed sample, in output samples from the start of `impulses`
+    time: f64,
+    impulses: Vec<[f32; 2]>,
+    level: [f32; 2],
+    sum: [f32; 2],
+}
+
+impl Resampler {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        Self {
+            kernel: (0..=PHASES)
+                .map(|phase| impulse(phase as f64 / PHASES as f64))
+                .collect(),
+            ratio: sample_rate as f64 / MIX_RATE as f64,
+            time: 0.0,
+            impulses: Vec::new(),
+            level: [0.0; 2],
+            sum: [0.0; 2],
+        }
+    }
+
+    /// add the next mixed sample
+    pub(super) fn push(&mut self, sample: [f32; 2]) {
+        if sample != self.level {
+            let index = self.time as usize;
+            let phase = ((self.time - index as f64) * PHASES as f64).round() as usize;
+            if self.impulses.len() < index + WIDTH {
+                self.impulses.resize(index + WIDTH, [0.0; 2]);
+            }
+            let delta = [sample[0] - self.level[0], sample[1] - self.level[1]];
+            for (tap, weight) in self.kernel[phase].iter().enumerate() {
+                let impulse = &mut self.impulses[index + tap];
+                impulse[0] += delta[0] * weight;
+                impulse[1] += delta[1] * weight;
+            }
+            self.level = sample;
+        }
+        self.time += self.ratio;
+    }
+
+    /// the output samples no later change of level can affect anymore
+    pub(super) fn take(&mut self) -> Vec<[f32; 2]> {
+        let count = self.time as usize;
+        self.impulses
+            .resize(self.impulses.len().max(count), [0.0; 2]);
+        let sum = &mut self.sum;
+        let samples = self
+            .impulses
+            .drain(..count)
+            .map(|impulse| {
+                sum[0] += impulse[0];
+                sum[1] += impulse[1];
+                *sum
+            })
+            .collect();
+        self.time -= count as f64;
+        samples
+    }
+}
+
+/// the taps of a low-pass impulse placed `offset` output samples after a sample
+fn impulse(offset: f64) -> [f32; WIDTH] {
+    let mut taps = [0.0; WIDTH];
+    for (tap, value) in taps.iter_mut().enumerate() {
+        let distance = tap as f64 - offset - (WIDTH / 2) as f64;
+        let x = 2.0 * CUTOFF * distance;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        // Blackman window over the width of the kernel
+        let position = distance / WIDTH as f64;
+        let window = 0.42 + 0.5 * (2.0 * PI * position).cos() + 0.08 * (4.0 * PI * position).cos();
+        *value = sinc * window;
+    }
+    let total: f64 = taps.iter().sum();
+    let mut kernel = [0.0; WIDTH];
+    for (weight, tap) in kernel.iter_mut().zip(taps.iter()) {
+        *weight = (tap / total) as f32;
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_tests() {
+        let mut resampler = Resampler::new(48000);
+        let mut count = 0;
+        for _ in 0..MIX_RATE / 8 {
+            resampler.push([0.0; 2]);
+            count += resampler.take().len();
+        }
+        assert_eq!(count, 6000);
+    }
+
+    #[test]
+    fn step_tests() {
+        // a step reaches its level once the kernel is past, and keeps it
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..1000 {
+            resampler.push([0.5, -0.25]);
+        }
+        let samples = resampler.take();
+        for sample in &samples[WIDTH..] {
+            assert!((sample[0] - 0.5).abs() < 1e-4);
+            assert!((sample[1] + 0.25).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn aliasing_tests() {
+        // a 262 kHz square wave is far above what 48 kHz can hold, almost nothing is left of it
+        let mut resampler = Resampler::new(48000);
+        for index in 0..20000 {
+            let level = if index / 2 % 2 == 0 { 0.5 } else { -0.5 };
+            resampler.push([level; 2]);
+        }
+        let samples = resampler.take();
+        for sample in &samples[WIDTH..] {
+            assert!(sample[0].abs() < 0.02, "{}", sample[0]);
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// waveforms selected by the duty bits of NRx1, from the first to the last step
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// the frequency sweep of channel 1, set by NR10
+#[derive(Default)]
+struct Sweep {
+    /// NR10 as written
+    register: u8,
+    enabled: bool,
+    timer: u8,
+    /// copy of the frequency the sweep works on
+    shadow: u16,
+    /// a subtraction was computed since the last trigger
+    negated: bool,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn negate(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    /// a period of 0 reloads the timer with 8
+    fn reload_timer(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    /// the next frequency, above 2047 when it overflows
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.negate() {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+/// a square channel: channel 1 with its sweep, or channel 2 without.
+///
+/// The registers are numbered from NRx0 to NRx4, channel 2 has no NRx0. The frequency timer
+/// counts (2048 - frequency) * 4 clock cycles before moving to the next of the 8 steps of the
+/// duty pattern.
+pub(super) struct Square {
+    sweep: Option<Sweep>,
+    enabled: bool,
+    duty: u8,
+    position: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Square {
+    pub(super) fn new(sweep: bool) -> Self {
+        Self {
+            sweep: if sweep { Some(Sweep::default()) } else { None },
+            enabled: false,
+            duty: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// the digital output of the channel, from 0 to 15
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.position as usize] * self.envelope.volume()
+    }
+
+    pub(super) fn rb(&self, register: u16) -> u8 {
+        match register {
+            0 => match &self.sweep {
+                Some(sweep) => sweep.register | 0x80,
+                None => 0xFF,
+            },
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.rb(),
+            3 => 0xFF,
+            4 => ((self.length.enabled() as u8) << 6) | 0xBF,
+            _ => unreachable!(),
+        }
+    }
+
+    /// `extra_length_clock` tells if the next step of the frame sequencer does not clock the
+    /// length counters
+    pub(super) fn wb(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.register = value;
+                    // leaving negate mode after a subtraction was used disables the channel
+                    if sweep.negated && !sweep.negate() {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.set_length(value & 0x3F);
+            }
+            2 => {
+                self.envelope.wb(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_control(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// load the length field of NRx1 alone, the only write that goes through while the APU is
+    /// off
+    pub(super) fn load_length(&mut self, value: u8) {
+        self.length.set_length(value & 0x3F);
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // the overflow check runs right away when there is a shift
+            if sweep.shift() != 0 && sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    /// advance the frequency timer by a number of clock cycles
+    pub(super) fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// clocked at 128 Hz by the frame sequencer
+    pub(super) fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 0x7FF {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // the new frequency goes through the overflow check again, without being kept
+            if sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+impl Snapshot for Square {
+    fn save(&self, state: &mut StateWriter) {
+        if let Some(sweep) = &self.sweep {
+            state.write_u8(sweep.register);
+            state.write_bool(sweep.enabled);
+            state.write_u8(sweep.timer);
+            state.write_u16(sweep.shadow);
+            state.write_bool(sweep.negated);
+        }
+        state.write_bool(self.enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.position);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.register = state.read_u8()?;
+            sweep.enabled = state.read_bool()?;
+            sweep.timer = state.read_u8()?;
+            sweep.shadow = state.read_u16()?;
+            sweep.negated = state.read_bool()?;
+        }
+        self.enabled = state.read_bool()?;
+        self.duty = state.read_u8()?;
+        self.position = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.length.load(state)?;
+        self.envelope.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a triggered channel at full volume
+    fn playing(sweep: u8, frequency: u16) -> Square {
+        let mut square = Square::new(true);
+        square.wb(0, sweep, false);
+        square.wb(1, 0x80, false);
+        square.wb(2, 0xF0, false);
+        square.wb(3, frequency as u8, false);
+        square.wb(4, 0x80 | (frequency >> 8) as u8, false);
+        square
+    }
+
+    #[test]
+    fn duty_tests() {
+        // 50% duty, each step lasts (2048 - 0x700) * 4 = 1024 cycles
+        let mut square = playing(0x00, 0x700);
+        let mut waveform = Vec::new();
+        for _ in 0..8 {
+            square.tick(1024);
+            waveform.push(square.output());
+        }
+        assert_eq!(waveform, [0, 0, 0, 0, 15, 15, 15, 15]);
+
+        square.wb(1, 0x00, false);
+        let mut waveform = Vec::new();
+        for _ in 0..8 {
+            square.tick(1024);
+            waveform.push(square.output());
+        }
+        assert_eq!(waveform, [0, 0, 0, 0, 0, 0, 15, 0]);
+    }
+
+    #[test]
+    fn registers_tests() {
+        let mut square = playing(0x00, 0x123);
+        assert_eq!(square.rb(0), 0x80);
+        assert_eq!(square.rb(1), 0xBF);
+        assert_eq!(square.rb(2), 0xF0);
+        assert_eq!(square.rb(3), 0xFF);
+        assert_eq!(square.rb(4), 0xBF);
+        square.wb(4, 0x40, false);
+        assert_eq!(square.rb(4), 0xFF);
+
+        let channel2 = Square::new(false);
+        assert_eq!(channel2.rb(0), 0xFF);
+    }
+
+    #[test]
+    fn dac_tests() {
+        let mut square = playing(0x00, 0x700);
+        assert!(square.enabled());
+        square.wb(2, 0x00, false);
+        assert!(!square.enabled());
+        square.wb(4, 0x80, false);
+        assert!(!square.enabled());
+    }
+
+    #[test]
+    fn length_tests() {
+        let mut square = playing(0x00, 0x700);
+        square.wb(1, 0x3E, false);
+        square.wb(4, 0x40, false);
+        square.clock_length();
+        assert!(square.enabled());
+        square.clock_length();
+        assert!(!square.enabled());
+    }
+
+    #[test]
+    fn sweep_tests() {
+        // period 1, addition, shift 1: 0x100 -> 0x180 -> 0x240
+        let mut square = playing(0x11, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x180);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x240);
+        assert!(square.enabled());
+
+        // subtraction never overflows
+        let mut square = playing(0x19, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x80);
+        assert!(square.enabled());
+    }
+
+    #[test]
+    fn sweep_overflow_tests() {
+        // the check on trigger already overflows
+        let square = playing(0x11, 0x600);
+        assert!(!square.enabled());
+
+        // 0x500 + 0x280 is kept, the second check of 0x780 + 0x3C0 overflows
+        let mut square = playing(0x11, 0x500);
+        assert!(square.enabled());
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x780);
+        assert!(!square.enabled());
+
+        // a shift of 0 still checks the overflow, without changing the frequency
+        let mut square = playing(0x10, 0x400);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x400);
+        assert!(!square.enabled());
+    }
+
+    #[test]
+    fn sweep_negate_tests() {
+        // clearing negate after a subtraction disables the channel
+        let mut square = playing(0x19, 0x100);
+        square.clock_sweep();
+        square.wb(0, 0x11, false);
+        assert!(!square.enabled());
+
+        // so does the overflow check of the trigger, which subtracts too
+        let mut square = playing(0x79, 0x100);
+        square.wb(0, 0x11, false);
+        assert!(!square.enabled());
+
+        // without a shift nothing was subtracted yet
+        let mut square = playing(0x78, 0x100);
+        square.wb(0, 0x70, false);
+        assert!(square.enabled());
+    }
+}
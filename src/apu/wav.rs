@@ -0,0 +1,52 @@
+//! just enough of WAV to write 16 bit stereo PCM
+
+/// encode stereo samples, left and right interleaved
+pub fn encode_stereo(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let data_length = (samples.len() * BYTES_PER_SAMPLE as usize) as u32;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+
+    let mut wav = Vec::with_capacity(44 + data_length as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_length).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_tests() {
+        let wav = encode_stereo(48000, &[0x0102, -2, 0x7FFF, 0]);
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav[4..8], 44u32.to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(wav[22..24], 2u16.to_le_bytes());
+        assert_eq!(wav[24..28], 48000u32.to_le_bytes());
+        assert_eq!(wav[28..32], 192000u32.to_le_bytes());
+        assert_eq!(wav[32..36], [4, 0, 16, 0]);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], 8u32.to_le_bytes());
+        assert_eq!(wav[44..], [0x02, 0x01, 0xFE, 0xFF, 0xFF, 0x7F, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,281 @@
+use super::length::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// clock cycles between triggering the channel and the first sample being read
+const TRIGGER_DELAY: u32 = 6;
+
+/// the wave channel, channel 3, playing the 32 4 bit samples of the wave RAM (0xFF30-0xFF3F).
+///
+/// The registers are numbered from NR30 to NR34. The frequency timer counts
+/// (2048 - frequency) * 2 clock cycles before reading the next sample, which is shifted right
+/// according to the output level of NR32.
+pub(super) struct Wave {
+    ram: [u8; 16],
+    /// bit 7 of NR30
+    dac_enabled: bool,
+    enabled: bool,
+    /// bits 5-6 of NR32
+    level: u8,
+    frequency: u16,
+    timer: u32,
+    /// sample being played, from 0 to 31
+    position: u8,
+    sample: u8,
+    /// clock cycles since the channel last read the wave RAM
+    since_read: u32,
+    length: LengthCounter,
+}
+
+impl Default for Wave {
+    fn default() -> Self {
+        Self {
+            ram: [0; 16],
+            dac_enabled: false,
+            enabled: false,
+            level: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            since_read: u32::MAX,
+            length: LengthCounter::new(256),
+        }
+    }
+}
+
+impl Wave {
+    /// copy the wave RAM of `previous`, which survives the APU being turned off
+    pub(super) fn keep_ram(&mut self, previous: Wave) {
+        self.ram = previous.ram;
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// the digital output of the channel, from 0 to 15
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.level {
+            0 => 0,
+            level => self.sample >> (level - 1),
+        }
+    }
+
+    pub(super) fn rb(&self, register: u16) -> u8 {
+        match register {
+            0 => ((self.dac_enabled as u8) << 7) | 0x7F,
+            1 => 0xFF,
+            2 => (self.level << 5) | 0x9F,
+            3 => 0xFF,
+            4 => ((self.length.enabled() as u8) << 6) | 0xBF,
+            _ => unreachable!(),
+        }
+    }
+
+    /// `extra_length_clock` tells if the next step of the frame sequencer does not clock the
+    /// length counters
+    pub(super) fn wb(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.set_length(value),
+            2 => self.level = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_control(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac_enabled;
+                    self.position = 0;
+                    self.timer = self.period() + TRIGGER_DELAY;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// load NR31 alone, the only write that goes through while the APU is off
+    pub(super) fn load_length(&mut self, value: u8) {
+        self.length.set_length(value);
+    }
+
+    /// read the wave RAM. On DMG, while the channel plays, the cpu only reaches the byte the
+    /// channel is reading, and only on the very cycle it reads it; it gets 0xFF otherwise.
+    pub(super) fn rb_ram(&self, addr: u16) -> u8 {
+        match self.ram_index(addr) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+
+    /// write the wave RAM, with the same restriction as reads while the channel plays
+    pub(super) fn wb_ram(&mut self, addr: u16, value: u8) {
+        if let Some(index) = self.ram_index(addr) {
+            self.ram[index] = value;
+        }
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.enabled {
+            Some((addr - 0xFF30) as usize)
+        } else if self.since_read < 2 {
+            Some(self.position as usize / 2)
+        } else {
+            None
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    /// advance the frequency timer by a number of clock cycles
+    pub(super) fn tick(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        self.since_read = self.since_read.saturating_add(cycles);
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+            self.since_read = cycles;
+        }
+        self.timer -= cycles;
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+impl Snapshot for Wave {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.dac_enabled);
+        state.write_bool(self.enabled);
+        state.write_u8(self.level);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample);
+        state.write_u32(self.since_read);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.ram)?;
+        self.dac_enabled = state.read_bool()?;
+        self.enabled = state.read_bool()?;
+        self.level = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()?;
+        self.sample = state.read_u8()?;
+        self.since_read = state.read_u32()?;
+        self.length.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a channel playing a ramp from 0 to 15 twice, at full volume
+    fn playing(frequency: u16) -> Wave {
+        let mut wave = Wave::default();
+        for index in 0..16u16 {
+            let high = (index * 2 % 16) as u8;
+            let low = (index * 2 % 16 + 1) as u8;
+            wave.wb_ram(0xFF30 + index, (high << 4) | low);
+        }
+        wave.wb(0, 0x80, false);
+        wave.wb(2, 0x20, false);
+        wave.wb(3, frequency as u8, false);
+        wave.wb(4, 0x80 | (frequency >> 8) as u8, false);
+        wave
+    }
+
+    #[test]
+    fn samples_tests() {
+        // each sample lasts (2048 - 0x7F0) * 2 = 32 cycles, the first one after the delay
+        let mut wave = playing(0x7F0);
+        wave.tick(32 + TRIGGER_DELAY);
+        assert_eq!(wave.output(), 1);
+        let mut samples = Vec::new();
+        for _ in 0..4 {
+            wave.tick(32);
+            samples.push(wave.output());
+        }
+        assert_eq!(samples, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn level_tests() {
+        let mut wave = playing(0x7F0);
+        wave.tick(32 * 15 + TRIGGER_DELAY);
+        assert_eq!(wave.output(), 15);
+        wave.wb(2, 0x40, false);
+        assert_eq!(wave.output(), 7);
+        wave.wb(2, 0x60, false);
+        assert_eq!(wave.output(), 3);
+        wave.wb(2, 0x00, false);
+        assert_eq!(wave.output(), 0);
+        assert_eq!(wave.rb(2), 0x9F);
+    }
+
+    #[test]
+    fn ram_access_tests() {
+        let mut wave = playing(0x7F0);
+        assert_eq!(wave.rb_ram(0xFF30), 0xFF);
+
+        // right when the channel reads the second byte, the cpu sees that byte wherever it reads
+        wave.tick(32 * 2 + TRIGGER_DELAY);
+        assert_eq!(wave.rb_ram(0xFF3F), 0x23);
+        wave.wb_ram(0xFF3F, 0x99);
+        wave.tick(4);
+        assert_eq!(wave.rb_ram(0xFF31), 0xFF);
+        wave.wb_ram(0xFF3F, 0x42);
+
+        wave.wb(0, 0x00, false);
+        assert_eq!(wave.rb_ram(0xFF31), 0x99);
+        assert_eq!(wave.rb_ram(0xFF3F), 0xEF);
+    }
+
+    #[test]
+    fn length_tests() {
+        let mut wave = playing(0x7F0);
+        wave.wb(1, 0xFE, false);
+        wave.wb(4, 0x47, false);
+        wave.clock_length();
+        assert!(wave.enabled());
+        wave.clock_length();
+        assert!(!wave.enabled());
+    }
+}
@@ -0,0 +1,118 @@
+/// cartridge types shipping a battery: MBC1, MBC2, ROM+RAM, MMM01, MBC3, MBC5, MBC7, Pocket
+/// Camera, TAMA5, HuC3 and HuC1
+const BATTERY_TYPES: [u8; 14] = [
+    0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E, 0x22, 0xFC, 0xFD, 0xFE, 0xFF,
+];
+
+/// the cartridge header lives at 0x0100-0x014F of every rom and describes the hardware shipped
+/// on the cartridge
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    /// parse the header of a rom. Missing bytes in a truncated rom are read as 0
+    pub fn parse(rom: &[u8]) -> Self {
+        let byte = |addr: usize| rom.get(addr).copied().unwrap_or(0);
+
+        // the title is 16 bytes long on old cartridges, but the last bytes were later reused for
+        // the manufacturer code and the cgb flag. It is always padded with zeroes.
+        let title = (0x0134..0x0144)
+            .map(byte)
+            .take_while(|&b| b != 0 && b < 0x80)
+            .map(|b| b as char)
+            .collect();
+
+        Self {
+            title,
+            cgb_flag: byte(0x0143),
+            cartridge_type: byte(0x0147),
+            rom_size: byte(0x0148),
+            ram_size: byte(0x0149),
+            header_checksum: byte(0x014D),
+            global_checksum: u16::from_be_bytes([byte(0x014E), byte(0x014F)]),
+        }
+    }
+
+    /// whether the external ram is kept alive by a battery when the console is off
+    pub fn has_battery(&self) -> bool {
+        BATTERY_TYPES.contains(&self.cartridge_type)
+    }
+
+    /// whether the cartridge embeds the MBC3 real time clock
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.cartridge_type, 0x0F | 0x10)
+    }
+
+    /// size of the external ram in bytes
+    pub fn ram_bytes(&self) -> usize {
+        match self.cartridge_type {
+            // the MBC2 has 512 half-bytes of ram built in, whatever the header says
+            0x05 | 0x06 => 0x200,
+            _ => match self.ram_size {
+                0x01 => 0x800,
+                0x02 => 0x2000,
+                0x03 => 0x8000,
+                0x04 => 0x20000,
+                0x05 => 0x10000,
+                _ => 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tests() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0139].copy_from_slice(b"TETRA");
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        rom[0x014E] = 0xBE;
+        rom[0x014F] = 0xEF;
+
+        let header = Header::parse(&rom);
+
+        assert_eq!(header.title, "TETRA");
+        assert_eq!(header.global_checksum, 0xBEEF);
+        assert!(header.has_battery());
+        assert!(!header.has_rtc());
+        assert_eq!(header.ram_bytes(), 0x2000);
+    }
+
+    #[test]
+    fn battery_tests() {
+        let mut rom = vec![0; 0x150];
+        for (cartridge_type, battery) in [(0x01, false), (0x06, true), (0x10, true), (0x1A, false)]
+        {
+            rom[0x0147] = cartridge_type;
+            assert_eq!(Header::parse(&rom).has_battery(), battery);
+        }
+    }
+
+    #[test]
+    fn mbc2_ram_tests() {
+        let mut rom = vec![0; 0x150];
+        rom[0x0147] = 0x06;
+        rom[0x0149] = 0x00;
+
+        assert_eq!(Header::parse(&rom).ram_bytes(), 0x200);
+    }
+
+    #[test]
+    fn truncated_rom_tests() {
+        let header = Header::parse(&[0; 0x10]);
+
+        assert_eq!(header.title, "");
+        assert_eq!(header.ram_bytes(), 0);
+    }
+}
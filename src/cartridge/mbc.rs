@@ -0,0 +1,270 @@
+use super::rtc::Rtc;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+impl MbcKind {
+    pub fn from_cartridge_type(cartridge_type: u8) -> Self {
+        match cartridge_type {
+            0x01..=0x03 => MbcKind::Mbc1,
+            0x05 | 0x06 => MbcKind::Mbc2,
+            0x0F..=0x13 => MbcKind::Mbc3,
+            0x19..=0x1E => MbcKind::Mbc5,
+            _ => MbcKind::None,
+        }
+    }
+}
+
+/// where an access to the external ram area ends up
+pub enum RamTarget {
+    /// offset in the external ram
+    Ram(usize),
+    /// one of the MBC3 clock registers
+    Rtc(u8),
+    /// ram is disabled or absent
+    Disabled,
+}
+
+/// the memory bank controller, it decodes writes to the rom area as bank switching commands
+pub struct Mbc {
+    kind: MbcKind,
+    rom_bank: u16,
+    /// on MBC1 this is the secondary 2 bits register, which may also select the upper rom bits
+    ram_bank: u8,
+    ram_enabled: bool,
+    /// MBC1 only, selects whether the secondary register also applies to 0x0000-0x3FFF and ram
+    banking_mode: bool,
+    pub rtc: Option<Rtc>,
+}
+
+impl Mbc {
+    pub fn new(kind: MbcKind, has_rtc: bool) -> Self {
+        Self {
+            kind,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            banking_mode: false,
+            rtc: if has_rtc { Some(Rtc::default()) } else { None },
+        }
+    }
+
+    pub fn kind(&self) -> MbcKind {
+        self.kind
+    }
+
+    /// offset in the rom of an address in 0x0000-0x7FFF, before masking by the rom size
+    pub fn rom_offset(&self, addr: u16) -> usize {
+        let bank = match (addr, self.kind) {
+            (0x0000..=0x3FFF, MbcKind::Mbc1) if self.banking_mode => (self.ram_bank as usize) << 5,
+            (0x0000..=0x3FFF, _) => 0,
+            (_, MbcKind::None) => 1,
+            (_, MbcKind::Mbc1) => (self.ram_bank as usize) << 5 | self.rom_bank as usize,
+            (_, _) => self.rom_bank as usize,
+        };
+        bank * 0x4000 + (addr as usize & 0x3FFF)
+    }
+
+    /// where an address in 0xA000-0xBFFF points to, given the size of the external ram
+    pub fn ram_target(&self, addr: u16, ram_size: usize) -> RamTarget {
+        let enabled = self.ram_enabled || self.kind == MbcKind::None;
+        if !enabled {
+            return RamTarget::Disabled;
+        }
+        let bank = match self.kind {
+            MbcKind::Mbc1 if self.banking_mode => self.ram_bank as usize,
+            MbcKind::Mbc3 if self.ram_bank >= 0x08 => {
+                // only 0x08-0x0C select a clock register, the rest of the range maps nothing
+                return match self.ram_bank {
+                    0x08..=0x0C => RamTarget::Rtc(self.ram_bank),
+                    _ => RamTarget::Disabled,
+                };
+            }
+            MbcKind::Mbc3 | MbcKind::Mbc5 => self.ram_bank as usize,
+            _ => 0,
+        };
+        if ram_size == 0 {
+            return RamTarget::Disabled;
+        }
+        let offset = match self.kind {
+            // the 512 half-bytes of the MBC2 are echoed over the whole area
+            MbcKind::Mbc2 => addr as usize & 0x1FF,
+            _ => bank * 0x2000 + (addr as usize & 0x1FFF),
+        };
+        RamTarget::Ram(offset % ram_size)
+    }
+
+    /// handle a write to the rom area
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match (self.kind, addr) {
+            (MbcKind::None, _) => {}
+            (MbcKind::Mbc2, 0x0000..=0x3FFF) => {
+                // the least significant bit of the upper address byte selects the register
+                if addr & 0x0100 == 0 {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else {
+                    self.rom_bank = (value as u16 & 0x0F).max(1);
+                }
+            }
+            (MbcKind::Mbc5, 0x0000..=0x1FFF) => self.ram_enabled = value == 0x0A,
+            (_, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MbcKind::Mbc1, 0x2000..=0x3FFF) => self.rom_bank = (value as u16 & 0x1F).max(1),
+            (MbcKind::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (value as u16 & 0x7F).max(1),
+            (MbcKind::Mbc5, 0x2000..=0x2FFF) => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as u16
+            }
+            (MbcKind::Mbc5, 0x3000..=0x3FFF) => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (value as u16 & 0x01) << 8
+            }
+            (MbcKind::Mbc1, 0x4000..=0x5FFF) => self.ram_bank = value & 0x03,
+            (MbcKind::Mbc3, 0x4000..=0x5FFF) => self.ram_bank = value & 0x0F,
+            (MbcKind::Mbc5, 0x4000..=0x5FFF) => self.ram_bank = value & 0x0F,
+            (MbcKind::Mbc1, 0x6000..=0x7FFF) => self.banking_mode = value & 0x01 != 0,
+            (MbcKind::Mbc3, 0x6000..=0x7FFF) => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for Mbc {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.banking_mode);
+        if let Some(rtc) = &self.rtc {
+            rtc.save(state);
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.ram_enabled = state.read_bool()?;
+        self.banking_mode = state.read_bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mbc1_tests() {
+        let mut mbc = Mbc::new(MbcKind::Mbc1, false);
+
+        assert_eq!(mbc.rom_offset(0x4000), 0x4000);
+
+        mbc.wb(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4123), 0x4123);
+
+        mbc.wb(0x2000, 0x05);
+        mbc.wb(0x4000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000), 0x25 * 0x4000);
+        assert_eq!(mbc.rom_offset(0x0000), 0);
+
+        mbc.wb(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x20 * 0x4000);
+    }
+
+    #[test]
+    fn mbc1_ram_tests() {
+        let mut mbc = Mbc::new(MbcKind::Mbc1, false);
+
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x8000),
+            RamTarget::Disabled
+        ));
+
+        mbc.wb(0x0000, 0x0A);
+        mbc.wb(0x4000, 0x02);
+        assert!(matches!(
+            mbc.ram_target(0xA010, 0x8000),
+            RamTarget::Ram(0x10)
+        ));
+
+        mbc.wb(0x6000, 0x01);
+        assert!(matches!(
+            mbc.ram_target(0xA010, 0x8000),
+            RamTarget::Ram(0x4010)
+        ));
+    }
+
+    #[test]
+    fn mbc2_tests() {
+        let mut mbc = Mbc::new(MbcKind::Mbc2, false);
+
+        mbc.wb(0x0100, 0x03);
+        assert_eq!(mbc.rom_offset(0x4000), 3 * 0x4000);
+
+        mbc.wb(0x0000, 0x0A);
+        assert!(matches!(
+            mbc.ram_target(0xA201, 0x200),
+            RamTarget::Ram(0x001)
+        ));
+    }
+
+    #[test]
+    fn mbc3_tests() {
+        let mut mbc = Mbc::new(MbcKind::Mbc3, true);
+        mbc.wb(0x0000, 0x0A);
+
+        mbc.wb(0x2000, 0x7F);
+        assert_eq!(mbc.rom_offset(0x4000), 0x7F * 0x4000);
+
+        mbc.wb(0x4000, 0x03);
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x8000),
+            RamTarget::Ram(0x6000)
+        ));
+
+        mbc.wb(0x4000, 0x08);
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x8000),
+            RamTarget::Rtc(0x08)
+        ));
+        mbc.wb(0x4000, 0x0C);
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x8000),
+            RamTarget::Rtc(0x0C)
+        ));
+        mbc.wb(0x4000, 0x0D);
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x8000),
+            RamTarget::Disabled
+        ));
+    }
+
+    #[test]
+    fn mbc5_tests() {
+        let mut mbc = Mbc::new(MbcKind::Mbc5, false);
+
+        mbc.wb(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0);
+
+        mbc.wb(0x2000, 0x23);
+        mbc.wb(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000), 0x123 * 0x4000);
+
+        mbc.wb(0x0000, 0x1A);
+        assert!(matches!(
+            mbc.ram_target(0xA000, 0x2000),
+            RamTarget::Disabled
+        ));
+    }
+}
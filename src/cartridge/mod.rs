@@ -0,0 +1,312 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cpu::CLOCK_SPEED;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+mod header;
+mod mbc;
+mod rtc;
+
+pub use header::Header;
+use mbc::{Mbc, MbcKind, RamTarget};
+
+pub struct Cartridge {
+    header: Header,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    /// where the external ram is persisted, only set for battery backed cartridges
+    save_path: Option<PathBuf>,
+    /// whether the ram changed since it was last written to the save file
+    ram_dirty: bool,
+    /// how often the save file is flushed, in cpu cycles
+    flush_interval: Option<u64>,
+    cycles_since_flush: u64,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Self {
+        let header = Header::parse(&rom);
+        let mbc = Mbc::new(
+            MbcKind::from_cartridge_type(header.cartridge_type),
+            header.has_rtc(),
+        );
+        Self {
+            ram: vec![0; header.ram_bytes()],
+            header,
+            rom,
+            mbc,
+            save_path: None,
+            ram_dirty: false,
+            flush_interval: None,
+            cycles_since_flush: 0,
+        }
+    }
+
+    /// load a rom from disk. If the cartridge has a battery, its ram is restored from the `.sav`
+    /// file next to the rom and will be written back to it.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut cartridge = Self::new(fs::read(&path)?);
+        if cartridge.header.has_battery() {
+            cartridge.attach_save_file(path.as_ref().with_extension("sav"))?;
+        }
+        Ok(cartridge)
+    }
+
+    /// persist the external ram in the given file, loading its content first if it exists
+    pub fn attach_save_file<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.into();
+        match fs::read(&path) {
+            Ok(save) => self.load_save(&save),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// how often the ram is written back to the save file while the game runs, measured in
+    /// emulated time. `None` only flushes when asked to or when the cartridge is dropped.
+    pub fn set_flush_interval(&mut self, interval: Option<Duration>) {
+        self.flush_interval =
+            interval.map(|interval| (interval.as_secs_f64() * CLOCK_SPEED as f64) as u64);
+        self.cycles_since_flush = 0;
+    }
+
+    /// write the external ram back to the save file, if it was modified since the last flush
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.cycles_since_flush = 0;
+        let path = match &self.save_path {
+            Some(path) if self.ram_dirty => path,
+            _ => return Ok(()),
+        };
+        fs::write(path, self.save_data())?;
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    /// the content of the save file: the raw ram, followed by the clock for MBC3 timer cartridges
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut save = self.ram.clone();
+        if let Some(rtc) = &self.mbc.rtc {
+            save.extend(rtc.save_footer(unix_timestamp()));
+        }
+        save
+    }
+
+    fn load_save(&mut self, save: &[u8]) {
+        let ram_size = self.ram.len().min(save.len());
+        self.ram[..ram_size].copy_from_slice(&save[..ram_size]);
+
+        if let Some(rtc) = &mut self.mbc.rtc {
+            if let Some(timestamp) = save.get(self.ram.len()..).and_then(|f| rtc.load_footer(f)) {
+                rtc.advance_seconds(unix_timestamp().saturating_sub(timestamp));
+            }
+        }
+    }
+
+    /// advance the cartridge hardware by a number of cpu cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.mbc.rtc {
+            rtc.tick(cycles);
+        }
+        if let Some(interval) = self.flush_interval {
+            self.cycles_since_flush += cycles as u64;
+            if self.cycles_since_flush >= interval {
+                // a failed periodic flush leaves the ram dirty, it will be retried on the next one
+                let _ = self.flush();
+            }
+        }
+    }
+
+    /// read a byte in 0x0000-0x7FFF
+    pub fn rb_rom(&self, addr: u16) -> u8 {
+        if self.rom.is_empty() {
+            return 0xFF;
+        }
+        self.rom[self.mbc.rom_offset(addr) % self.rom.len()]
+    }
+
+    /// write a byte in 0x0000-0x7FFF
+    pub fn wb_rom(&mut self, addr: u16, value: u8) {
+        self.mbc.wb(addr, value);
+    }
+
+    /// read a byte in 0xA000-0xBFFF
+    pub fn rb_ram(&self, addr: u16) -> u8 {
+        match self.mbc.ram_target(addr, self.ram.len()) {
+            RamTarget::Ram(offset) if self.mbc.kind() == MbcKind::Mbc2 => self.ram[offset] | 0xF0,
+            RamTarget::Ram(offset) => self.ram[offset],
+            RamTarget::Rtc(register) => match &self.mbc.rtc {
+                Some(rtc) => rtc.rb(register),
+                None => 0xFF,
+            },
+            RamTarget::Disabled => 0xFF,
+        }
+    }
+
+    /// write a byte in 0xA000-0xBFFF
+    pub fn wb_ram(&mut self, addr: u16, value: u8) {
+        match self.mbc.ram_target(addr, self.ram.len()) {
+            RamTarget::Ram(offset) => {
+                let value = if self.mbc.kind() == MbcKind::Mbc2 {
+                    value & 0x0F
+                } else {
+                    value
+                };
+                if self.ram[offset] != value {
+                    self.ram[offset] = value;
+                    self.ram_dirty = true;
+                }
+            }
+            RamTarget::Rtc(register) => {
+                if let Some(rtc) = &mut self.mbc.rtc {
+                    rtc.wb(register, value);
+                    self.ram_dirty = true;
+                }
+            }
+            RamTarget::Disabled => {}
+        }
+    }
+}
+
+impl Snapshot for Cartridge {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        self.mbc.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.ram)?;
+        self.mbc.load(state)?;
+        // the restored ram has to reach the save file as well
+        self.ram_dirty = true;
+        Ok(())
+    }
+}
+
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        // nothing sensible can be done with an error on shutdown
+        let _ = self.flush();
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery_rom(cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = 0x02;
+        rom
+    }
+
+    fn temp_save_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dmg-01-{}-{}.sav", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn ram_tests() {
+        let mut cartridge = Cartridge::new(battery_rom(0x03));
+
+        cartridge.wb_ram(0xA000, 0x42);
+        assert_eq!(cartridge.rb_ram(0xA000), 0xFF);
+
+        cartridge.wb_rom(0x0000, 0x0A);
+        cartridge.wb_ram(0xA000, 0x42);
+        assert_eq!(cartridge.rb_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn save_file_tests() {
+        let path = temp_save_path("save-file");
+
+        let mut cartridge = Cartridge::new(battery_rom(0x03));
+        cartridge.attach_save_file(&path).unwrap();
+        cartridge.flush().unwrap();
+        assert!(!path.exists());
+
+        cartridge.wb_rom(0x0000, 0x0A);
+        cartridge.wb_ram(0xA123, 0x42);
+        drop(cartridge);
+
+        let save = fs::read(&path).unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x123], 0x42);
+
+        let mut cartridge = Cartridge::new(battery_rom(0x03));
+        cartridge.attach_save_file(&path).unwrap();
+        cartridge.wb_rom(0x0000, 0x0A);
+        assert_eq!(cartridge.rb_ram(0xA123), 0x42);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn periodic_flush_tests() {
+        let path = temp_save_path("periodic-flush");
+
+        let mut cartridge = Cartridge::new(battery_rom(0x03));
+        cartridge.attach_save_file(&path).unwrap();
+        cartridge.set_flush_interval(Some(Duration::from_secs(1)));
+        cartridge.wb_rom(0x0000, 0x0A);
+        cartridge.wb_ram(0xA000, 0x01);
+
+        cartridge.tick(CLOCK_SPEED - 4);
+        assert!(!path.exists());
+
+        cartridge.tick(4);
+        assert_eq!(fs::read(&path).unwrap()[0], 0x01);
+
+        // an unmodified ram is not written again
+        fs::remove_file(&path).unwrap();
+        cartridge.tick(CLOCK_SPEED);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rtc_save_tests() {
+        let path = temp_save_path("rtc-save");
+
+        let mut cartridge = Cartridge::new(battery_rom(0x10));
+        cartridge.attach_save_file(&path).unwrap();
+        cartridge.wb_rom(0x0000, 0x0A);
+        cartridge.wb_ram(0xA000, 0x99);
+        cartridge.flush().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap().len(), 0x2000 + 48);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unmapped_rtc_register_tests() {
+        let mut cartridge = Cartridge::new(battery_rom(0x10));
+        cartridge.wb_rom(0x0000, 0x0A);
+        for bank in 0x0D..=0x0F {
+            cartridge.wb_rom(0x4000, bank);
+            cartridge.wb_ram(0xA000, 0x12);
+            assert_eq!(cartridge.rb_ram(0xA000), 0xFF);
+        }
+        cartridge.wb_rom(0x4000, 0x00);
+        assert_eq!(cartridge.rb_ram(0xA000), 0x00);
+    }
+}
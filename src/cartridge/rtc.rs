@@ -0,0 +1,275 @@
+use crate::cpu::CLOCK_SPEED;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// the real time clock embedded in MBC3 cartridges
+#[derive(Default)]
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    /// copy of the registers taken on the last latch, this is what the cpu reads
+    latched: [u8; 5],
+    /// a latch happens when 0x00 then 0x01 are written to 0x6000-0x7FFF
+    latch_armed: bool,
+    /// cycles elapsed since the last second tick
+    sub_second: u32,
+}
+
+impl Rtc {
+    /// read one of the latched registers. `register` is the value written to select it (0x08-0x0C)
+    pub fn rb(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    /// write one of the live registers. `register` is the value written to select it (0x08-0x0C)
+    pub fn wb(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.sub_second = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halted = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// handle a write to the latch register
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.latched = self.registers();
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    /// advance the clock by a number of cpu cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if self.halted {
+            return;
+        }
+        self.sub_second += cycles;
+        let seconds = self.sub_second / CLOCK_SPEED;
+        self.sub_second %= CLOCK_SPEED;
+        self.add_seconds(seconds as u64);
+    }
+
+    /// advance the clock by a number of real world seconds, used to catch up on the time that
+    /// passed while the emulator was not running
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        if self.halted {
+            return;
+        }
+        self.add_seconds(seconds);
+    }
+
+    fn add_seconds(&mut self, seconds: u64) {
+        let minutes = count(&mut self.seconds, seconds, 60, 0x40);
+        let hours = count(&mut self.minutes, minutes, 60, 0x40);
+        let days = count(&mut self.hours, hours, 24, 0x20) + self.days as u64;
+        self.days = (days % 512) as u16;
+        if days >= 512 {
+            self.day_carry = true;
+        }
+    }
+
+    /// current value of the live registers, in register order
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 | (self.halted as u8) << 6 | (self.day_carry as u8) << 7,
+        ]
+    }
+
+    /// serialize the clock the way BGB and SameBoy append it to save files: the live registers,
+    /// then the latched registers, each as a 32 bit little endian word, followed by a 64 bit
+    /// unix timestamp
+    pub fn save_footer(&self, timestamp: u64) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(48);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    /// restore the clock from a save file footer. Both the 48 bytes format and the older 44 bytes
+    /// format with a 32 bit timestamp are accepted. Returns the timestamp stored in the footer.
+    pub fn load_footer(&mut self, footer: &[u8]) -> Option<u64> {
+        let word = |index: usize| {
+            let bytes = &footer[index * 4..index * 4 + 4];
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        };
+        let timestamp = match footer.len() {
+            48 => word(10) as u64 | (word(11) as u64) << 32,
+            44 => word(10) as u64,
+            _ => return None,
+        };
+
+        for register in 0..5 {
+            self.wb(0x08 + register as u8, word(register) as u8);
+            self.latched[register] = word(register + 5) as u8;
+        }
+        Some(timestamp)
+    }
+}
+
+/// add `amount` to a counter that wraps at `modulo` and return how many times it wrapped. An out
+/// of range value written by the game keeps counting until its bits overflow at `limit`, without
+/// carrying into the next counter.
+fn count(value: &mut u8, amount: u64, modulo: u64, limit: u64) -> u64 {
+    let mut amount = amount;
+    if *value as u64 >= modulo {
+        let overflow = limit - *value as u64;
+        if amount < overflow {
+            *value += amount as u8;
+            return 0;
+        }
+        amount -= overflow;
+        *value = 0;
+    }
+    let total = *value as u64 + amount;
+    *value = (total % modulo) as u8;
+    total / modulo
+}
+
+impl Snapshot for Rtc {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.seconds);
+        state.write_u8(self.minutes);
+        state.write_u8(self.hours);
+        state.write_u16(self.days);
+        state.write_bool(self.halted);
+        state.write_bool(self.day_carry);
+        state.write_bytes(&self.latched);
+        state.write_bool(self.latch_armed);
+        state.write_u32(self.sub_second);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = state.read_u8()?;
+        self.minutes = state.read_u8()?;
+        self.hours = state.read_u8()?;
+        self.days = state.read_u16()?;
+        self.halted = state.read_bool()?;
+        self.day_carry = state.read_bool()?;
+        state.read_bytes_into(&mut self.latched)?;
+        self.latch_armed = state.read_bool()?;
+        self.sub_second = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_tests() {
+        let mut rtc = Rtc::default();
+        rtc.wb(0x08, 59);
+        rtc.wb(0x09, 59);
+        rtc.wb(0x0A, 23);
+        rtc.wb(0x0B, 0xFF);
+        rtc.wb(0x0C, 0x01);
+
+        rtc.tick(CLOCK_SPEED - 1);
+        assert_eq!(rtc.registers(), [59, 59, 23, 0xFF, 0x01]);
+
+        rtc.tick(1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn advance_tests() {
+        let mut rtc = Rtc::default();
+        rtc.advance_seconds(((511 * 24 + 23) * 60 + 59) * 60 + 59);
+        assert_eq!(rtc.registers(), [59, 59, 23, 0xFF, 0x01]);
+
+        rtc.advance_seconds(1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0x80]);
+
+        // years away from the game, the clock still lands on the right time
+        let mut rtc = Rtc::default();
+        rtc.advance_seconds(1_000 * 24 * 60 * 60 + 90);
+        assert_eq!(rtc.registers(), [30, 1, 0, (1_000 - 512) as u8, 0x81]);
+    }
+
+    #[test]
+    fn out_of_range_tests() {
+        let mut rtc = Rtc::default();
+        rtc.wb(0x08, 62);
+        rtc.wb(0x09, 63);
+        rtc.wb(0x0A, 30);
+
+        // the seconds overflow their 6 bits without carrying into the minutes
+        rtc.advance_seconds(1);
+        assert_eq!(rtc.registers(), [63, 63, 30, 0, 0]);
+        rtc.advance_seconds(1);
+        assert_eq!(rtc.registers(), [0, 63, 30, 0, 0]);
+
+        // then count normally, the minutes and hours overflow the same way
+        rtc.advance_seconds(60);
+        assert_eq!(rtc.registers(), [0, 0, 30, 0, 0]);
+        rtc.advance_seconds(2 * 60 * 60);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn halt_tests() {
+        let mut rtc = Rtc::default();
+        rtc.wb(0x0C, 0x40);
+
+        rtc.tick(CLOCK_SPEED * 3);
+        rtc.advance_seconds(10);
+
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0x40]);
+    }
+
+    #[test]
+    fn latch_tests() {
+        let mut rtc = Rtc::default();
+        rtc.advance_seconds(61);
+
+        assert_eq!(rtc.rb(0x08), 0);
+
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.rb(0x08), 0);
+
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.rb(0x08), 1);
+        assert_eq!(rtc.rb(0x09), 1);
+    }
+
+    #[test]
+    fn footer_tests() {
+        let mut rtc = Rtc::default();
+        rtc.advance_seconds(3 * 60 + 7);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+
+        let footer = rtc.save_footer(0x1234_5678_9ABC);
+        assert_eq!(footer.len(), 48);
+        assert_eq!(&footer[0..8], &[7, 0, 0, 0, 3, 0, 0, 0]);
+
+        let mut restored = Rtc::default();
+        assert_eq!(restored.load_footer(&footer), Some(0x1234_5678_9ABC));
+        assert_eq!(restored.registers(), rtc.registers());
+        assert_eq!(restored.rb(0x09), 3);
+
+        assert_eq!(restored.load_footer(&footer[..44]), Some(0x5678_9ABC));
+        assert_eq!(restored.load_footer(&footer[..40]), None);
+    }
+}
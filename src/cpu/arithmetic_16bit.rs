@@ -0,0 +1,186 @@
+use super::Register;
+use super::CPU;
+use crate::ppu::OamCorruption;
+
+impl CPU {
+    pub fn inc_ss(&mut self, ss: u8) {
+        let value = self.register_pair(ss);
+        // the pointer goes through the address bus of the incrementer, which trips the OAM bug
+        self.mmu.corrupt_oam(value, OamCorruption::Write);
+        self.set_register_pair(ss, value.wrapping_add(1));
+    }
+
+    pub fn dec_ss(&mut self, ss: u8) {
+        let value = self.register_pair(ss);
+        self.mmu.corrupt_oam(value, OamCorruption::Write);
+        self.set_register_pair(ss, value.wrapping_sub(1));
+    }
+
+    /// add BC, DE, HL or SP to HL. Z is left alone, H and C come from bits 11 and 15.
+    pub fn add_hl_ss(&mut self, ss: u8) {
+        let hl = self.register_pair(0b10);
+        let operand = self.register_pair(ss);
+        let (result, carry) = hl.overflowing_add(operand);
+        let half_carry = (hl & 0x0FFF) + (operand & 0x0FFF) > 0x0FFF;
+        let zero = self.registers[Register::F] & (1 << 7) != 0;
+        self.set_flags(zero, false, half_carry, carry);
+        self.set_register_pair(0b10, result);
+    }
+
+    pub fn add_sp_e(&mut self) {
+        self.stack_pointer = self.stack_pointer_offset();
+    }
+
+    /// SP plus the signed offset following the opcode. The flags are those of adding the offset
+    /// to the lower byte of SP, as unsigned numbers.
+    pub(super) fn stack_pointer_offset(&mut self) -> u16 {
+        // types are strictly the same size so a plain cast reinterprets the bits just fine
+        let offset = self.read_immediate() as i8;
+        let [_, lower] = self.stack_pointer.to_be_bytes();
+        let half_carry = (lower & 0x0F) + (offset as u8 & 0x0F) > 0x0F;
+        let carry = lower.overflowing_add(offset as u8).1;
+        self.set_flags(false, false, half_carry, carry);
+        self.stack_pointer.wrapping_add(offset as u16)
+    }
+
+    /// value of BC, DE, HL or SP
+    fn register_pair(&self, ss: u8) -> u16 {
+        match ss {
+            0b00 => u16::from_be_bytes([self.registers[Register::B], self.registers[Register::C]]),
+            0b01 => u16::from_be_bytes([self.registers[Register::D], self.registers[Register::E]]),
+            0b10 => u16::from_be_bytes([self.registers[Register::H], self.registers[Register::L]]),
+            0b11 => self.stack_pointer,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_register_pair(&mut self, ss: u8, value: u16) {
+        let [upper, lower] = value.to_be_bytes();
+        match ss {
+            0b00 => {
+                self.registers[Register::B] = upper;
+                self.registers[Register::C] = lower;
+            }
+            0b01 => {
+                self.registers[Register::D] = upper;
+                self.registers[Register::E] = lower;
+            }
+            0b10 => {
+                self.registers[Register::H] = upper;
+                self.registers[Register::L] = lower;
+            }
+            0b11 => self.stack_pointer = value,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inc_ss_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::B] = 0x12;
+        cpu.registers[Register::C] = 0xFF;
+        cpu.registers[Register::F] = 0xF0;
+
+        let instruction = 0b00_000_011;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::B], 0x13);
+        assert_eq!(cpu.registers[Register::C], 0x00);
+        assert_eq!(cpu.registers[Register::F], 0xF0);
+
+        cpu.stack_pointer = 0xFFFF;
+        let instruction = 0b00_110_011;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.stack_pointer, 0x0000);
+    }
+
+    #[test]
+    fn dec_ss_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::D] = 0x12;
+        cpu.registers[Register::E] = 0x00;
+
+        let instruction = 0b00_011_011;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::D], 0x11);
+        assert_eq!(cpu.registers[Register::E], 0xFF);
+
+        let instruction = 0b00_101_011;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::H], 0xFF);
+        assert_eq!(cpu.registers[Register::L], 0xFF);
+    }
+
+    #[test]
+    fn add_hl_ss_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::H] = 0x8A;
+        cpu.registers[Register::L] = 0x23;
+        cpu.registers[Register::B] = 0x06;
+        cpu.registers[Register::C] = 0x05;
+        cpu.registers[Register::F] = 0b11000000;
+
+        let instruction = 0b00_001_001;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::H], 0x90);
+        assert_eq!(cpu.registers[Register::L], 0x28);
+        assert_eq!(cpu.registers[Register::F], 0b10100000);
+
+        let instruction = 0b00_101_001;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::H], 0x20);
+        assert_eq!(cpu.registers[Register::L], 0x50);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
+    }
+
+    #[test]
+    fn add_sp_e_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0x02);
+        cpu.mmu.wb(0x1, 0xFE);
+        cpu.stack_pointer = 0xFFF8;
+
+        let instruction = 0b11_101_000;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.stack_pointer, 0xFFFA);
+        assert_eq!(cpu.registers[Register::F], 0b00000000);
+
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.stack_pointer, 0xFFF8);
+        assert_eq!(cpu.registers[Register::F], 0b00110000);
+    }
+
+    #[test]
+    fn oam_bug_tests() {
+        for enabled in [false, true].iter() {
+            let mut cpu = CPU::default();
+            cpu.mmu.wb(0xFE08, 0x0F);
+            cpu.mmu.wb(0xFE10, 0x42);
+            cpu.mmu.ppu_mut().set_oam_bug(*enabled);
+            cpu.mmu.wb(0xFF40, 0x80);
+            // the OAM scan is reading the third row
+            cpu.mmu.tick(8);
+            cpu.registers[Register::H] = 0xFE;
+            cpu.registers[Register::L] = 0x80;
+
+            let instruction = 0b00_100_011;
+            cpu.execute(instruction);
+
+            assert_eq!(cpu.registers[Register::L], 0x81);
+            let corrupted = if *enabled { 0x02 } else { 0x42 };
+            assert_eq!(cpu.mmu.peek(0xFE10), corrupted);
+        }
+    }
+}
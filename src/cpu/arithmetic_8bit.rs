@@ -1,60 +1,128 @@
-use std::usize;
-
 use crate::cpu::Register;
 use crate::cpu::CPU;
 
 impl CPU {
-    pub fn add_a_r(&mut self, register: u8) {
-        let (result, carry) =
-            self.registers[Register::A].overflowing_add(self.registers[register as usize]);
-        self.set_add_flags(
-            self.registers[Register::A],
-            self.registers[register as usize],
-            result,
-            carry,
-        );
-        self.registers[Register::A] = result;
+    pub fn alu_a_r(&mut self, op: u8, register: u8) {
+        self.alu(op, self.registers[register as usize]);
     }
 
-    pub fn add_a_n(&mut self) {
-        let (result, carry) =
-            self.registers[Register::A].overflowing_add(self.mmu.rb(self.program_counter));
-        self.set_add_flags(
-            self.registers[Register::A],
-            self.mmu.rb(self.program_counter),
-            result,
-            carry,
-        );
-        self.registers[Register::A] = result;
-        self.program_counter += 1;
-    }
-
-    pub fn add_a_hl(&mut self) {
-        let memory_pointer =
-            u16::from_be_bytes([self.registers[Register::H], self.registers[Register::L]]);
-        let (result, carry) =
-            self.registers[Register::A].overflowing_add(self.mmu.rb(memory_pointer));
-        self.set_add_flags(
-            self.registers[Register::A],
-            self.mmu.rb(memory_pointer),
-            result,
-            carry,
-        );
-        self.registers[Register::A] = result;
-        self.program_counter += 1;
+    pub fn alu_a_n(&mut self, op: u8) {
+        let operand = self.read_immediate();
+        self.alu(op, operand);
     }
 
-    fn set_add_flags(&mut self, a: u8, b: u8, result: u8, carry: bool) {
-        self.registers[Register::F] = 0x0;
-        if carry {
-            self.registers[Register::F] |= 1 << 4;
-        }
-        if Self::half_carry(a, b, result) {
-            self.registers[Register::F] |= 1 << 5;
+    pub fn alu_a_hl(&mut self, op: u8) {
+        let operand = self.read(self.hl());
+        self.alu(op, operand);
+    }
+
+    /// the operation encoded in the arithmetic and logic instructions, on A and an operand:
+    ///
+    /// | op  | operation |
+    /// | 000 | ADD       |
+    /// | 001 | ADC       |
+    /// | 010 | SUB       |
+    /// | 011 | SBC       |
+    /// | 100 | AND       |
+    /// | 101 | XOR       |
+    /// | 110 | OR        |
+    /// | 111 | CP        |
+    fn alu(&mut self, op: u8, operand: u8) {
+        let a = self.registers[Register::A];
+        self.registers[Register::A] = match op {
+            0b000 => self.add_with_carry(operand, false),
+            0b001 => self.add_with_carry(operand, self.carry()),
+            0b010 => self.subtract_with_carry(operand, false),
+            0b011 => self.subtract_with_carry(operand, self.carry()),
+            0b100 => {
+                self.set_flags(a & operand == 0, false, true, false);
+                a & operand
+            }
+            0b101 => {
+                self.set_flags(a ^ operand == 0, false, false, false);
+                a ^ operand
+            }
+            0b110 => {
+                self.set_flags(a | operand == 0, false, false, false);
+                a | operand
+            }
+            // CP only keeps the flags of the subtraction
+            0b111 => {
+                self.subtract_with_carry(operand, false);
+                a
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    /// A + operand + carry, with the flags set
+    fn add_with_carry(&mut self, operand: u8, carry: bool) -> u8 {
+        let a = self.registers[Register::A];
+        let result = a as u16 + operand as u16 + carry as u16;
+        let half_carry = Self::half_carry(a, operand, result as u8);
+        self.set_flags(result as u8 == 0, false, half_carry, result > 0xFF);
+        result as u8
+    }
+
+    /// A - operand - carry, with the flags set
+    fn subtract_with_carry(&mut self, operand: u8, carry: bool) -> u8 {
+        let a = self.registers[Register::A];
+        let result = a.wrapping_sub(operand).wrapping_sub(carry as u8);
+        let half_carry = Self::half_carry(a, operand, result);
+        let borrow = (a as u16) < operand as u16 + carry as u16;
+        self.set_flags(result == 0, true, half_carry, borrow);
+        result
+    }
+
+    /// increment a register or the byte at HL, C is left alone
+    pub fn inc_r(&mut self, r: u8) {
+        let result = self.read_operand(r).wrapping_add(1);
+        self.write_operand(r, result);
+        self.set_flags(result == 0, false, result & 0x0F == 0, self.carry());
+    }
+
+    /// decrement a register or the byte at HL, C is left alone
+    pub fn dec_r(&mut self, r: u8) {
+        let result = self.read_operand(r).wrapping_sub(1);
+        self.write_operand(r, result);
+        self.set_flags(result == 0, true, result & 0x0F == 0x0F, self.carry());
+    }
+
+    /// adjust A to binary coded decimal after an addition or a subtraction of two BCD numbers
+    pub fn daa(&mut self) {
+        let flags = self.registers[Register::F];
+        let subtract = flags & (1 << 6) != 0;
+        let half_carry = flags & (1 << 5) != 0;
+        let mut carry = self.carry();
+        let a = self.registers[Register::A];
+
+        let mut adjust = 0;
+        if half_carry || (!subtract && a & 0x0F > 0x09) {
+            adjust |= 0x06;
         }
-        if result == 0 {
-            self.registers[Register::F] |= 1 << 7;
+        if carry || (!subtract && a > 0x99) {
+            adjust |= 0x60;
+            carry = true;
         }
+        let result = match subtract {
+            true => a.wrapping_sub(adjust),
+            false => a.wrapping_add(adjust),
+        };
+        self.registers[Register::A] = result;
+        self.set_flags(result == 0, subtract, false, carry);
+    }
+
+    pub fn cpl(&mut self) {
+        self.registers[Register::A] = !self.registers[Register::A];
+        self.registers[Register::F] |= 0b01100000;
+    }
+
+    pub fn scf(&mut self) {
+        self.registers[Register::F] = self.registers[Register::F] & 0b10000000 | 0b00010000;
+    }
+
+    pub fn ccf(&mut self) {
+        self.registers[Register::F] = (self.registers[Register::F] ^ 0b00010000) & 0b10010000;
     }
 }
 
@@ -123,5 +191,131 @@ mod tests {
         cpu.execute(instruction);
 
         assert_eq!(cpu.mmu.rb(0x271C), 0xF4);
+        assert_eq!(cpu.registers[Register::A], 0xF4);
+        assert_eq!(cpu.program_counter, 0);
+    }
+
+    #[test]
+    fn alu_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::A] = 0xE1;
+        cpu.registers[Register::B] = 0x0F;
+        cpu.registers[Register::F] = 0b00010000;
+
+        // ADC A,B
+        cpu.execute(0b10_001_000);
+        assert_eq!(cpu.registers[Register::A], 0xF1);
+        assert_eq!(cpu.registers[Register::F], 0b00100000);
+
+        // SUB B
+        cpu.execute(0b10_010_000);
+        assert_eq!(cpu.registers[Register::A], 0xE2);
+        assert_eq!(cpu.registers[Register::F], 0b01100000);
+
+        // SBC A,B with the carry set
+        cpu.registers[Register::F] = 0b00010000;
+        cpu.registers[Register::A] = 0x3B;
+        cpu.registers[Register::B] = 0x4F;
+        cpu.execute(0b10_011_000);
+        assert_eq!(cpu.registers[Register::A], 0xEB);
+        assert_eq!(cpu.registers[Register::F], 0b01110000);
+
+        // AND B
+        cpu.execute(0b10_100_000);
+        assert_eq!(cpu.registers[Register::A], 0x4B);
+        assert_eq!(cpu.registers[Register::F], 0b00100000);
+
+        // OR B
+        cpu.execute(0b10_110_000);
+        assert_eq!(cpu.registers[Register::A], 0x4F);
+        assert_eq!(cpu.registers[Register::F], 0b00000000);
+
+        // CP B leaves A alone
+        cpu.execute(0b10_111_000);
+        assert_eq!(cpu.registers[Register::A], 0x4F);
+        assert_eq!(cpu.registers[Register::F], 0b11000000);
+
+        // XOR A
+        cpu.execute(0b10_101_111);
+        assert_eq!(cpu.registers[Register::A], 0x00);
+        assert_eq!(cpu.registers[Register::F], 0b10000000);
+
+        // CP 0x01, from the operand
+        cpu.mmu.wb(0x0, 0x01);
+        cpu.execute(0b11_111_110);
+        assert_eq!(cpu.registers[Register::F], 0b01110000);
+        assert_eq!(cpu.program_counter, 1);
+    }
+
+    #[test]
+    fn inc_dec_r_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::B] = 0x0F;
+        cpu.registers[Register::F] = 0b00010000;
+
+        cpu.execute(0b00_000_100);
+        assert_eq!(cpu.registers[Register::B], 0x10);
+        assert_eq!(cpu.registers[Register::F], 0b00110000);
+
+        cpu.registers[Register::E] = 0x01;
+        cpu.execute(0b00_011_101);
+        assert_eq!(cpu.registers[Register::E], 0x00);
+        assert_eq!(cpu.registers[Register::F], 0b11010000);
+
+        // DEC (HL)
+        cpu.registers[Register::H] = 0xC0;
+        cpu.registers[Register::L] = 0x00;
+        cpu.execute(0b00_110_101);
+        assert_eq!(cpu.mmu.rb(0xC000), 0xFF);
+        assert_eq!(cpu.registers[Register::F], 0b01110000);
+    }
+
+    #[test]
+    fn daa_tests() {
+        let mut cpu = CPU::default();
+
+        // 0x45 + 0x38 = 0x83 in BCD
+        cpu.registers[Register::A] = 0x45;
+        cpu.registers[Register::B] = 0x38;
+        cpu.execute(0b10_000_000);
+        cpu.execute(0b00_100_111);
+        assert_eq!(cpu.registers[Register::A], 0x83);
+        assert_eq!(cpu.registers[Register::F], 0b00000000);
+
+        // 0x83 - 0x38 = 0x45 in BCD
+        cpu.execute(0b10_010_000);
+        cpu.execute(0b00_100_111);
+        assert_eq!(cpu.registers[Register::A], 0x45);
+        assert_eq!(cpu.registers[Register::F], 0b01000000);
+
+        // 0x99 + 0x01 = 0x00 with a carry
+        cpu.registers[Register::A] = 0x99;
+        cpu.registers[Register::B] = 0x01;
+        cpu.execute(0b10_000_000);
+        cpu.execute(0b00_100_111);
+        assert_eq!(cpu.registers[Register::A], 0x00);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
+    }
+
+    #[test]
+    fn flag_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::A] = 0x35;
+        cpu.registers[Register::F] = 0b10000000;
+
+        // CPL
+        cpu.execute(0b00_101_111);
+        assert_eq!(cpu.registers[Register::A], 0xCA);
+        assert_eq!(cpu.registers[Register::F], 0b11100000);
+
+        // SCF
+        cpu.execute(0b00_110_111);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
+
+        // CCF
+        cpu.execute(0b00_111_111);
+        assert_eq!(cpu.registers[Register::F], 0b10000000);
+        cpu.execute(0b00_111_111);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
     }
 }
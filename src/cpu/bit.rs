@@ -0,0 +1,55 @@
+use super::CPU;
+
+impl CPU {
+    /// set Z when bit b of a register or of the byte at HL is clear
+    pub fn bit_b_r(&mut self, b: u8, r: u8) {
+        let value = self.read_operand(r);
+        let carry = self.carry();
+        self.set_flags(value & (1 << b) == 0, false, true, carry);
+    }
+
+    pub fn set_b_r(&mut self, b: u8, r: u8) {
+        let value = self.read_operand(r);
+        self.write_operand(r, value | 1 << b);
+    }
+
+    pub fn res_b_r(&mut self, b: u8, r: u8) {
+        let value = self.read_operand(r);
+        self.write_operand(r, value & !(1 << b));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Register;
+    use super::*;
+
+    #[test]
+    fn bit_tests() {
+        let mut cpu = CPU::default();
+        // BIT 7,H, BIT 0,(HL), SET 3,(HL), RES 7,H
+        for (offset, byte) in [0xCB, 0x7C, 0xCB, 0x46, 0xCB, 0xDE, 0xCB, 0xBC]
+            .iter()
+            .enumerate()
+        {
+            cpu.mmu.wb(offset as u16, *byte);
+        }
+        cpu.registers[Register::H] = 0xC0;
+        cpu.registers[Register::L] = 0x10;
+        cpu.registers[Register::F] = 0b00010000;
+        cpu.mmu.wb(0xC010, 0x02);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers[Register::F], 0b00110000);
+
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.registers[Register::F], 0b10110000);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.mmu.rb(0xC010), 0x0A);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers[Register::H], 0x40);
+        assert_eq!(cpu.registers[Register::F], 0b10110000);
+    }
+}
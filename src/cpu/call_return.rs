@@ -0,0 +1,148 @@
+use super::CPU;
+
+impl CPU {
+    pub fn call_nn(&mut self) {
+        let address = self.read_immediate_word();
+        self.internal_cycle();
+        self.push_program_counter();
+        self.program_counter = address;
+    }
+
+    pub fn call_cc_nn(&mut self, cc: u8) {
+        let address = self.read_immediate_word();
+        if self.condition(cc) {
+            self.internal_cycle();
+            self.push_program_counter();
+            self.program_counter = address;
+            self.extra_cycles = 3;
+        }
+    }
+
+    pub fn ret(&mut self) {
+        self.pop_program_counter();
+    }
+
+    pub fn ret_cc(&mut self, cc: u8) {
+        if self.condition(cc) {
+            self.pop_program_counter();
+            self.extra_cycles = 3;
+        }
+    }
+
+    /// return from an interrupt handler, interrupts are enabled right away unlike with EI
+    pub fn reti(&mut self) {
+        self.pop_program_counter();
+        self.interrupt_master_enable = true;
+    }
+
+    /// call one of the 8 restart addresses, 0x00, 0x08, ... 0x38
+    pub fn rst(&mut self, t: u8) {
+        self.internal_cycle();
+        self.push_program_counter();
+        self.program_counter = t as u16 * 8;
+    }
+
+    pub(super) fn push_program_counter(&mut self) {
+        let [upper, lower] = self.program_counter.to_be_bytes();
+        self.stack_pointer = self.stack_pointer.wrapping_sub(2);
+        self.write(self.stack_pointer.wrapping_add(1), upper);
+        self.write(self.stack_pointer, lower);
+    }
+
+    fn pop_program_counter(&mut self) {
+        let lower = self.read(self.stack_pointer);
+        let upper = self.read(self.stack_pointer.wrapping_add(1));
+        self.program_counter = u16::from_le_bytes([lower, upper]);
+        self.stack_pointer = self.stack_pointer.wrapping_add(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Register;
+
+    #[test]
+    fn call_ret_tests() {
+        let mut cpu = CPU {
+            stack_pointer: 0xFFFE,
+            ..CPU::default()
+        };
+        cpu.mmu.wb(0x0, 0xCD);
+        cpu.mmu.wb(0x1, 0x00);
+        cpu.mmu.wb(0x2, 0x10);
+        cpu.mmu.wb(0x1000, 0xC9);
+
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.program_counter, 0x1000);
+        assert_eq!(cpu.stack_pointer, 0xFFFC);
+        assert_eq!(cpu.mmu.rb(0xFFFC), 0x03);
+        assert_eq!(cpu.mmu.rb(0xFFFD), 0x00);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.program_counter, 0x0003);
+        assert_eq!(cpu.stack_pointer, 0xFFFE);
+    }
+
+    #[test]
+    fn call_cc_ret_cc_tests() {
+        let mut cpu = CPU {
+            stack_pointer: 0xFFFE,
+            ..CPU::default()
+        };
+        cpu.mmu.wb(0x0, 0xDC);
+        cpu.mmu.wb(0x1, 0x00);
+        cpu.mmu.wb(0x2, 0x10);
+        cpu.mmu.wb(0x3, 0xD4);
+        cpu.mmu.wb(0x4, 0x00);
+        cpu.mmu.wb(0x5, 0x10);
+        cpu.mmu.wb(0x1000, 0xC8);
+        cpu.mmu.wb(0x1001, 0xC0);
+
+        // C is clear: CALL C falls through, CALL NC calls
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.program_counter, 0x3);
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.program_counter, 0x1000);
+
+        // Z is clear: RET Z falls through, RET NZ returns
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.program_counter, 0x1001);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.program_counter, 0x6);
+        assert_eq!(cpu.stack_pointer, 0xFFFE);
+        assert_eq!(cpu.registers[Register::F], 0);
+    }
+
+    #[test]
+    fn reti_tests() {
+        let mut cpu = CPU {
+            stack_pointer: 0xFFFC,
+            ..CPU::default()
+        };
+        cpu.mmu.wb(0xFFFC, 0x34);
+        cpu.mmu.wb(0xFFFD, 0x12);
+
+        let instruction = 0b11011001;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.program_counter, 0x1234);
+        assert!(cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn rst_tests() {
+        let mut cpu = CPU {
+            stack_pointer: 0xFFFE,
+            program_counter: 0x1234,
+            ..CPU::default()
+        };
+
+        let instruction = 0b11101111;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.program_counter, 0x28);
+        assert_eq!(cpu.mmu.rb(0xFFFD), 0x12);
+        assert_eq!(cpu.mmu.rb(0xFFFC), 0x34);
+    }
+}
@@ -0,0 +1,127 @@
+use super::Register;
+use super::CPU;
+
+impl CPU {
+    pub fn jp_nn(&mut self) {
+        self.program_counter = self.read_immediate_word();
+    }
+
+    pub fn jp_cc_nn(&mut self, cc: u8) {
+        let address = self.read_immediate_word();
+        if self.condition(cc) {
+            self.program_counter = address;
+            self.extra_cycles = 1;
+        }
+    }
+
+    pub fn jp_hl(&mut self) {
+        self.program_counter =
+            u16::from_be_bytes([self.registers[Register::H], self.registers[Register::L]]);
+    }
+
+    pub fn jr_e(&mut self) {
+        let offset = self.read_immediate() as i8;
+        self.program_counter = self.program_counter.wrapping_add(offset as u16);
+    }
+
+    pub fn jr_cc_e(&mut self, cc: u8) {
+        let offset = self.read_immediate() as i8;
+        if self.condition(cc) {
+            self.program_counter = self.program_counter.wrapping_add(offset as u16);
+            self.extra_cycles = 1;
+        }
+    }
+
+    /// the condition encoded in conditional jumps, calls and returns:
+    ///
+    /// | cc | condition |
+    /// | 00 | NZ        |
+    /// | 01 | Z         |
+    /// | 10 | NC        |
+    /// | 11 | C         |
+    pub(super) fn condition(&self, cc: u8) -> bool {
+        let flags = self.registers[Register::F];
+        match cc {
+            0b00 => flags & (1 << 7) == 0,
+            0b01 => flags & (1 << 7) != 0,
+            0b10 => flags & (1 << 4) == 0,
+            0b11 => flags & (1 << 4) != 0,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jp_nn_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xC3);
+        cpu.mmu.wb(0x1, 0x34);
+        cpu.mmu.wb(0x2, 0x12);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test]
+    fn jp_cc_nn_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xCA);
+        cpu.mmu.wb(0x1, 0x34);
+        cpu.mmu.wb(0x2, 0x12);
+
+        // Z is clear, the jump is not taken
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.program_counter, 0x3);
+
+        cpu.program_counter = 0;
+        cpu.registers[Register::F] = 0x80;
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test]
+    fn jp_hl_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::H] = 0x12;
+        cpu.registers[Register::L] = 0x34;
+
+        let instruction = 0b11101001;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test]
+    fn jr_e_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x10, 0x18);
+        cpu.mmu.wb(0x11, 0x05);
+        cpu.mmu.wb(0x17, 0x18);
+        cpu.mmu.wb(0x18, 0xFE);
+        cpu.program_counter = 0x10;
+
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.program_counter, 0x17);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x17);
+    }
+
+    #[test]
+    fn jr_cc_e_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0x38);
+        cpu.mmu.wb(0x1, 0x10);
+        cpu.mmu.wb(0x2, 0x30);
+        cpu.mmu.wb(0x3, 0x10);
+
+        // C is clear: JR C falls through, JR NC jumps
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.program_counter, 0x2);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.program_counter, 0x14);
+    }
+}
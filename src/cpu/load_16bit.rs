@@ -1,30 +1,26 @@
 use super::Register;
 use super::CPU;
+use crate::ppu::OamCorruption;
 
 impl CPU {
     pub fn ld_dd_nn(&mut self, dd: u8) {
+        let [upper, lower] = self.read_immediate_word().to_be_bytes();
         match dd {
             0b00 => {
-                self.registers[Register::B] = self.mmu.rb(self.program_counter);
-                self.registers[Register::C] = self.mmu.rb(self.program_counter + 1);
+                self.registers[Register::B] = upper;
+                self.registers[Register::C] = lower;
             }
             0b01 => {
-                self.registers[Register::D] = self.mmu.rb(self.program_counter);
-                self.registers[Register::E] = self.mmu.rb(self.program_counter + 1);
+                self.registers[Register::D] = upper;
+                self.registers[Register::E] = lower;
             }
             0b10 => {
-                self.registers[Register::H] = self.mmu.rb(self.program_counter);
-                self.registers[Register::L] = self.mmu.rb(self.program_counter + 1);
-            }
-            0b11 => {
-                self.stack_pointer = u16::from_be_bytes([
-                    self.mmu.rb(self.program_counter),
-                    self.mmu.rb(self.program_counter + 1),
-                ]);
+                self.registers[Register::H] = upper;
+                self.registers[Register::L] = lower;
             }
+            0b11 => self.stack_pointer = u16::from_be_bytes([upper, lower]),
             _ => unreachable!(),
         }
-        self.program_counter += 2;
     }
 
     pub fn ld_sp_hl(&mut self) {
@@ -33,98 +29,68 @@ impl CPU {
     }
 
     pub fn push_qq(&mut self, qq: u8) {
-        self.stack_pointer -= 2;
-        match qq {
-            0b00 => {
-                self.mmu.ww(
-                    self.stack_pointer,
-                    u16::from_be_bytes([self.registers[Register::C], self.registers[Register::B]]),
-                );
-            }
-            0b01 => {
-                self.mmu.ww(
-                    self.stack_pointer,
-                    u16::from_be_bytes([self.registers[Register::E], self.registers[Register::D]]),
-                );
-            }
-            0b10 => {
-                self.mmu.ww(
-                    self.stack_pointer,
-                    u16::from_be_bytes([self.registers[Register::L], self.registers[Register::H]]),
-                );
-            }
-            0b11 => {
-                self.mmu.ww(
-                    self.stack_pointer,
-                    u16::from_be_bytes([self.registers[Register::F], self.registers[Register::A]]),
-                );
-            }
+        let (upper, lower) = match qq {
+            0b00 => (self.registers[Register::B], self.registers[Register::C]),
+            0b01 => (self.registers[Register::D], self.registers[Register::E]),
+            0b10 => (self.registers[Register::H], self.registers[Register::L]),
+            0b11 => (self.registers[Register::A], self.registers[Register::F]),
             _ => unreachable!(),
-        }
+        };
+        // the first decrement of SP happens on its own, the two writes then trip the OAM bug too
+        self.mmu
+            .corrupt_oam(self.stack_pointer, OamCorruption::Write);
+        self.internal_cycle();
+        self.stack_pointer = self.stack_pointer.wrapping_sub(2);
+        self.write(self.stack_pointer.wrapping_add(1), upper);
+        self.write(self.stack_pointer, lower);
     }
 
     pub fn pop_qq(&mut self, qq: u8) {
+        // each read goes along with an increment of SP
+        let lower = self.read_corrupting(self.stack_pointer, OamCorruption::ReadIncrease);
+        let upper = self.read_corrupting(
+            self.stack_pointer.wrapping_add(1),
+            OamCorruption::ReadIncrease,
+        );
         match qq {
             0b00 => {
-                self.registers[Register::C] = self.mmu.rb(self.stack_pointer);
-                self.registers[Register::B] = self.mmu.rb(self.stack_pointer + 1);
+                self.registers[Register::C] = lower;
+                self.registers[Register::B] = upper;
             }
             0b01 => {
-                self.registers[Register::E] = self.mmu.rb(self.stack_pointer);
-                self.registers[Register::D] = self.mmu.rb(self.stack_pointer + 1);
+                self.registers[Register::E] = lower;
+                self.registers[Register::D] = upper;
             }
             0b10 => {
-                self.registers[Register::L] = self.mmu.rb(self.stack_pointer);
-                self.registers[Register::H] = self.mmu.rb(self.stack_pointer + 1);
+                self.registers[Register::L] = lower;
+                self.registers[Register::H] = upper;
             }
             0b11 => {
-                self.registers[Register::F] = self.mmu.rb(self.stack_pointer);
-                self.registers[Register::A] = self.mmu.rb(self.stack_pointer + 1);
+                // the lower 4 bits of F are always zero
+                self.registers[Register::F] = lower & 0xF0;
+                self.registers[Register::A] = upper;
             }
             _ => unreachable!(),
         }
-        self.stack_pointer += 2;
+        self.stack_pointer = self.stack_pointer.wrapping_add(2);
     }
 
     pub fn ldhl_sp_e(&mut self) {
-        // types are strictly the same size so transmute seems just fine
-        let operand = unsafe { std::mem::transmute::<u8, i8>(self.mmu.rb(self.program_counter)) };
-
-        let (result, carry) = match operand.is_negative() {
-            true => self
-                .stack_pointer
-                .overflowing_sub(operand.unsigned_abs() as u16),
-            false => self
-                .stack_pointer
-                .overflowing_add(operand.unsigned_abs() as u16),
-        };
-
-        self.registers[Register::H] = result.to_be_bytes()[0];
-        self.registers[Register::L] = result.to_be_bytes()[1];
-        self.registers[Register::F] &= 0b00001111;
-        if carry {
-            self.registers[Register::F] |= 1 << 5;
-        }
-        if Self::half_carry(
-            self.stack_pointer.to_be_bytes()[0],
-            operand.unsigned_abs(),
-            result.to_be_bytes()[0],
-        ) {
-            self.registers[Register::F] |= 1 << 4;
-        }
-        self.program_counter += 1;
+        let [upper, lower] = self.stack_pointer_offset().to_be_bytes();
+        self.registers[Register::H] = upper;
+        self.registers[Register::L] = lower;
     }
 
     pub fn ld_nn_sp(&mut self) {
-        self.stack_pointer = u16::from_be_bytes([
-            self.mmu.rb(self.program_counter),
-            self.mmu.rb(self.program_counter + 1),
-        ]);
-        self.program_counter += 2;
+        let address = self.read_immediate_word();
+        let [upper, lower] = self.stack_pointer.to_be_bytes();
+        self.write(address, lower);
+        self.write(address.wrapping_add(1), upper);
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
 
@@ -132,8 +98,8 @@ mod tests {
     fn ld_dd_nn_tests() {
         let mut cpu = CPU::default();
 
-        cpu.mmu.wb(0x0, 0xCD);
-        cpu.mmu.wb(0x1, 0x1F);
+        cpu.mmu.wb(0x0, 0x1F);
+        cpu.mmu.wb(0x1, 0xCD);
 
         let instruction = 0b00_100_001;
 
@@ -144,8 +110,8 @@ mod tests {
         assert_eq!(cpu.registers[Register::L], 0x1F);
         assert_eq!(cpu.program_counter, 2);
 
-        cpu.mmu.wb(0x2, 0x57);
-        cpu.mmu.wb(0x3, 0xD3);
+        cpu.mmu.wb(0x2, 0xD3);
+        cpu.mmu.wb(0x3, 0x57);
 
         // load into SP
         let instruction = 0b00_110_001;
@@ -221,9 +187,10 @@ mod tests {
         let instruction = 0b11_111_000;
         cpu.execute(instruction);
 
+        // the flags come from 0x2A + 0xF5
         assert_eq!(cpu.registers[Register::H], 0x0F);
         assert_eq!(cpu.registers[Register::L], 0x1F);
-        assert_eq!(cpu.registers[Register::F], 0b00000000);
+        assert_eq!(cpu.registers[Register::F], 0b00010000);
         assert_eq!(cpu.program_counter, 0x2);
     }
 
@@ -231,12 +198,15 @@ mod tests {
     fn ld_nn_sp_tests() {
         let mut cpu = CPU::default();
 
-        cpu.mmu.wb(0x0, 0x73);
-        cpu.mmu.wb(0x1, 0xE1);
+        cpu.stack_pointer = 0x73E1;
+        cpu.mmu.wb(0x0, 0x00);
+        cpu.mmu.wb(0x1, 0xC1);
 
         let instruction = 0b00_001_000;
         cpu.execute(instruction);
 
+        assert_eq!(cpu.mmu.rb(0xC100), 0xE1);
+        assert_eq!(cpu.mmu.rb(0xC101), 0x73);
         assert_eq!(cpu.stack_pointer, 0x73E1);
         assert_eq!(cpu.program_counter, 0x2);
     }
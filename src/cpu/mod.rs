@@ -1,10 +1,40 @@
-use std::usize;
-
-use crate::mmu::MMU;
+use crate::cartridge::Cartridge;
+use crate::mmu::{Model, MMU};
+use crate::ppu::{CompatibilityPalettes, OamCorruption, CYCLES_PER_FRAME};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 
+mod arithmetic_16bit;
 mod arithmetic_8bit;
+mod bit;
+mod call_return;
+mod jump;
 mod load_16bit;
 mod load_8bit;
+mod rotate_shift;
+
+/// number of cpu cycles per second
+pub const CLOCK_SPEED: u32 = 4_194_304;
+
+/// duration of each instruction in machine cycles (4 clock cycles), when no branch is taken
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4,
+    2, 3, 3, 1, 3, 4, 2, 4, 2, 4, 3, 1, 3, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 4, 1, 4, 1, 1, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 3, 2, 4, 1, 1, 1, 2, 4,
+];
 
 struct Register;
 impl Register {
@@ -18,6 +48,7 @@ impl Register {
     const L: usize = 0b101;
 }
 
+#[derive(Default)]
 pub struct CPU {
     /// the code for each register is as follows :
     ///
@@ -31,43 +62,257 @@ pub struct CPU {
     /// | H        | 100  |
     /// | L        | 101  |
     ///
-    /// [^1] : F is not a regular register. Opcode where you might expect 110 to refer to this
+    /// [^1]: F is not a regular register. Opcode where you might expect 110 to refer to this
     /// register usually refer to a complety different instruction.
     registers: [u8; 8],
     program_counter: u16,
     stack_pointer: u16,
+    /// IME, whether interrupts are dispatched at all
+    interrupt_master_enable: bool,
+    /// EI only enables interrupts after the instruction following it
+    interrupt_enable_scheduled: bool,
+    /// set by HALT, the cpu stops executing instructions until an interrupt is pending
+    halted: bool,
+    /// set by STOP, the whole system is asleep until a button of a selected joypad row is held
+    stopped: bool,
+    /// machine cycles the instruction takes on top of its duration in `CYCLES`, for taken
+    /// conditional branches and the instructions behind the CB prefix
+    extra_cycles: u8,
+    /// cpu cycles of the current step the rest of the hardware already ran for, one machine
+    /// cycle for each memory access made so far
+    ticked: u32,
+    /// clock cycles elapsed since power on, at the normal speed clock
+    cycles: u64,
     mmu: MMU,
 }
 
-impl Default for CPU {
-    fn default() -> Self {
-        Self {
-            registers: [0; 8],
-            program_counter: 0,
-            stack_pointer: 0,
-            mmu: MMU::default(),
+impl CPU {
+    /// create a DMG with the cartridge inserted, in the state the boot rom leaves it in
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self::with_model(cartridge, Model::Dmg)
+    }
+
+    /// create a system of the given model with the cartridge inserted, in the state the boot
+    /// rom leaves it in. `Model::for_cartridge` picks the model the cartridge is meant for.
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Self {
+        let mut cpu = Self {
+            mmu: MMU::new(model),
+            ..Self::default()
+        };
+        let registers = match model {
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
+        for (register, value) in [
+            Register::A,
+            Register::F,
+            Register::B,
+            Register::C,
+            Register::D,
+            Register::E,
+            Register::H,
+            Register::L,
+        ]
+        .iter()
+        .zip(registers.iter())
+        {
+            cpu.registers[*register] = *value;
+        }
+        cpu.program_counter = 0x0100;
+        cpu.stack_pointer = 0xFFFE;
+        // the boot rom of the CGB colors the cartridges made for the DMG
+        if model == Model::Cgb && Model::for_cartridge(cartridge.header()) == Model::Dmg {
+            let palettes = CompatibilityPalettes::for_cartridge(&cartridge);
+            cpu.mmu.ppu_mut().set_compatibility_palettes(&palettes);
         }
+        cpu.mmu.insert_cartridge(cartridge);
+        cpu
+    }
+
+    pub fn mmu(&self) -> &MMU {
+        &self.mmu
+    }
+
+    pub fn mmu_mut(&mut self) -> &mut MMU {
+        &mut self.mmu
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn set_stack_pointer(&mut self, value: u16) {
+        self.stack_pointer = value;
+    }
+
+    /// call a routine on behalf of the host with `a` in A, as if a CALL at `return_address` ran.
+    /// This is how code that was never meant to run on its own, such as a sound driver ripped
+    /// from a game, gets driven.
+    pub fn call(&mut self, address: u16, return_address: u16, a: u8) {
+        self.registers[Register::A] = a;
+        // the host takes no time to do it
+        let [upper, lower] = return_address.to_be_bytes();
+        self.stack_pointer = self.stack_pointer.wrapping_sub(2);
+        self.mmu.wb(self.stack_pointer.wrapping_add(1), upper);
+        self.mmu.wb(self.stack_pointer, lower);
+        self.program_counter = address;
+        self.halted = false;
     }
-}
 
-impl CPU {
     pub fn run(&mut self) {
         // very naive main loop
         loop {
-            let current = self.program_counter;
-            self.program_counter += 1;
-            self.execute(self.mmu.rb(current));
+            self.step();
         }
     }
 
+    /// run until the PPU completes a frame, or for the duration of a frame while the LCD is off.
+    /// The picture is then available in the PPU framebuffer.
+    pub fn run_frame(&mut self) {
+        let start = self.cycles;
+        loop {
+            self.step();
+            if self.mmu.ppu_mut().take_frame_ready()
+                || self.cycles - start >= CYCLES_PER_FRAME as u64
+            {
+                break;
+            }
+        }
+    }
+
+    /// execute a single instruction and advance the rest of the hardware by the time it took.
+    /// Returns the number of clock cycles elapsed.
+    pub fn step(&mut self) -> u32 {
+        if self.stopped {
+            if !self.mmu.joypad().any_selected_pressed() {
+                // the clocks are stopped, only the time passes
+                self.cycles += 4;
+                return 4;
+            }
+            self.stopped = false;
+        }
+        let start = self.cycles;
+        self.ticked = 0;
+        let cycles = self.run_next();
+        // the memory accesses already ran the hardware up to their machine cycle
+        self.advance(cycles - self.ticked);
+        (self.cycles - start) as u32
+    }
+
+    /// run the next instruction, or what the cpu does in its place: wait for the VRAM DMA,
+    /// dispatch an interrupt or stay halted. Returns how many cpu cycles it takes.
+    fn run_next(&mut self) -> u32 {
+        // the cpu waits while the VRAM DMA copies its blocks
+        let stall = self.mmu.take_hdma_stall();
+        if stall > 0 {
+            return stall;
+        }
+        let pending = self.mmu.pending_interrupts();
+        if pending != 0 {
+            self.halted = false;
+            if self.interrupt_master_enable {
+                self.dispatch_interrupt(pending);
+                return 20;
+            }
+        }
+        if self.halted {
+            return 4;
+        }
+
+        let enable_interrupts = self.interrupt_enable_scheduled;
+        let opcode = self.read_immediate();
+        self.execute(opcode);
+        if enable_interrupts {
+            self.interrupt_enable_scheduled = false;
+            self.interrupt_master_enable = true;
+        }
+
+        let cycles = CYCLES[opcode as usize] + std::mem::take(&mut self.extra_cycles);
+        cycles as u32 * 4
+    }
+
+    /// run the rest of the hardware for a number of cpu cycles, which only take half the time
+    /// in double speed
+    fn advance(&mut self, cycles: u32) {
+        let elapsed = match self.mmu.double_speed() {
+            true => cycles / 2,
+            false => cycles,
+        };
+        self.cycles += elapsed as u64;
+        self.mmu.tick(cycles);
+    }
+
+    /// read a byte on the bus. Each access takes a machine cycle, the rest of the hardware runs
+    /// through it first so that the access sees the registers as they are at that time.
+    pub(super) fn read(&mut self, addr: u16) -> u8 {
+        self.read_corrupting(addr, OamCorruption::Read)
+    }
+
+    /// `read`, for the instructions that do more with the address than reading, which changes
+    /// how the OAM bug garbles OAM
+    pub(super) fn read_corrupting(&mut self, addr: u16, corruption: OamCorruption) -> u8 {
+        self.internal_cycle();
+        self.mmu.cpu_rb(addr, corruption)
+    }
+
+    /// write a byte on the bus, at the end of its machine cycle like `read`
+    pub(super) fn write(&mut self, addr: u16, value: u8) {
+        self.internal_cycle();
+        self.mmu.wb(addr, value);
+    }
+
+    /// the byte at PC, which moves past it
+    pub(super) fn read_immediate(&mut self) -> u8 {
+        let value = self.read(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        value
+    }
+
+    /// the 16 bit operand following the opcode, little endian. PC moves past it.
+    pub(super) fn read_immediate_word(&mut self) -> u16 {
+        let lower = self.read_immediate();
+        let upper = self.read_immediate();
+        u16::from_le_bytes([lower, upper])
+    }
+
+    /// a machine cycle spent without using the bus, which delays the accesses that follow it
+    pub(super) fn internal_cycle(&mut self) {
+        self.ticked += 4;
+        self.advance(4);
+    }
+
+    /// jump to the handler of the highest priority pending interrupt
+    fn dispatch_interrupt(&mut self, pending: u8) {
+        let bit = pending.trailing_zeros() as u16;
+        self.interrupt_master_enable = false;
+        self.mmu.acknowledge_interrupt(1 << bit);
+        self.internal_cycle();
+        self.internal_cycle();
+        self.push_program_counter();
+        self.program_counter = 0x0040 + bit * 8;
+    }
+
     fn execute(&mut self, opcode: u8) {
         let op = (opcode & 0b11000000) >> 6;
         let x = (opcode & 0b00111000) >> 3;
-        let y = (opcode & 0b00000111) >> 0;
+        let y = opcode & 0b00000111;
 
         #[rustfmt::skip]
         match (op, x, y) {
             (0b00, 0b000, 0b000) => self.nop(),
+            (0b00, 0b010, 0b000) => self.stop(),
+            (0b00, 0b000, 0b111) => self.rlca(),
+            (0b00, 0b001, 0b111) => self.rrca(),
+            (0b00, 0b010, 0b111) => self.rla(),
+            (0b00, 0b011, 0b111) => self.rra(),
+            (0b00, 0b100, 0b111) => self.daa(),
+            (0b00, 0b101, 0b111) => self.cpl(),
+            (0b00, 0b110, 0b111) => self.scf(),
+            (0b00, 0b111, 0b111) => self.ccf(),
             (0b00, 0b110, 0b110) => self.ld_hl_n(),
             (0b00, 0b001, 0b010) => self.ld_a_ptr(Register::B, Register::C),
             (0b00, 0b011, 0b010) => self.ld_a_ptr(Register::D, Register::E),
@@ -78,16 +323,23 @@ impl CPU {
             (0b00, 0b100, 0b010) => self.ld_hli_a(),
             (0b00, 0b110, 0b010) => self.ld_hld_a(),
             (0b00, 0b001, 0b000) => self.ld_nn_sp(),
+            (0b00, 0b011, 0b000) => self.jr_e(),
+            (0b00, cc   , 0b000) if (cc >= 0b100) => self.jr_cc_e(cc - 0b100),
             (0b00, dd   , 0b001) if (dd % 2 == 0) => self.ld_dd_nn(dd >> 1),
             (0b00, _    , 0b110) => self.ld_r_n(x),
+            (0b00, ss   , 0b011) if (ss % 2 == 0) => self.inc_ss(ss >> 1),
+            (0b00, ss   , 0b011) => self.dec_ss(ss >> 1),
+            (0b00, ss   , 0b001) => self.add_hl_ss(ss >> 1),
+            (0b00, r    , 0b100) => self.inc_r(r),
+            (0b00, r    , 0b101) => self.dec_r(r),
             (0b01, 0b110, 0b110) => self.halt(),
             (0b01, _    , 0b110) => self.ld_r_hl(x),
             (0b01, 0b110, _    ) => self.ld_hl_r(y),
             (0b01, _    , _    ) => self.ld_rr(x, y),
-            // (0b11, 0b110, 0b010) => self.ld_a_c(), /* disabled on the gameboy cpu */
-            (0b10, 0b000, 0b110) => self.add_a_hl(),
-            (0b10, 0b000, r    ) => self.add_a_r(r),
-            (0b11, 0b000, 0b110) => self.add_a_n(),
+            (0b10, op   , 0b110) => self.alu_a_hl(op),
+            (0b10, op   , r    ) => self.alu_a_r(op, r),
+            (0b11, op   , 0b110) => self.alu_a_n(op),
+            (0b11, 0b110, 0b010) => self.ld_a_c(),
             (0b11, 0b100, 0b010) => self.ld_c_a(),
             (0b11, 0b110, 0b000) => self.ld_a_n(),
             (0b11, 0b100, 0b000) => self.ld_n_a(),
@@ -95,31 +347,542 @@ impl CPU {
             (0b11, 0b101, 0b010) => self.ld_nn_a(),
             (0b11, 0b111, 0b000) => self.ldhl_sp_e(),
             (0b11, 0b111, 0b001) => self.ld_sp_hl(),
+            (0b11, 0b101, 0b000) => self.add_sp_e(),
+            (0b11, 0b110, 0b011) => self.di(),
+            (0b11, 0b111, 0b011) => self.ei(),
             (0b11, qq   , 0b101) if (qq % 2 == 0) => self.push_qq(qq >> 1),
             (0b11, qq   , 0b001) if (qq % 2 == 0) => self.pop_qq(qq >> 1),
-            _ => todo!("instruction {:08b} not yet supported", opcode)
+            (0b11, 0b000, 0b011) => self.jp_nn(),
+            (0b11, cc   , 0b010) if (cc < 0b100) => self.jp_cc_nn(cc),
+            (0b11, 0b101, 0b001) => self.jp_hl(),
+            (0b11, 0b001, 0b101) => self.call_nn(),
+            (0b11, cc   , 0b100) if (cc < 0b100) => self.call_cc_nn(cc),
+            (0b11, 0b001, 0b001) => self.ret(),
+            (0b11, cc   , 0b000) if (cc < 0b100) => self.ret_cc(cc),
+            (0b11, 0b011, 0b001) => self.reti(),
+            (0b11, t    , 0b111) => self.rst(t),
+            (0b11, 0b001, 0b011) => self.execute_cb(),
+            // D3, DB, DD, E3, E4, EB, EC, ED, F4, FC and FD
+            _ => self.lock_up(),
         };
     }
 
+    /// the instructions behind the CB prefix, whose opcode is the byte following it
+    fn execute_cb(&mut self) {
+        let opcode = self.read_immediate();
+        let op = (opcode & 0b11000000) >> 6;
+        let x = (opcode & 0b00111000) >> 3;
+        let r = opcode & 0b00000111;
+
+        // the second opcode byte takes a machine cycle, (HL) adds a read and a write
+        self.extra_cycles = match (op, r) {
+            (0b01, 0b110) => 2,
+            (_, 0b110) => 3,
+            _ => 1,
+        };
+        match op {
+            0b00 => self.rotate_shift_r(x, r),
+            0b01 => self.bit_b_r(x, r),
+            0b10 => self.res_b_r(x, r),
+            0b11 => self.set_b_r(x, r),
+            _ => unreachable!(),
+        }
+    }
+
+    /// the opcodes the cpu does not know hang it for good: it no longer handles interrupts and
+    /// only a reset gets it going again
+    fn lock_up(&mut self) {
+        self.program_counter = self.program_counter.wrapping_sub(1);
+        self.interrupt_master_enable = false;
+        self.interrupt_enable_scheduled = false;
+    }
+
     fn nop(&self) {}
 
     fn halt(&mut self) {
-        todo!("halt")
+        self.halted = true;
+    }
+
+    fn stop(&mut self) {
+        // STOP is followed by a byte that is skipped, it also resets DIV
+        self.program_counter = self.program_counter.wrapping_add(1);
+        // on CGB it switches speed instead of stopping, when KEY1 asks for it
+        if self.mmu.speed_switch_armed() {
+            self.mmu.switch_speed();
+        } else {
+            self.stopped = true;
+        }
+        self.mmu.wb(0xFF04, 0);
+    }
+
+    fn di(&mut self) {
+        self.interrupt_master_enable = false;
+        self.interrupt_enable_scheduled = false;
+    }
+
+    fn ei(&mut self) {
+        self.interrupt_enable_scheduled = true;
+    }
+
+    /// snapshot the whole machine. The state can only be loaded back while the same rom runs.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        for byte in STATE_MAGIC.iter() {
+            state.write_u8(*byte);
+        }
+        state.write_u16(STATE_VERSION);
+        let (title, checksum) = self.rom_identity();
+        state.write_bytes(title.as_bytes());
+        state.write_u16(checksum);
+        self.save(&mut state);
+        state.into_inner()
+    }
+
+    /// restore a state created by `save_state`. The machine is left untouched if the state is
+    /// refused.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        self.check_state_header(&mut state)?;
+
+        // a truncated or corrupted body is only noticed halfway through, roll back to the
+        // current state so the machine is never left half restored
+        let backup = self.save_state();
+        let result = self.load(&mut state).and_then(|_| match state.is_empty() {
+            true => Ok(()),
+            false => Err(StateError::InvalidData("trailing bytes after the state")),
+        });
+        if result.is_err() {
+            let mut state = StateReader::new(&backup);
+            self.check_state_header(&mut state)
+                .and_then(|_| self.load(&mut state))
+                .expect("restoring the backup state");
+        }
+        result
+    }
+
+    fn check_state_header(&self, state: &mut StateReader) -> Result<(), StateError> {
+        for byte in STATE_MAGIC.iter() {
+            if state.read_u8().map_err(|_| StateError::InvalidMagic)? != *byte {
+                return Err(StateError::InvalidMagic);
+            }
+        }
+        let version = state.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let found_title = String::from_utf8_lossy(state.read_bytes()?).into_owned();
+        let found_checksum = state.read_u16()?;
+        let (expected_title, expected_checksum) = self.rom_identity();
+        if found_title != expected_title || found_checksum != expected_checksum {
+            return Err(StateError::RomMismatch {
+                expected_title,
+                expected_checksum,
+                found_title,
+                found_checksum,
+            });
+        }
+        Ok(())
+    }
+
+    /// the title and global checksum of the running rom
+    fn rom_identity(&self) -> (String, u16) {
+        match self.mmu.cartridge() {
+            Some(cartridge) => (
+                cartridge.header().title.clone(),
+                cartridge.header().global_checksum,
+            ),
+            None => (String::new(), 0),
+        }
     }
 
     fn half_carry(a: u8, b: u8, result: u8) -> bool {
         (a ^ b ^ result) & 0x10 != 0
     }
+
+    /// the C flag
+    fn carry(&self) -> bool {
+        self.registers[Register::F] & (1 << 4) != 0
+    }
+
+    /// set all the flags at once, Z N H C from bit 7 to bit 4
+    fn set_flags(&mut self, zero: bool, subtract: bool, half_carry: bool, carry: bool) {
+        self.registers[Register::F] = (zero as u8) << 7
+            | (subtract as u8) << 6
+            | (half_carry as u8) << 5
+            | (carry as u8) << 4;
+    }
+
+    /// the operand of the instructions that work on a register or on the byte at HL, which
+    /// takes the code of F
+    fn read_operand(&mut self, r: u8) -> u8 {
+        match r as usize {
+            Register::F => self.read(self.hl()),
+            r => self.registers[r],
+        }
+    }
+
+    fn write_operand(&mut self, r: u8, value: u8) {
+        match r as usize {
+            Register::F => self.write(self.hl(), value),
+            r => self.registers[r] = value,
+        }
+    }
+
+    fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.registers[Register::H], self.registers[Register::L]])
+    }
+}
+
+impl Snapshot for CPU {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_u16(self.program_counter);
+        state.write_u16(self.stack_pointer);
+        state.write_bool(self.interrupt_master_enable);
+        state.write_bool(self.interrupt_enable_scheduled);
+        state.write_bool(self.halted);
+        state.write_bool(self.stopped);
+        state.write_u64(self.cycles);
+        self.mmu.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.registers)?;
+        self.program_counter = state.read_u16()?;
+        self.stack_pointer = state.read_u16()?;
+        self.interrupt_master_enable = state.read_bool()?;
+        self.interrupt_enable_scheduled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        self.cycles = state.read_u64()?;
+        self.mmu.load(state)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::joypad::Button;
+    use crate::mmu::Interrupt;
+
+    #[test]
+    fn step_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0x00);
+        cpu.mmu.wb(0x1, 0x01);
+        cpu.mmu.wb(0x2, 0x34);
+        cpu.mmu.wb(0x3, 0x12);
+
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.program_counter, 4);
+        assert_eq!(cpu.cycles(), 16);
+    }
+
+    #[test]
+    fn lock_up_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xFB);
+        cpu.mmu.wb(0x1, 0x00);
+        cpu.mmu.wb(0x2, 0xD3);
+        cpu.mmu.wb(0xFFFF, Interrupt::TIMER);
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 2);
+        assert!(!cpu.interrupt_master_enable);
+
+        // a pending interrupt does not get it out
+        cpu.mmu.request_interrupt(Interrupt::TIMER);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.program_counter, 2);
+    }
+
+    #[test]
+    fn memory_timing_tests() {
+        // LDH (0x05),A writes TIMA on its third machine cycle, after the falling edge of the
+        // timer bit that increments it
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xE0);
+        cpu.mmu.wb(0x1, 0x05);
+        cpu.registers[Register::A] = 0x80;
+        cpu.mmu.wb(0xFF07, 0x05);
+        cpu.mmu.tick(4);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.mmu.rb(0xFF05), 0x80);
+
+        // LDH (0x04),A resets DIV once bit 3 of the counter is set, which increments TIMA
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xE0);
+        cpu.mmu.wb(0x1, 0x04);
+        cpu.mmu.wb(0xFF07, 0x05);
+        cpu.step();
+        assert_eq!(cpu.mmu.rb(0xFF05), 0x01);
+    }
+
+    #[test]
+    fn new_tests() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x3E;
+
+        let cpu = CPU::new(Cartridge::new(rom));
+
+        assert_eq!(cpu.program_counter, 0x0100);
+        assert_eq!(cpu.stack_pointer, 0xFFFE);
+        assert_eq!(cpu.mmu.rb(0x0100), 0x3E);
+    }
+
+    #[test]
+    fn run_frame_tests() {
+        let mut cpu = CPU::default();
+
+        cpu.run_frame();
+        assert_eq!(cpu.cycles(), CYCLES_PER_FRAME as u64);
+
+        // wait on a HALT, running into the locked video ram would fetch 0xFF opcodes
+        cpu.mmu.wb(cpu.program_counter, 0x76);
+        cpu.mmu.wb(0xFF40, 0x80);
+        cpu.run_frame();
+        assert_eq!(cpu.mmu.rb(0xFF44), 144);
+    }
+
+    #[test]
+    fn ei_di_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0xFB);
+        cpu.mmu.wb(0x3, 0xF3);
+
+        cpu.step();
+        assert!(!cpu.interrupt_master_enable);
+        cpu.step();
+        assert!(cpu.interrupt_master_enable);
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn timer_interrupt_tests() {
+        let mut cpu = CPU {
+            stack_pointer: 0xFFFE,
+            interrupt_master_enable: true,
+            ..CPU::default()
+        };
+        cpu.mmu.wb(0xFFFF, Interrupt::TIMER);
+        cpu.mmu.wb(0xFF05, 0xFF);
+        cpu.mmu.wb(0xFF07, 0x05);
+
+        // the timer overflows after 4 nops, and the interrupt is requested one cycle later
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(cpu.program_counter, 0x0005);
+        assert_eq!(cpu.mmu.rb(0xFF0F) & Interrupt::TIMER, Interrupt::TIMER);
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.program_counter, 0x0050);
+        assert_eq!(cpu.stack_pointer, 0xFFFC);
+        assert_eq!(cpu.mmu.rb(0xFFFC), 0x05);
+        assert_eq!(cpu.mmu.rb(0xFFFD), 0x00);
+        assert_eq!(cpu.mmu.rb(0xFF0F) & Interrupt::TIMER, 0);
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn halt_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0x76);
+        cpu.mmu.wb(0xFFFF, Interrupt::TIMER);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 1);
+
+        // with IME off the cpu simply resumes after HALT
+        cpu.mmu.request_interrupt(Interrupt::TIMER);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 2);
+    }
+
+    #[test]
+    fn stop_tests() {
+        let mut cpu = CPU::default();
+        cpu.mmu.wb(0x0, 0x10);
+        cpu.mmu.wb(0xFF00, 0x10);
+        cpu.mmu.tick(256);
+
+        cpu.step();
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(cpu.mmu.rb(0xFF04), 0);
+
+        // the rest of the system sleeps with the cpu
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 2);
+        assert_eq!(cpu.mmu.rb(0xFF04), 0);
+
+        // a button of a row that is not selected does not wake it up
+        cpu.mmu.joypad_mut().press(Button::Up);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 2);
+
+        cpu.mmu.joypad_mut().press(Button::Start);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 3);
+        assert_eq!(cpu.mmu.rb(0xFF0F) & Interrupt::JOYPAD, Interrupt::JOYPAD);
+    }
+
+    #[test]
+    fn speed_switch_tests() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x10;
+        rom[0x0142] = 0x10;
+        rom[0x0143] = 0x80;
+        let cartridge = Cartridge::new(rom);
+        let model = Model::for_cartridge(cartridge.header());
+        let mut cpu = CPU::with_model(cartridge, model);
+        assert_eq!(cpu.mmu.model(), Model::Cgb);
+        assert_eq!(cpu.registers[Register::A], 0x11);
+        assert_eq!(cpu.mmu.rb(0xFF4D), 0x7E);
+
+        cpu.mmu.wb(0xFF4D, 0x01);
+        assert_eq!(cpu.mmu.rb(0xFF4D), 0x7F);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0102);
+        assert_eq!(cpu.mmu.rb(0xFF4D), 0xFE);
+
+        // the cpu and the timer run twice as fast, the PPU keeps its pace
+        cpu.mmu.wb(0xFF40, 0x80);
+        let cycles = cpu.cycles();
+        for _ in 0..64 {
+            assert_eq!(cpu.step(), 2);
+        }
+        assert_eq!(cpu.cycles() - cycles, 128);
+        assert_eq!(cpu.mmu.rb(0xFF04), 1);
+        assert_eq!(cpu.mmu.rb(0xFF41) & 0x03, 3);
+
+        // and back to normal speed
+        cpu.mmu.wb(0xFF4D, 0x01);
+        cpu.step();
+        assert_eq!(cpu.mmu.rb(0xFF4D), 0x7E);
+        assert_eq!(cpu.step(), 4);
+    }
+
+    #[test]
+    fn hdma_stall_tests() {
+        let mut cpu = CPU {
+            mmu: MMU::new(Model::Cgb),
+            ..CPU::default()
+        };
+        // a general purpose DMA of 2 blocks
+        cpu.mmu.wb(0xFF51, 0xC0);
+        cpu.mmu.wb(0xFF55, 0x01);
+
+        assert_eq!(cpu.step(), 64);
+        assert_eq!(cpu.program_counter, 0);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.program_counter, 1);
+    }
+
+    fn test_rom(title: &[u8], checksum: u16) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        rom[0x014E..0x0150].copy_from_slice(&checksum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn compatibility_mode_tests() {
+        let mut rom = test_rom(b"TETRIS", 0x1234);
+        rom[0x014B] = 0x01;
+        let cartridge = Cartridge::new(rom);
+        let palettes = CompatibilityPalettes::for_cartridge(&cartridge);
+        let mut cpu = CPU::with_model(cartridge, Model::Cgb);
+        assert!(cpu.mmu.ppu().compatibility());
+        // the registers of the CGB are gone
+        cpu.mmu.wb(0xFF4F, 0x01);
+        assert_eq!(cpu.mmu.rb(0xFF4F), 0xFF);
+        assert_eq!(cpu.mmu.rb(0xFF68), 0xFF);
+        assert_eq!(cpu.mmu.rb(0xFF4D), 0xFF);
+        assert_eq!(palettes.background[2], 0x001F);
+
+        let cpu = CPU::with_model(Cartridge::new(test_rom(b"TETRIS", 0x1234)), Model::Dmg);
+        assert!(!cpu.mmu.ppu().compatibility());
+    }
+
+    #[test]
+    fn save_state_tests() {
+        let mut cpu = CPU::new(Cartridge::new(test_rom(b"GAME", 0x1234)));
+        cpu.mmu.wb(0x0000, 0x0A);
+        cpu.mmu.wb(0xA000, 0x42);
+        cpu.mmu.wb(0xC000, 0x24);
+        cpu.registers[Register::B] = 0x77;
+        cpu.step();
+        let state = cpu.save_state();
+
+        cpu.mmu.wb(0xA000, 0x00);
+        cpu.mmu.wb(0xC000, 0x00);
+        cpu.registers[Register::B] = 0x00;
+        cpu.step();
+
+        assert_eq!(cpu.load_state(&state), Ok(()));
+        assert_eq!(cpu.mmu.rb(0xA000), 0x42);
+        assert_eq!(cpu.mmu.rb(0xC000), 0x24);
+        assert_eq!(cpu.registers[Register::B], 0x77);
+        assert_eq!(cpu.program_counter, 0x0101);
+        assert_eq!(cpu.cycles(), 4);
+    }
+
+    #[test]
+    fn load_state_errors_tests() {
+        let mut cpu = CPU::new(Cartridge::new(test_rom(b"GAME", 0x1234)));
+        let state = cpu.save_state();
+
+        let mut other = CPU::new(Cartridge::new(test_rom(b"OTHER", 0x4321)));
+        assert_eq!(
+            other.load_state(&state),
+            Err(StateError::RomMismatch {
+                expected_title: String::from("OTHER"),
+                expected_checksum: 0x4321,
+                found_title: String::from("GAME"),
+                found_checksum: 0x1234,
+            })
+        );
+
+        let mut wrong_version = state.clone();
+        wrong_version[8] = 0xFF;
+        assert!(matches!(
+            cpu.load_state(&wrong_version),
+            Err(StateError::UnsupportedVersion(_))
+        ));
+
+        assert_eq!(cpu.load_state(b"garbage"), Err(StateError::InvalidMagic));
+    }
+
+    #[test]
+    fn load_state_rollback_tests() {
+        let mut cpu = CPU::new(Cartridge::new(test_rom(b"GAME", 0x1234)));
+        cpu.mmu.wb(0xC000, 0x24);
+        let state = cpu.save_state();
+
+        cpu.mmu.wb(0xC000, 0x42);
+        cpu.registers[Register::B] = 0x77;
+
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::UnexpectedEnd)
+        );
+        assert_eq!(cpu.mmu.rb(0xC000), 0x42);
+        assert_eq!(cpu.registers[Register::B], 0x77);
+    }
 
     #[test]
     fn half_carry_tests() {
-        assert_eq!(CPU::half_carry(0x25, 0x48, 0x6D), false);
-        assert_eq!(CPU::half_carry(0x39, 0x48, 0x81), true);
-        assert_eq!(CPU::half_carry(0x72, 0x73, 0xE5), false);
+        assert!(!CPU::half_carry(0x25, 0x48, 0x6D));
+        assert!(CPU::half_carry(0x39, 0x48, 0x81));
+        assert!(!CPU::half_carry(0x72, 0x73, 0xE5));
     }
 }
@@ -0,0 +1,120 @@
+use super::Register;
+use super::CPU;
+
+impl CPU {
+    pub fn rlca(&mut self) {
+        self.rotate_a(0b000);
+    }
+
+    pub fn rrca(&mut self) {
+        self.rotate_a(0b001);
+    }
+
+    pub fn rla(&mut self) {
+        self.rotate_a(0b010);
+    }
+
+    pub fn rra(&mut self) {
+        self.rotate_a(0b011);
+    }
+
+    /// the rotates of A outside the CB prefix, which always clear Z
+    fn rotate_a(&mut self, op: u8) {
+        self.registers[Register::A] = self.rotate_shift(op, self.registers[Register::A]);
+        self.registers[Register::F] &= 0b00010000;
+    }
+
+    pub fn rotate_shift_r(&mut self, op: u8, r: u8) {
+        let value = self.read_operand(r);
+        let result = self.rotate_shift(op, value);
+        self.write_operand(r, result);
+    }
+
+    /// the operation encoded in the rotate and shift instructions, C gets the bit shifted out:
+    ///
+    /// | op  | operation |
+    /// | 000 | RLC       |
+    /// | 001 | RRC       |
+    /// | 010 | RL        |
+    /// | 011 | RR        |
+    /// | 100 | SLA       |
+    /// | 101 | SRA       |
+    /// | 110 | SWAP      |
+    /// | 111 | SRL       |
+    fn rotate_shift(&mut self, op: u8, value: u8) -> u8 {
+        let carry = self.carry() as u8;
+        let (result, shifted_out) = match op {
+            0b000 => (value.rotate_left(1), value >> 7),
+            0b001 => (value.rotate_right(1), value & 1),
+            0b010 => (value << 1 | carry, value >> 7),
+            0b011 => (value >> 1 | carry << 7, value & 1),
+            0b100 => (value << 1, value >> 7),
+            0b101 => (value >> 1 | value & 0x80, value & 1),
+            0b110 => (value.rotate_left(4), 0),
+            0b111 => (value >> 1, value & 1),
+            _ => unreachable!(),
+        };
+        self.set_flags(result == 0, false, false, shifted_out != 0);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_a_tests() {
+        let mut cpu = CPU::default();
+        cpu.registers[Register::A] = 0x85;
+
+        let instruction = 0b00_000_111;
+        cpu.execute(instruction);
+
+        assert_eq!(cpu.registers[Register::A], 0x0B);
+        assert_eq!(cpu.registers[Register::F], 0b00010000);
+
+        cpu.registers[Register::A] = 0x00;
+        let instruction = 0b00_011_111;
+        cpu.execute(instruction);
+
+        // the carry goes in, Z stays clear
+        assert_eq!(cpu.registers[Register::A], 0x80);
+        assert_eq!(cpu.registers[Register::F], 0b00000000);
+    }
+
+    #[test]
+    fn rotate_shift_r_tests() {
+        let mut cpu = CPU::default();
+        // RL D, SRA E, SWAP A, SRL (HL)
+        for (offset, byte) in [0xCB, 0x12, 0xCB, 0x2B, 0xCB, 0x37, 0xCB, 0x3E]
+            .iter()
+            .enumerate()
+        {
+            cpu.mmu.wb(offset as u16, *byte);
+        }
+        cpu.registers[Register::D] = 0x80;
+        cpu.registers[Register::E] = 0x81;
+        cpu.registers[Register::A] = 0xF1;
+        cpu.registers[Register::H] = 0xC0;
+        cpu.registers[Register::L] = 0x00;
+        cpu.mmu.wb(0xC000, 0x01);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers[Register::D], 0x00);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers[Register::E], 0xC0);
+        assert_eq!(cpu.registers[Register::F], 0b00010000);
+
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.registers[Register::A], 0x1F);
+        assert_eq!(cpu.registers[Register::F], 0b00000000);
+
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.mmu.rb(0xC000), 0x00);
+        assert_eq!(cpu.registers[Register::F], 0b10010000);
+        assert_eq!(cpu.program_counter, 8);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::apu::APU;
+use crate::cartridge::Cartridge;
+use crate::cpu::{CLOCK_SPEED, CPU};
+use crate::mmu::Interrupt;
+use crate::ppu::CYCLES_PER_FRAME;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// size of the header, the code follows it
+const HEADER_SIZE: usize = 0x70;
+/// lowest load address allowed, below it live the vectors the player sets up
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+/// the code starts in the rom area, further banks are reached through MBC5
+const MAX_LOAD_ADDRESS: u16 = 0x7FFF;
+/// the largest rom MBC5 maps
+const MAX_ROM_SIZE: usize = 0x80_0000;
+/// where the init and play routines return to: a HALT, then a JR back to it
+const RETURN_ADDRESS: u16 = 0x00F0;
+
+#[derive(Debug, PartialEq)]
+pub enum GbsError {
+    /// the data does not start with "GBS"
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    /// the data ends before the end of the header
+    Truncated,
+    /// the code would overwrite the vectors set up by the player, or does not fit in the rom
+    InvalidLoadAddress(u16),
+    /// there is no song with this index, counting from 0
+    InvalidSong(u8),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::InvalidMagic => write!(f, "not a GBS file"),
+            GbsError::UnsupportedVersion(version) => {
+                write!(f, "GBS version {} is not supported", version)
+            }
+            GbsError::Truncated => write!(f, "GBS file is truncated"),
+            GbsError::InvalidLoadAddress(address) => {
+                write!(f, "GBS code does not fit at load address {:04X}", address)
+            }
+            GbsError::InvalidSong(song) => write!(f, "there is no song {}", *song as u16 + 1),
+        }
+    }
+}
+
+impl Error for GbsError {}
+
+/// the header of a GBS file, which describes how to play the sound code ripped from a game
+pub struct GbsHeader {
+    pub song_count: u8,
+    /// the song to play first, counting from 1
+    pub first_song: u8,
+    /// where the code is mapped, from 0x0400 to 0x7FFF
+    pub load_address: u16,
+    /// called once per song with the song index, counting from 0, in A
+    pub init_address: u16,
+    /// called at the rate set by the timer registers, or at the VBlank rate
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    /// when bit 2 is set play is called on every timer interrupt, otherwise on every VBlank.
+    /// Bit 7 runs the cpu and the timer at the CGB double speed.
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, GbsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(GbsError::Truncated);
+        }
+        if &data[0..3] != b"GBS" {
+            return Err(GbsError::InvalidMagic);
+        }
+        if data[3] != 1 {
+            return Err(GbsError::UnsupportedVersion(data[3]));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        // the strings are 32 bytes long, padded with zeroes
+        let string = |offset: usize| {
+            data[offset..offset + 32]
+                .iter()
+                .take_while(|&&b| b != 0)
+                .map(|&b| b as char)
+                .collect()
+        };
+        let header = Self {
+            song_count: data[4],
+            first_song: data[5],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: string(0x10),
+            author: string(0x30),
+            copyright: string(0x50),
+        };
+        let code_end = header.load_address as usize + data.len() - HEADER_SIZE;
+        if header.load_address < MIN_LOAD_ADDRESS
+            || header.load_address > MAX_LOAD_ADDRESS
+            || code_end > MAX_ROM_SIZE
+        {
+            return Err(GbsError::InvalidLoadAddress(header.load_address));
+        }
+        Ok(header)
+    }
+
+    /// whether play is called by the timer rather than at the VBlank rate
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// whether the code runs at the CGB double speed
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+}
+
+/// plays the songs of a GBS file.
+///
+/// The code is mapped at its load address in a synthetic MBC5 cartridge with 8 KiB of ram, whose
+/// first page holds the restart vectors, pointing to the load address plus the vector, and the
+/// loop the routines return to. The player calls init when a song starts, then play at the
+/// timer or VBlank rate whenever the previous call returned. The LCD stays off.
+pub struct GbsPlayer {
+    header: GbsHeader,
+    rom: Vec<u8>,
+    cpu: CPU,
+    song: u8,
+    /// clock cycle of the next call to play at the VBlank rate
+    next_play: u64,
+}
+
+impl GbsPlayer {
+    /// load a GBS file and start its first song
+    pub fn new(data: &[u8]) -> Result<Self, GbsError> {
+        let header = GbsHeader::parse(data)?;
+        let rom = build_rom(&header, &data[HEADER_SIZE..]);
+        let first_song = header.first_song.saturating_sub(1);
+        let mut player = Self {
+            header,
+            rom,
+            cpu: CPU::default(),
+            song: 0,
+            next_play: 0,
+        };
+        // a first song out of range falls back to the first one
+        if player.start_song(first_song).is_err() {
+            player.start_song(0)?;
+        }
+        Ok(player)
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    /// the song playing, counting from 0
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    /// the sample rate, filters, mute and recordings are set up through here
+    pub fn apu_mut(&mut self) -> &mut APU {
+        self.cpu.mmu_mut().apu_mut()
+    }
+
+    /// reset the system and call init for a song, counting from 0
+    pub fn start_song(&mut self, song: u8) -> Result<(), GbsError> {
+        if song >= self.header.song_count {
+            return Err(GbsError::InvalidSong(song));
+        }
+        let sample_rate = self.cpu.mmu().apu().sample_rate();
+        let high_pass = self.cpu.mmu().apu().high_pass();
+        let recording = self.cpu.mmu_mut().apu_mut().stop_recording();
+
+        let mut cartridge = Cartridge::new(self.rom.clone());
+        // the code expects its ram to be there without asking
+        cartridge.wb_rom(0x0000, 0x0A);
+        let mut cpu = CPU::default();
+        cpu.mmu_mut().insert_cartridge(cartridge);
+
+        let apu = cpu.mmu_mut().apu_mut();
+        apu.set_sample_rate(sample_rate);
+        apu.set_high_pass(high_pass);
+        if let Some(recording) = recording {
+            apu.resume_recording(recording);
+        }
+        let mmu = cpu.mmu_mut();
+        mmu.wb(0xFF26, 0x80);
+        mmu.wb(0xFF25, 0xFF);
+        mmu.wb(0xFF24, 0x77);
+        mmu.wb(0xFF05, self.header.timer_modulo);
+        mmu.wb(0xFF06, self.header.timer_modulo);
+        mmu.wb(0xFF07, self.header.timer_control);
+        if self.header.double_speed() {
+            mmu.switch_speed();
+        }
+        // HALT wakes up on the timer interrupt, which is never dispatched as IME stays off
+        if self.header.uses_timer() {
+            mmu.wb(0xFFFF, Interrupt::TIMER);
+        }
+        cpu.set_stack_pointer(self.header.stack_pointer);
+        cpu.call(self.header.init_address, RETURN_ADDRESS, song);
+
+        self.cpu = cpu;
+        self.song = song;
+        self.next_play = CYCLES_PER_FRAME as u64;
+        Ok(())
+    }
+
+    /// run for a number of clock cycles
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < end {
+            if self.returned() && self.play_due() {
+                self.cpu
+                    .call(self.header.play_address, RETURN_ADDRESS, self.song);
+            }
+            self.cpu.step();
+        }
+    }
+
+    /// run for the duration of a frame and return the samples produced, left and right
+    /// interleaved
+    pub fn render_frame(&mut self) -> Vec<i16> {
+        self.run(CYCLES_PER_FRAME as u64);
+        self.apu_mut().take_samples_i16()
+    }
+
+    /// run for a while and return the samples produced, left and right interleaved
+    pub fn render(&mut self, duration: Duration) -> Vec<i16> {
+        let cycles = (duration.as_secs_f64() * CLOCK_SPEED as f64) as u64;
+        let mut samples = Vec::new();
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let slice = remaining.min(CYCLES_PER_FRAME as u64);
+            self.run(slice);
+            samples.extend(self.apu_mut().take_samples_i16());
+            remaining -= slice;
+        }
+        samples
+    }
+
+    /// whether the last routine called returned to the idle loop
+    fn returned(&self) -> bool {
+        let pc = self.cpu.program_counter();
+        pc == RETURN_ADDRESS || pc == RETURN_ADDRESS + 1
+    }
+
+    fn play_due(&mut self) -> bool {
+        if self.header.uses_timer() {
+            let mmu = self.cpu.mmu_mut();
+            if mmu.rb(0xFF0F) & Interrupt::TIMER == 0 {
+                return false;
+            }
+            mmu.acknowledge_interrupt(Interrupt::TIMER);
+            true
+        } else if self.cpu.cycles() >= self.next_play {
+            self.next_play += CYCLES_PER_FRAME as u64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// the rom of the synthetic cartridge, the code mapped at its load address
+fn build_rom(header: &GbsHeader, code: &[u8]) -> Vec<u8> {
+    let load = header.load_address as usize;
+    let end = load + code.len();
+    let size = ((end + 0x3FFF) & !0x3FFF).max(0x8000);
+    let mut rom = vec![0; size];
+    rom[load..end].copy_from_slice(code);
+
+    // RST n jumps to the load address + n
+    for vector in (0..0x40).step_by(8) {
+        rom[vector] = 0xC3;
+        rom[vector + 1..vector + 3]
+            .copy_from_slice(&(header.load_address + vector as u16).to_le_bytes());
+    }
+    // should the code enable interrupts, their handlers return right away
+    for vector in (0x40..=0x60).step_by(8) {
+        rom[vector] = 0xD9;
+    }
+    let idle = RETURN_ADDRESS as usize;
+    rom[idle..idle + 3].copy_from_slice(&[0x76, 0x18, 0xFD]);
+
+    // MBC5 with ram, 8 KiB of ram
+    rom[0x0147] = 0x1A;
+    rom[0x0149] = 0x02;
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a GBS file whose init stores the song in 0xC000 and starts channel 2, and whose play
+    /// counts its calls in 0xC001
+    fn test_gbs(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut gbs = vec![0; HEADER_SIZE];
+        gbs[0..4].copy_from_slice(b"GBS\x01");
+        gbs[4] = 3;
+        gbs[5] = 2;
+        gbs[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        gbs[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        gbs[0x0A..0x0C].copy_from_slice(&0x0410u16.to_le_bytes());
+        gbs[0x0C..0x0E].copy_from_slice(&0xDFFEu16.to_le_bytes());
+        gbs[0x0E] = timer_modulo;
+        gbs[0x0F] = timer_control;
+        gbs[0x10..0x14].copy_from_slice(b"Song");
+        gbs[0x30..0x36].copy_from_slice(b"Author");
+
+        let mut init = vec![
+            0xEA, 0x00, 0xC0, // LD (0xC000), A
+            0x3E, 0xF0, // LD A, 0xF0
+            0xE0, 0x17, // LDH (0x17), A
+            0x3E, 0x87, // LD A, 0x87
+            0xE0, 0x19, // LDH (0x19), A
+            0xC9, // RET
+        ];
+        init.resize(0x10, 0x00);
+        let play = [
+            0xFA, 0x01, 0xC0, // LD A, (0xC001)
+            0xC6, 0x01, // ADD A, 1
+            0xEA, 0x01, 0xC0, // LD (0xC001), A
+            0xC9, // RET
+        ];
+        gbs.extend_from_slice(&init);
+        gbs.extend_from_slice(&play);
+        gbs
+    }
+
+    #[test]
+    fn header_tests() {
+        let header = GbsHeader::parse(&test_gbs(0x00, 0x00)).unwrap();
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.first_song, 2);
+        assert_eq!(header.load_address, 0x0400);
+        assert_eq!(header.play_address, 0x0410);
+        assert_eq!(header.stack_pointer, 0xDFFE);
+        assert_eq!(header.title, "Song");
+        assert_eq!(header.author, "Author");
+        assert_eq!(header.copyright, "");
+        assert!(!header.uses_timer());
+
+        let mut gbs = test_gbs(0x00, 0x00);
+        gbs[0] = b'X';
+        assert_eq!(GbsHeader::parse(&gbs).err(), Some(GbsError::InvalidMagic));
+        gbs = test_gbs(0x00, 0x00);
+        gbs[3] = 2;
+        assert_eq!(
+            GbsHeader::parse(&gbs).err(),
+            Some(GbsError::UnsupportedVersion(2))
+        );
+        gbs = test_gbs(0x00, 0x00);
+        gbs[0x07] = 0x03;
+        assert_eq!(
+            GbsHeader::parse(&gbs).err(),
+            Some(GbsError::InvalidLoadAddress(0x0300))
+        );
+        // the restart vectors would point past 0xFFFF
+        gbs[0x06..0x08].copy_from_slice(&0xFFF0u16.to_le_bytes());
+        assert_eq!(
+            GbsHeader::parse(&gbs).err(),
+            Some(GbsError::InvalidLoadAddress(0xFFF0))
+        );
+        gbs[0x06..0x08].copy_from_slice(&0x8000u16.to_le_bytes());
+        assert_eq!(
+            GbsHeader::parse(&gbs).err(),
+            Some(GbsError::InvalidLoadAddress(0x8000))
+        );
+        gbs[0x06..0x08].copy_from_slice(&0x7F00u16.to_le_bytes());
+        gbs.resize(HEADER_SIZE + MAX_ROM_SIZE, 0);
+        assert_eq!(
+            GbsHeader::parse(&gbs).err(),
+            Some(GbsError::InvalidLoadAddress(0x7F00))
+        );
+        assert_eq!(
+            GbsHeader::parse(&gbs[..0x20]).err(),
+            Some(GbsError::Truncated)
+        );
+    }
+
+    #[test]
+    fn instruction_tests() {
+        // an init working its way through the arithmetic, rotate and bit instructions
+        let routine = [
+            0xAF, // XOR A
+            0x06, 0x0F, // LD B, 0x0F
+            0x04, // INC B
+            0x05, // DEC B
+            0x3E, 0x3C, // LD A, 0x3C
+            0xA0, // AND B
+            0xF6, 0x80, // OR 0x80
+            0xD6, 0x0D, // SUB 0x0D
+            0x37, // SCF
+            0xCE, 0x00, // ADC A, 0x00
+            0x37, // SCF
+            0xDE, 0x00, // SBC A, 0x00
+            0xFE, 0x7F, // CP 0x7F
+            0x20, 0xFE, // JR NZ, -2
+            0x21, 0x34, 0x12, // LD HL, 0x1234
+            0x11, 0x11, 0x11, // LD DE, 0x1111
+            0x19, // ADD HL, DE
+            0x07, // RLCA
+            0xCB, 0x37, // SWAP A
+            0xCB, 0x3F, // SRL A
+            0xCB, 0xFF, // SET 7, A
+            0xCB, 0x87, // RES 0, A
+            0xCB, 0x7F, // BIT 7, A
+            0x28, 0xFE, // JR Z, -2
+            0xEA, 0x02, 0xC0, // LD (0xC002), A
+            0x7C, // LD A, H
+            0xEA, 0x03, 0xC0, // LD (0xC003), A
+            0x7D, // LD A, L
+            0xEA, 0x04, 0xC0, // LD (0xC004), A
+            0x78, // LD A, B
+            0xEA, 0x05, 0xC0, // LD (0xC005), A
+            0xC9, // RET
+        ];
+        let mut gbs = test_gbs(0x00, 0x00);
+        gbs.truncate(HEADER_SIZE);
+        gbs.extend_from_slice(&routine);
+        // play is the RET at the end
+        let play = 0x0400 + routine.len() as u16 - 1;
+        gbs[0x0A..0x0C].copy_from_slice(&play.to_le_bytes());
+
+        let mut player = GbsPlayer::new(&gbs).unwrap();
+        player.run(1000);
+        let mmu = player.cpu().mmu();
+        assert_eq!(mmu.rb(0xC002), 0xF6);
+        assert_eq!(mmu.rb(0xC003), 0x23);
+        assert_eq!(mmu.rb(0xC004), 0x45);
+        assert_eq!(mmu.rb(0xC005), 0x0F);
+    }
+
+    #[test]
+    fn init_tests() {
+        let mut player = GbsPlayer::new(&test_gbs(0x00, 0x00)).unwrap();
+        assert_eq!(player.song(), 1);
+        player.run(1000);
+        assert_eq!(player.cpu().mmu().rb(0xC000), 1);
+        assert_eq!(player.cpu().mmu().rb(0xFF26) & 0x02, 0x02);
+
+        player.start_song(2).unwrap();
+        player.run(1000);
+        assert_eq!(player.cpu().mmu().rb(0xC000), 2);
+        assert_eq!(player.start_song(3), Err(GbsError::InvalidSong(3)));
+    }
+
+    #[test]
+    fn vblank_rate_tests() {
+        let mut player = GbsPlayer::new(&test_gbs(0x00, 0x00)).unwrap();
+        player.run(CLOCK_SPEED as u64);
+        assert_eq!(player.cpu().mmu().rb(0xC001), 59);
+    }
+
+    #[test]
+    fn timer_rate_tests() {
+        // 4096 Hz divided by 256 - 0xC0 makes 64 calls per second
+        let mut player = GbsPlayer::new(&test_gbs(0xC0, 0x04)).unwrap();
+        // the 64th overflow comes a few cycles after the second is up
+        player.run(CLOCK_SPEED as u64 + 64);
+        assert_eq!(player.cpu().mmu().rb(0xC001), 64);
+
+        // the timer runs twice as fast at double speed
+        let mut player = GbsPlayer::new(&test_gbs(0xC0, 0x84)).unwrap();
+        assert!(player.cpu().mmu().double_speed());
+        player.run(CLOCK_SPEED as u64 + 64);
+        assert_eq!(player.cpu().mmu().rb(0xC001), 128);
+    }
+
+    #[test]
+    fn render_tests() {
+        let mut player = GbsPlayer::new(&test_gbs(0x00, 0x00)).unwrap();
+        player.apu_mut().set_sample_rate(44100);
+        player.apu_mut().start_recording(false);
+        let samples = player.render(Duration::from_millis(100));
+        assert_eq!(samples.len(), 4410 * 2);
+        assert!(samples.iter().any(|sample| *sample > 1000));
+
+        // the recording goes on across songs
+        player.start_song(0).unwrap();
+        player.render_frame();
+        let recording = player.apu_mut().stop_recording().unwrap();
+        assert!(recording.samples().len() > samples.len());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// a button of the Game Boy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// bit of the button in `Joypad::pressed`, the direction keys in the low nibble and the
+    /// action buttons in the high nibble, in the order of the P10-P13 lines
+    fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+/// the joypad register, 0xFF00.
+///
+/// Writing 0 to P14 (bit 4) selects the direction keys, writing 0 to P15 (bit 5) selects the
+/// action buttons. The lower 4 bits read 0 for every pressed button of the selected rows, when
+/// both rows are selected a line is low as soon as a button of either row pulls it down. A line
+/// going from high to low requests the joypad interrupt.
+pub struct Joypad {
+    /// P14 and P15, as written by the cpu
+    select: u8,
+    /// buttons held by the player, see `Button::mask`
+    pressed: u8,
+    interrupt: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self {
+            select: 0x30,
+            pressed: 0,
+            interrupt: false,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn press(&mut self, button: Button) {
+        let lines = self.lines();
+        self.pressed |= button.mask();
+        self.detect_falling_edge(lines);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.mask() != 0
+    }
+
+    /// whether the joypad requested an interrupt since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    /// whether a button of a selected row is held, which is what brings the cpu out of STOP
+    pub fn any_selected_pressed(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    pub fn rb(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn wb(&mut self, value: u8) {
+        // selecting a row where a button is already held pulls its line down too
+        let lines = self.lines();
+        self.select = value & 0x30;
+        self.detect_falling_edge(lines);
+    }
+
+    /// state of the P10-P13 lines, a bit is 0 when its line is pulled low
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & 0x10 == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    fn detect_falling_edge(&mut self, previous_lines: u8) {
+        if previous_lines & !self.lines() != 0 {
+            self.interrupt = true;
+        }
+    }
+}
+
+impl Snapshot for Joypad {
+    // the buttons held belong to the player, not to the saved machine
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.select);
+        state.write_bool(self.interrupt);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.select = state.read_u8()? & 0x30;
+        self.interrupt = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_select_tests() {
+        let mut joypad = Joypad::default();
+        joypad.press(Button::Down);
+        joypad.press(Button::A);
+
+        // nothing selected
+        assert_eq!(joypad.rb(), 0xFF);
+
+        joypad.wb(0x20);
+        assert_eq!(joypad.rb(), 0xE7);
+        joypad.wb(0x10);
+        assert_eq!(joypad.rb(), 0xDE);
+
+        // both rows at once: the lines are shared
+        joypad.wb(0x00);
+        assert_eq!(joypad.rb(), 0xC6);
+
+        joypad.release(Button::Down);
+        assert_eq!(joypad.rb(), 0xCE);
+        assert!(joypad.is_pressed(Button::A));
+        assert!(!joypad.is_pressed(Button::Down));
+    }
+
+    #[test]
+    fn interrupt_tests() {
+        let mut joypad = Joypad::default();
+
+        // a button of a row that is not selected does not change the lines
+        joypad.wb(0x10);
+        joypad.press(Button::Left);
+        assert!(!joypad.take_interrupt());
+
+        joypad.press(Button::Start);
+        assert!(joypad.take_interrupt());
+        assert!(!joypad.take_interrupt());
+
+        // selecting the direction keys pulls the line of Left down
+        joypad.wb(0x00);
+        assert!(joypad.take_interrupt());
+        joypad.press(Button::Select);
+        assert!(joypad.take_interrupt());
+
+        // a line already low does not fall again
+        joypad.press(Button::Up);
+        assert!(!joypad.take_interrupt());
+        joypad.press(Button::B);
+        assert!(!joypad.take_interrupt());
+        joypad.release(Button::B);
+        assert!(!joypad.take_interrupt());
+    }
+
+    #[test]
+    fn select_interrupt_tests() {
+        let mut joypad = Joypad::default();
+        joypad.press(Button::Right);
+        assert!(!joypad.take_interrupt());
+        assert!(!joypad.any_selected_pressed());
+
+        joypad.wb(0x20);
+        assert!(joypad.take_interrupt());
+        assert!(joypad.any_selected_pressed());
+    }
+}
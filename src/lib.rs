@@ -1,2 +1,10 @@
+pub mod apu;
+pub mod cartridge;
 pub mod cpu;
+pub mod gbs;
+pub mod joypad;
 pub mod mmu;
+pub mod ppu;
+pub mod serial;
+pub mod state;
+pub mod timer;
@@ -1 +1,44 @@
-fn main() {}
+use dmg_01::gbs::GbsPlayer;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "usage: dmg_01 gbs2wav <file.gbs> <output.wav> [song] [seconds]";
+
+/// render a song of a GBS file to WAV, without any window or audio device
+fn gbs_to_wav(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (input, output) = match args {
+        [input, output, ..] => (input, output),
+        _ => return Err(USAGE.into()),
+    };
+    let mut player = GbsPlayer::new(&fs::read(input)?)?;
+    // songs count from 1 on the command line, like in the header
+    if let Some(song) = args.get(2) {
+        let song: u8 = song.parse()?;
+        player.start_song(song.saturating_sub(1))?;
+    }
+    let seconds: u64 = match args.get(3) {
+        Some(seconds) => seconds.parse()?,
+        None => 120,
+    };
+
+    player.apu_mut().start_recording(false);
+    player.render(Duration::from_secs(seconds));
+    let recording = player.apu_mut().stop_recording().unwrap();
+    recording.write_wav(output)?;
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, args)) if command == "gbs2wav" => gbs_to_wav(args),
+        _ => Err(USAGE.into()),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// number of bytes copied by an OAM DMA transfer, one per machine cycle
+pub const DMA_LENGTH: u16 = 0xA0;
+
+/// the OAM DMA controller, started by a write to 0xFF46.
+///
+/// The transfer begins after one machine cycle of setup, then copies one byte per machine cycle
+/// from `XX00` to OAM. While it runs the DMA owns the external and video buses.
+#[derive(Default)]
+pub struct Dma {
+    /// last value written to 0xFF46
+    register: u8,
+    /// transfer waiting for its setup cycle
+    starting: Option<u16>,
+    /// source address and number of bytes copied by the running transfer
+    running: Option<(u16, u16)>,
+    /// last byte copied, this is what the cpu sees when reading from a bus the DMA owns
+    pub bus: u8,
+}
+
+impl Dma {
+    pub fn rb(&self) -> u8 {
+        self.register
+    }
+
+    /// start a new transfer. A transfer already running keeps going until the new one begins.
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        let source = (value as u16) << 8;
+        // there is no memory to copy from past the work ram, the echo ram is used instead
+        self.starting = Some(if source >= 0xE000 {
+            source - 0x2000
+        } else {
+            source
+        });
+    }
+
+    /// whether a transfer currently owns the bus
+    pub fn is_active(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// advance the transfer by one machine cycle. Returns the source address and OAM offset of
+    /// the byte to copy during that cycle.
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        let copy = self
+            .running
+            .map(|(source, copied)| (source + copied, copied));
+        self.running = match self.running {
+            Some((source, copied)) if copied + 1 < DMA_LENGTH => Some((source, copied + 1)),
+            _ => None,
+        };
+        if let Some(source) = self.starting.take() {
+            self.running = Some((source, 0));
+        }
+        copy
+    }
+}
+
+impl Snapshot for Dma {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_bool(self.starting.is_some());
+        state.write_u16(self.starting.unwrap_or(0));
+        state.write_bool(self.running.is_some());
+        let (source, copied) = self.running.unwrap_or((0, 0));
+        state.write_u16(source);
+        state.write_u16(copied);
+        state.write_u8(self.bus);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        let starting = state.read_bool()?;
+        let starting_source = state.read_u16()?;
+        self.starting = if starting {
+            Some(starting_source)
+        } else {
+            None
+        };
+        let running = state.read_bool()?;
+        let source = state.read_u16()?;
+        let copied = state.read_u16()?;
+        if copied >= DMA_LENGTH {
+            return Err(StateError::InvalidData("OAM DMA progress out of range"));
+        }
+        self.running = if running {
+            Some((source, copied))
+        } else {
+            None
+        };
+        self.bus = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::MMU;
+
+    #[test]
+    fn transfer_tests() {
+        let mut mmu = MMU::default();
+        for i in 0..DMA_LENGTH {
+            mmu.wb(0xC100 + i, i as u8);
+        }
+
+        mmu.wb(0xFF46, 0xC1);
+        assert_eq!(mmu.rb(0xFF46), 0xC1);
+        assert_eq!(mmu.rb(0xC105), 0x05);
+
+        // after the setup cycle the cpu accesses happen alongside the copy
+        mmu.tick(4);
+        assert!(mmu.dma.is_active());
+        mmu.tick(4 * 6);
+        assert_eq!(mmu.ppu.rb(0xFE05), 0x05);
+        assert_eq!(mmu.ppu.rb(0xFE06), 0x00);
+
+        // the cpu only sees the byte being transferred outside of 0xFF00-0xFFFF
+        assert_eq!(mmu.rb(0xC142), 0x05);
+        mmu.wb(0xC142, 0xAA);
+        mmu.wb(0xFF80, 0xBB);
+        assert_eq!(mmu.rb(0xFF80), 0xBB);
+
+        mmu.tick(4 * (DMA_LENGTH as u32 - 6));
+        assert_eq!(mmu.ppu.rb(0xFE9F), 0x9F);
+        assert_eq!(mmu.rb(0xC142), 0x42);
+    }
+
+    #[test]
+    fn restart_tests() {
+        let mut mmu = MMU::default();
+        mmu.wb(0xC000, 0x11);
+        mmu.wb(0xC001, 0x12);
+        mmu.wb(0xD000, 0x21);
+        mmu.wb(0xD001, 0x22);
+
+        mmu.wb(0xFF46, 0xC0);
+        mmu.tick(4 * 3);
+        mmu.wb(0xFF46, 0xD0);
+
+        // the first transfer keeps the bus during the setup of the second one
+        mmu.tick(4);
+        assert!(mmu.dma.is_active());
+        assert_eq!(mmu.ppu.rb(0xFE02), 0x00);
+
+        mmu.tick(4 * 2);
+        assert_eq!(mmu.ppu.rb(0xFE00), 0x21);
+        assert_eq!(mmu.ppu.rb(0xFE01), 0x22);
+    }
+
+    #[test]
+    fn echo_source_tests() {
+        let mut dma = Dma::default();
+
+        dma.start(0xFE);
+        dma.step();
+
+        assert_eq!(dma.step(), Some((0xDE00, 0)));
+        assert_eq!(dma.step(), Some((0xDE01, 1)));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// number of bytes copied by the VRAM DMA at a time
+pub const BLOCK_LENGTH: u16 = 0x10;
+
+/// the VRAM DMA of the CGB, programmed through 0xFF51-0xFF55.
+///
+/// It copies blocks of 16 bytes from the rom or the work or external ram to the video ram
+/// bank selected by VBK. A general purpose DMA copies every block as soon as HDMA5 is written,
+/// an HBlank DMA copies one block each time the PPU enters HBlank. The cpu waits while a block
+/// is copied.
+#[derive(Default)]
+pub struct Hdma {
+    /// the low 4 bits are always clear
+    source: u16,
+    /// offset in the video ram, the low 4 bits are always clear
+    destination: u16,
+    /// blocks left to copy minus one, as HDMA5 reads
+    length: u8,
+    /// a transfer is running, or waiting for the next HBlank
+    active: bool,
+    /// the transfer copies one block per HBlank
+    hblank: bool,
+}
+
+impl Hdma {
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            // bit 7 is clear while a transfer is running, 0xFF means it completed
+            0xFF55 => (!self.active as u8) << 7 | self.length,
+            // the address registers cannot be read back
+            _ => 0xFF,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF51 => self.source = (value as u16) << 8 | (self.source & 0x00F0),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            // the destination is always in the video ram
+            0xFF53 => self.destination = ((value & 0x1F) as u16) << 8 | (self.destination & 0x00F0),
+            0xFF54 => self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16,
+            0xFF55 => self.start(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// HDMA5: start a transfer of `(value & 0x7F) + 1` blocks, in HBlank mode if bit 7 is set.
+    /// Writing it with bit 7 clear during an HBlank DMA cancels it instead.
+    fn start(&mut self, value: u8) {
+        if self.active && self.hblank && value & 0x80 == 0 {
+            self.active = false;
+            return;
+        }
+        self.length = value & 0x7F;
+        self.hblank = value & 0x80 != 0;
+        self.active = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// whether the running transfer waits for HBlank between blocks
+    pub fn is_hblank(&self) -> bool {
+        self.hblank
+    }
+
+    /// move on to the next block. Returns the source address and video ram offset to copy it
+    /// from and to.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(BLOCK_LENGTH);
+        self.destination = (self.destination + BLOCK_LENGTH) & 0x1FF0;
+        let (length, done) = self.length.overflowing_sub(1);
+        self.length = length & 0x7F;
+        // the transfer also ends when it reaches the end of the video ram
+        if done || self.destination == 0 {
+            self.active = false;
+            self.length = 0x7F;
+        }
+        Some(block)
+    }
+}
+
+impl Snapshot for Hdma {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.destination);
+        state.write_u8(self.length);
+        state.write_bool(self.active);
+        state.write_bool(self.hblank);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()? & 0xFFF0;
+        self.destination = state.read_u16()? & 0x1FF0;
+        self.length = state.read_u8()? & 0x7F;
+        self.active = state.read_bool()?;
+        self.hblank = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_tests() {
+        let mut hdma = Hdma::default();
+        hdma.wb(0xFF51, 0x12);
+        hdma.wb(0xFF52, 0x3F);
+        hdma.wb(0xFF53, 0xFE);
+        hdma.wb(0xFF54, 0x5A);
+        assert_eq!(hdma.rb(0xFF51), 0xFF);
+        assert_eq!(hdma.rb(0xFF55), 0x80);
+
+        hdma.wb(0xFF55, 0x01);
+        assert_eq!(hdma.rb(0xFF55), 0x01);
+        assert_eq!(hdma.next_block(), Some((0x1230, 0x1E50)));
+        assert_eq!(hdma.rb(0xFF55), 0x00);
+        assert_eq!(hdma.next_block(), Some((0x1240, 0x1E60)));
+        assert_eq!(hdma.rb(0xFF55), 0xFF);
+        assert_eq!(hdma.next_block(), None);
+    }
+
+    #[test]
+    fn cancel_tests() {
+        let mut hdma = Hdma::default();
+        hdma.wb(0xFF55, 0x83);
+        assert!(hdma.is_hblank());
+        hdma.next_block();
+        assert_eq!(hdma.rb(0xFF55), 0x02);
+
+        hdma.wb(0xFF55, 0x00);
+        assert!(!hdma.is_active());
+        assert_eq!(hdma.rb(0xFF55), 0x82);
+        assert_eq!(hdma.next_block(), None);
+    }
+
+    #[test]
+    fn end_of_vram_tests() {
+        let mut hdma = Hdma::default();
+        hdma.wb(0xFF53, 0x1F);
+        hdma.wb(0xFF54, 0xE0);
+        hdma.wb(0xFF55, 0x7F);
+
+        assert_eq!(hdma.next_block(), Some((0x0000, 0x1FE0)));
+        assert_eq!(hdma.next_block(), Some((0x0010, 0x1FF0)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.rb(0xFF55), 0xFF);
+    }
+}
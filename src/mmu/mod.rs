@@ -1,34 +1,614 @@
+mod dma;
+mod hdma;
+
+use crate::apu::APU;
+use crate::cartridge::{Cartridge, Header};
+use crate::joypad::Joypad;
+use crate::ppu::{Mode, OamCorruption, PPU};
+use crate::serial::Serial;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use crate::timer::Timer;
+use dma::Dma;
+use hdma::{Hdma, BLOCK_LENGTH};
+
+/// bits of the IF (0xFF0F) and IE (0xFFFF) registers, by order of priority
+pub struct Interrupt;
+impl Interrupt {
+    pub const VBLANK: u8 = 1 << 0;
+    pub const STAT: u8 = 1 << 1;
+    pub const TIMER: u8 = 1 << 2;
+    pub const SERIAL: u8 = 1 << 3;
+    pub const JOYPAD: u8 = 1 << 4;
+}
+
+/// the hardware being emulated
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    /// the Game Boy Color, with its banked work and video ram and its double speed mode
+    Cgb,
+}
+
+impl Model {
+    /// the model a cartridge is meant for, CGB if its header says it supports it
+    pub fn for_cartridge(header: &Header) -> Self {
+        match header.cgb_flag & 0x80 {
+            0 => Model::Dmg,
+            _ => Model::Cgb,
+        }
+    }
+}
+
+/// registers of the CGB only, they read 0xFF and ignore writes on DMG and in the compatibility
+/// mode for DMG cartridges
+const CGB_REGISTERS: [u16; 12] = [
+    0xFF4D, 0xFF4F, 0xFF51, 0xFF52, 0xFF53, 0xFF54, 0xFF55, 0xFF68, 0xFF69, 0xFF6A, 0xFF6B, 0xFF70,
+];
+
 pub struct MMU {
+    model: Model,
     memory: Vec<u8>,
+    /// work ram, 0xC000-0xDFFF. The CGB has 8 banks of 4 KiB, the first one is always at
+    /// 0xC000 and SVBK selects the one at 0xD000.
+    wram: Vec<u8>,
+    svbk: u8,
+    /// KEY1 bit 0, the next STOP switches speed
+    speed_switch_armed: bool,
+    /// CGB double speed, the cpu, timer, serial port and OAM DMA then run at twice the clock
+    double_speed: bool,
+    /// clock cycles in double speed not yet passed on to the hardware that keeps the normal
+    /// pace, it is advanced in steps of 4
+    normal_speed_cycles: u32,
+    cartridge: Option<Cartridge>,
+    timer: Timer,
+    dma: Dma,
+    hdma: Hdma,
+    /// cpu cycles the cpu must wait for the blocks the VRAM DMA copied
+    hdma_stall: u32,
+    ppu: PPU,
+    joypad: Joypad,
+    serial: Serial,
+    apu: APU,
 }
 
 impl Default for MMU {
     fn default() -> Self {
-        Self {
-            memory: vec![0; 0xFFFF],
-        }
+        Self::new(Model::default())
     }
 }
 
 impl MMU {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            memory: vec![0; 0x10000],
+            wram: vec![0; 0x8000],
+            svbk: 0,
+            speed_switch_armed: false,
+            double_speed: false,
+            normal_speed_cycles: 0,
+            cartridge: None,
+            timer: Timer::default(),
+            dma: Dma::default(),
+            hdma: Hdma::default(),
+            hdma_stall: 0,
+            ppu: PPU::new(model),
+            joypad: Joypad::default(),
+            serial: Serial::default(),
+            apu: APU::default(),
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// whether KEY1 asks for a speed switch, which STOP then performs
+    pub fn speed_switch_armed(&self) -> bool {
+        self.speed_switch_armed
+    }
+
+    /// switch between normal and double speed, as STOP does once KEY1 armed it
+    pub fn switch_speed(&mut self) {
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        self.timer.set_double_speed(self.double_speed);
+    }
+
+    /// map a cartridge over the rom and external ram areas. Without a cartridge these areas
+    /// behave like plain memory.
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+
+    /// advance the hardware living on the bus by a number of cpu cycles. In double speed the
+    /// PPU, the APU and the cartridge clock only see half of them.
+    pub fn tick(&mut self, cycles: u32) {
+        let normal_cycles = self.normal_speed_cycles(cycles);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick(normal_cycles);
+        }
+        self.timer.tick(cycles);
+        if self.timer.take_interrupt() {
+            self.request_interrupt(Interrupt::TIMER);
+        }
+        self.apu.tick(normal_cycles);
+        for _ in 0..self.timer.take_frame_sequencer_clocks() {
+            self.apu.clock_frame_sequencer();
+        }
+        self.serial.tick(cycles);
+        if self.serial.take_interrupt() {
+            self.request_interrupt(Interrupt::SERIAL);
+        }
+        for _ in 0..cycles / 4 {
+            if let Some((source, offset)) = self.dma.step() {
+                let value = self.read(source);
+                self.dma.bus = value;
+                self.ppu.wb(0xFE00 + offset, value);
+            }
+        }
+        self.ppu.tick(normal_cycles);
+        // an HBlank DMA copies a block at the start of each HBlank of the visible lines
+        if self.ppu.take_hblank_started() && self.hdma.is_active() && self.hdma.is_hblank() {
+            self.copy_vram_blocks(1);
+        }
+        let interrupts = self.ppu.take_interrupts();
+        self.request_interrupt(interrupts);
+        if self.joypad.take_interrupt() {
+            self.request_interrupt(Interrupt::JOYPAD);
+        }
+    }
+
+    /// cpu cycles the cpu must wait since the last call, while the VRAM DMA copied blocks
+    pub fn take_hdma_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.hdma_stall)
+    }
+
+    /// HDMA5 was written: a general purpose DMA copies everything right away, an HBlank DMA
+    /// copies its first block right away if the PPU is already in HBlank or the LCD is off
+    fn start_vram_dma(&mut self) {
+        if !self.hdma.is_active() {
+            return;
+        }
+        if !self.hdma.is_hblank() {
+            self.copy_vram_blocks(u8::MAX);
+        } else if !self.ppu.lcd_enabled() || self.ppu.mode() == Mode::HBlank {
+            self.copy_vram_blocks(1);
+        }
+    }
+
+    /// copy up to `blocks` blocks of the VRAM DMA. Each takes about 8 µs during which the cpu
+    /// waits, 8 machine cycles in normal speed and 16 in double speed.
+    fn copy_vram_blocks(&mut self, blocks: u8) {
+        for _ in 0..blocks {
+            let (source, destination) = match self.hdma.next_block() {
+                Some(block) => block,
+                None => break,
+            };
+            for offset in 0..BLOCK_LENGTH {
+                let value = self.hdma_read(source.wrapping_add(offset));
+                self.ppu.wb(0x8000 + destination + offset, value);
+            }
+            self.hdma_stall += if self.double_speed { 64 } else { 32 };
+        }
+    }
+
+    /// a byte as the VRAM DMA reads it: it cannot read the video ram, and 0xE000-0xFFFF reach
+    /// the external ram
+    fn hdma_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9FFF => 0xFF,
+            0xE000..=0xFFFF => self.read(addr - 0x4000),
+            _ => self.read(addr),
+        }
+    }
+
+    /// the cycles of the hardware that keeps the normal pace, for a number of cpu cycles
+    fn normal_speed_cycles(&mut self, cycles: u32) -> u32 {
+        if !self.double_speed {
+            return cycles;
+        }
+        self.normal_speed_cycles += cycles / 2;
+        let normal_cycles = self.normal_speed_cycles & !3;
+        self.normal_speed_cycles &= 3;
+        normal_cycles
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    /// the buttons are pressed and released through here
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    /// devices are plugged into the link port through here
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    /// set an interrupt flag in IF
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        self.memory[0xFF0F] |= interrupt;
+    }
+
+    /// interrupts both requested and enabled, the cpu leaves HALT as soon as there is one
+    pub fn pending_interrupts(&self) -> u8 {
+        self.memory[0xFF0F] & self.memory[0xFFFF] & 0x1F
+    }
+
+    /// clear an interrupt flag in IF, once it is serviced
+    pub fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.memory[0xFF0F] &= !interrupt;
+    }
+
     /// read a byte in memory
     pub fn rb(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+        // a running OAM DMA owns every bus except the one to the io registers and high ram
+        if self.dma.is_active() && addr < 0xFF00 {
+            return self.dma.bus;
+        }
+        if !self.ppu.cpu_can_access(addr) {
+            return 0xFF;
+        }
+        self.read(addr)
     }
 
     /// write a byte in memory
     pub fn wb(&mut self, addr: u16, value: u8) {
-        self.memory[addr as usize] = value;
+        self.corrupt_oam(addr, OamCorruption::Write);
+        if (self.dma.is_active() && addr < 0xFF00) || !self.ppu.cpu_can_access(addr) {
+            return;
+        }
+        self.write(addr, value)
+    }
+
+    /// read a byte for the cpu. Like a write, putting an OAM address on the bus during mode 2
+    /// corrupts OAM on DMG, in a way that depends on what the cpu does with the address.
+    pub fn cpu_rb(&mut self, addr: u16, corruption: OamCorruption) -> u8 {
+        self.corrupt_oam(addr, corruption);
+        self.rb(addr)
+    }
+
+    /// the cpu put `addr` on the bus, which corrupts OAM on DMG if it points to 0xFE00-0xFEFF
+    pub fn corrupt_oam(&mut self, addr: u16, corruption: OamCorruption) {
+        // the OAM DMA owns the bus, the cpu cannot reach OAM
+        if (0xFE00..=0xFEFF).contains(&addr) && !self.dma.is_active() {
+            self.ppu.corrupt_oam(corruption);
+        }
+    }
+
+    /// read a byte the way a debugger sees it, whatever owns the bus at the moment
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    /// write a byte the way a debugger does, whatever owns the bus at the moment. Writes to the
+    /// io registers still have their usual side effects.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.write(addr, value)
+    }
+
+    fn cgb_registers_enabled(&self) -> bool {
+        self.model == Model::Cgb && !self.ppu.compatibility()
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        if !self.cgb_registers_enabled() && CGB_REGISTERS.contains(&addr) {
+            return 0xFF;
+        }
+        match (addr, &self.cartridge) {
+            (0x0000..=0x7FFF, Some(cartridge)) => cartridge.rb_rom(addr),
+            (0xA000..=0xBFFF, Some(cartridge)) => cartridge.rb_ram(addr),
+            (0x8000..=0x9FFF, _) | (0xFE00..=0xFE9F, _) => self.ppu.rb(addr),
+            (0xFF00, _) => self.joypad.rb(),
+            (0xFF01..=0xFF02, _) => self.serial.rb(addr),
+            (0xFF04..=0xFF07, _) => self.timer.rb(addr),
+            (0xFF10..=0xFF26, _) | (0xFF30..=0xFF3F, _) => self.apu.rb(addr),
+            (0xFF40..=0xFF45, _) | (0xFF47..=0xFF4B, _) => self.ppu.rb(addr),
+            (0xFF46, _) => self.dma.rb(),
+            (0xC000..=0xFDFF, _) => self.wram[self.wram_offset(addr)],
+            (0xFF4D, _) => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            (0xFF4F, _) | (0xFF68..=0xFF6B, _) => self.ppu.rb(addr),
+            (0xFF51..=0xFF55, _) => self.hdma.rb(addr),
+            (0xFF70, _) => 0xF8 | self.svbk,
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if !self.cgb_registers_enabled() && CGB_REGISTERS.contains(&addr) {
+            return;
+        }
+        match (addr, &mut self.cartridge) {
+            (0x0000..=0x7FFF, Some(cartridge)) => cartridge.wb_rom(addr, value),
+            (0xA000..=0xBFFF, Some(cartridge)) => cartridge.wb_ram(addr, value),
+            (0x8000..=0x9FFF, _) | (0xFE00..=0xFE9F, _) => self.ppu.wb(addr, value),
+            (0xFF00, _) => self.joypad.wb(value),
+            (0xFF01..=0xFF02, _) => self.serial.wb(addr, value),
+            (0xFF04..=0xFF07, _) => self.timer.wb(addr, value),
+            (0xFF10..=0xFF26, _) | (0xFF30..=0xFF3F, _) => self.apu.wb(addr, value),
+            (0xFF40..=0xFF45, _) | (0xFF47..=0xFF4B, _) => self.ppu.wb(addr, value),
+            (0xFF46, _) => self.dma.start(value),
+            (0xC000..=0xFDFF, _) => {
+                let offset = self.wram_offset(addr);
+                self.wram[offset] = value
+            }
+            (0xFF4D, _) => self.speed_switch_armed = value & 0x01 != 0,
+            (0xFF4F, _) | (0xFF68..=0xFF6B, _) => self.ppu.wb(addr, value),
+            (0xFF51..=0xFF55, _) => {
+                self.hdma.wb(addr, value);
+                if addr == 0xFF55 {
+                    self.start_vram_dma();
+                }
+            }
+            (0xFF70, _) => self.svbk = value & 0x07,
+            _ => self.memory[addr as usize] = value,
+        }
+    }
+
+    /// where `addr` lands in the work ram, 0xE000-0xFDFF mirrors 0xC000-0xDDFF
+    fn wram_offset(&self, addr: u16) -> usize {
+        let offset = (addr as usize - 0xC000) & 0x1FFF;
+        match offset {
+            0x0000..=0x0FFF => offset,
+            // bank 0 cannot be selected at 0xD000, it maps bank 1 instead
+            _ => self.svbk.max(1) as usize * 0x1000 + offset - 0x1000,
+        }
     }
 
     /// read a 16bit word in memory
     pub fn rw(&self, addr: u16) -> u16 {
-        u16::from_be_bytes([self.memory[addr as usize], self.memory[(addr + 1) as usize]])
+        u16::from_be_bytes([self.rb(addr), self.rb(addr.wrapping_add(1))])
     }
 
     /// write a 16bit word in memory
     pub fn ww(&mut self, addr: u16, value: u16) {
-        self.memory[addr as usize] = value.to_be_bytes()[0];
-        self.memory[(addr + 1) as usize] = value.to_be_bytes()[1];
+        self.wb(addr, value.to_be_bytes()[0]);
+        self.wb(addr.wrapping_add(1), value.to_be_bytes()[1]);
+    }
+}
+
+impl Snapshot for MMU {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.memory);
+        state.write_u8(self.model as u8);
+        state.write_bytes(&self.wram);
+        state.write_u8(self.svbk);
+        state.write_bool(self.speed_switch_armed);
+        state.write_bool(self.double_speed);
+        state.write_u8(self.normal_speed_cycles as u8);
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save(state);
+        }
+        self.timer.save(state);
+        self.dma.save(state);
+        self.hdma.save(state);
+        state.write_u32(self.hdma_stall);
+        self.ppu.save(state);
+        self.joypad.save(state);
+        self.serial.save(state);
+        self.apu.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.memory)?;
+        if state.read_u8()? != self.model as u8 {
+            return Err(StateError::InvalidData("state saved on another model"));
+        }
+        state.read_bytes_into(&mut self.wram)?;
+        self.svbk = state.read_u8()? & 0x07;
+        self.speed_switch_armed = state.read_bool()?;
+        self.double_speed = state.read_bool()?;
+        self.normal_speed_cycles = state.read_u8()? as u32 & 3;
+        self.timer.set_double_speed(self.double_speed);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load(state)?;
+        }
+        self.timer.load(state)?;
+        self.dma.load(state)?;
+        self.hdma.load(state)?;
+        self.hdma_stall = state.read_u32()?;
+        self.ppu.load(state)?;
+        self.joypad.load(state)?;
+        self.serial.load(state)?;
+        self.apu.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::Mode;
+
+    #[test]
+    fn ppu_lock_tests() {
+        let mut mmu = MMU::default();
+        mmu.wb(0x8000, 0x11);
+        mmu.wb(0xFE00, 0x22);
+        mmu.wb(0xFF40, 0x80);
+
+        // mode 2: OAM is locked, the video ram is not
+        assert_eq!(mmu.ppu().mode(), Mode::OamScan);
+        assert_eq!(mmu.rb(0x8000), 0x11);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        mmu.wb(0xFE00, 0x33);
+
+        // mode 3: both are locked
+        mmu.tick(80);
+        assert_eq!(mmu.ppu().mode(), Mode::Drawing);
+        assert_eq!(mmu.rb(0x8000), 0xFF);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        mmu.wb(0x8000, 0x44);
+
+        // a debugger still sees and changes the real memory
+        assert_eq!(mmu.peek(0x8000), 0x11);
+        assert_eq!(mmu.peek(0xFE00), 0x22);
+        mmu.poke(0x8001, 0x55);
+
+        mmu.tick(172);
+        assert_eq!(mmu.ppu().mode(), Mode::HBlank);
+        assert_eq!(mmu.rb(0x8000), 0x11);
+        assert_eq!(mmu.rb(0x8001), 0x55);
+        assert_eq!(mmu.rb(0xFE00), 0x22);
+    }
+
+    #[test]
+    fn wram_banking_tests() {
+        let mut mmu = MMU::new(Model::Cgb);
+        mmu.wb(0xC000, 0x10);
+        for bank in 1..8 {
+            mmu.wb(0xFF70, bank);
+            mmu.wb(0xD000, 0x10 + bank);
+        }
+        assert_eq!(mmu.rb(0xFF70), 0xFF);
+
+        // bank 0 maps bank 1 at 0xD000
+        mmu.wb(0xFF70, 0x00);
+        assert_eq!(mmu.rb(0xFF70), 0xF8);
+        assert_eq!(mmu.rb(0xD000), 0x11);
+        mmu.wb(0xFF70, 0x03);
+        assert_eq!(mmu.rb(0xD000), 0x13);
+        assert_eq!(mmu.rb(0xC000), 0x10);
+
+        // echo ram follows the banks
+        assert_eq!(mmu.rb(0xE000), 0x10);
+        assert_eq!(mmu.rb(0xF000), 0x13);
+        mmu.wb(0xF001, 0x42);
+        assert_eq!(mmu.rb(0xD001), 0x42);
+    }
+
+    #[test]
+    fn vram_banking_tests() {
+        let mut mmu = MMU::new(Model::Cgb);
+        mmu.wb(0x8000, 0x11);
+        mmu.wb(0xFF4F, 0x01);
+        assert_eq!(mmu.rb(0xFF4F), 0xFF);
+        assert_eq!(mmu.rb(0x8000), 0x00);
+        mmu.wb(0x8000, 0x22);
+        mmu.wb(0xFF4F, 0x00);
+        assert_eq!(mmu.rb(0xFF4F), 0xFE);
+        assert_eq!(mmu.rb(0x8000), 0x11);
+    }
+
+    #[test]
+    fn dmg_cgb_registers_tests() {
+        let mut mmu = MMU::default();
+        for &register in CGB_REGISTERS.iter() {
+            mmu.wb(register, 0x01);
+            assert_eq!(mmu.rb(register), 0xFF);
+        }
+        assert!(!mmu.speed_switch_armed());
+
+        // there is a single bank at 0xD000
+        mmu.wb(0xD000, 0x42);
+        mmu.wb(0xFF4F, 0x01);
+        mmu.wb(0x8000, 0x24);
+        assert_eq!(mmu.rb(0xD000), 0x42);
+        assert_eq!(mmu.ppu().rb(0x8000), 0x24);
+    }
+
+    /// program the VRAM DMA to copy from `source` to `destination`
+    fn program_hdma(mmu: &mut MMU, source: u16, destination: u16, control: u8) {
+        mmu.wb(0xFF51, (source >> 8) as u8);
+        mmu.wb(0xFF52, source as u8);
+        mmu.wb(0xFF53, (destination >> 8) as u8);
+        mmu.wb(0xFF54, destination as u8);
+        mmu.wb(0xFF55, control);
+    }
+
+    #[test]
+    fn general_purpose_dma_tests() {
+        let mut mmu = MMU::new(Model::Cgb);
+        for offset in 0..0x30 {
+            mmu.wb(0xC100 + offset, offset as u8);
+        }
+        mmu.wb(0xFF4F, 0x01);
+        // the low bits of the addresses are ignored, the destination is always in video ram
+        program_hdma(&mut mmu, 0xC10F, 0xE80A, 0x01);
+
+        assert_eq!(mmu.rb(0xFF55), 0xFF);
+        assert_eq!(mmu.take_hdma_stall(), 64);
+        assert_eq!(mmu.rb(0x8800), 0x00);
+        assert_eq!(mmu.rb(0x881F), 0x1F);
+        assert_eq!(mmu.rb(0x8820), 0x00);
+        mmu.wb(0xFF4F, 0x00);
+        assert_eq!(mmu.rb(0x8800), 0x00);
+
+        // blocks take as long in double speed, which is twice the cpu cycles
+        mmu.switch_speed();
+        program_hdma(&mut mmu, 0xC100, 0x8000, 0x00);
+        assert_eq!(mmu.take_hdma_stall(), 64);
+    }
+
+    #[test]
+    fn hblank_dma_tests() {
+        let mut mmu = MMU::new(Model::Cgb);
+        for offset in 0..0x30 {
+            mmu.wb(0xC000 + offset, 0x80 | offset as u8);
+        }
+        mmu.wb(0xFF40, 0x80);
+        program_hdma(&mut mmu, 0xC000, 0x9000, 0x82);
+        assert_eq!(mmu.rb(0xFF55), 0x02);
+        assert_eq!(mmu.take_hdma_stall(), 0);
+
+        // one block at the start of each HBlank
+        mmu.tick(248);
+        assert_eq!(mmu.rb(0xFF55), 0x02);
+        mmu.tick(4);
+        assert_eq!(mmu.rb(0xFF55), 0x01);
+        assert_eq!(mmu.take_hdma_stall(), 32);
+        assert_eq!(mmu.rb(0x900F), 0x8F);
+        assert_eq!(mmu.rb(0x9010), 0x00);
+        mmu.tick(200);
+        assert_eq!(mmu.rb(0xFF55), 0x01);
+        mmu.tick(456);
+        assert_eq!(mmu.rb(0xFF55), 0x00);
+        assert_eq!(mmu.rb(0x901F), 0x9F);
+
+        // cancelled before the last block
+        mmu.wb(0xFF55, 0x00);
+        assert_eq!(mmu.rb(0xFF55), 0x80);
+        mmu.tick(456);
+        assert_eq!(mmu.rb(0x9020), 0x00);
+
+        // started during HBlank, the first block is copied right away
+        program_hdma(&mut mmu, 0xC020, 0x9020, 0x80);
+        assert_eq!(mmu.rb(0xFF55), 0xFF);
+        assert_eq!(mmu.rb(0x9020), 0xA0);
     }
 }
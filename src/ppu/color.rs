@@ -0,0 +1,128 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// RGB555 of the DMG shades, from white to black
+pub const DMG_SHADES: [u16; 4] = [
+    rgb555(31, 31, 31),
+    rgb555(21, 21, 21),
+    rgb555(10, 10, 10),
+    0,
+];
+
+/// a color as the CGB stores it: red in bits 0-4, green in bits 5-9 and blue in bits 10-14
+pub const fn rgb555(red: u8, green: u8, blue: u8) -> u16 {
+    red as u16 | (green as u16) << 5 | (blue as u16) << 10
+}
+
+/// approximate how the CGB LCD shows a color: its channels bleed into each other, which makes
+/// colors duller than their raw values. The grays, white and black are left as they are.
+pub fn correct(color: u16) -> u16 {
+    let red = (color & 0x1F) as u32;
+    let green = (color >> 5 & 0x1F) as u32;
+    let blue = (color >> 10 & 0x1F) as u32;
+    rgb555(
+        ((red * 26 + green * 4 + blue * 2) / 32) as u8,
+        ((green * 24 + blue * 8) / 32) as u8,
+        ((red * 6 + green * 4 + blue * 22) / 32) as u8,
+    )
+}
+
+/// the 8 palettes of 4 colors of the CGB for the background or the objects. The cpu reaches
+/// them through an index register (BCPS, OCPS), whose bit 7 moves the index forward after
+/// each write, and a data register (BCPD, OCPD).
+pub(super) struct PaletteRam {
+    data: [u8; 64],
+    /// the byte reached through the data register in bits 0-5, auto increment in bit 7
+    index: u8,
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        Self {
+            data: [0xFF; 64],
+            index: 0,
+        }
+    }
+}
+
+impl PaletteRam {
+    pub fn rb_index(&self) -> u8 {
+        0x40 | self.index
+    }
+
+    pub fn wb_index(&mut self, value: u8) {
+        self.index = value & 0xBF;
+    }
+
+    pub fn rb_data(&self) -> u8 {
+        self.data[(self.index & 0x3F) as usize]
+    }
+
+    pub fn wb_data(&mut self, value: u8) {
+        self.data[(self.index & 0x3F) as usize] = value;
+        if self.index & 0x80 != 0 {
+            self.index = 0x80 | (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// overwrite the 4 colors of a palette, as the boot rom does
+    pub fn set_palette(&mut self, palette: u8, colors: &[u16; 4]) {
+        for (color, &value) in colors.iter().enumerate() {
+            let offset = palette as usize * 8 + color * 2;
+            self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// RGB555 of a color of a palette
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = palette as usize * 8 + color as usize * 2;
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) & 0x7FFF
+    }
+}
+
+impl Snapshot for PaletteRam {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.index);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.data)?;
+        self.index = state.read_u8()? & 0xBF;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_ram_tests() {
+        let mut palettes = PaletteRam::default();
+        palettes.wb_index(0x80 | 0x3E);
+        assert_eq!(palettes.rb_index(), 0xFE);
+        palettes.wb_data(0x1F);
+        palettes.wb_data(0x7C);
+        // the index wraps around
+        assert_eq!(palettes.rb_index(), 0xC0);
+        palettes.wb_data(0x34);
+        assert_eq!(palettes.color(7, 3), rgb555(31, 0, 31));
+        assert_eq!(palettes.color(0, 0), 0x7F34);
+
+        // without auto increment the index stays put
+        palettes.wb_index(0x02);
+        palettes.wb_data(0x12);
+        palettes.wb_data(0x56);
+        assert_eq!(palettes.rb_index(), 0x42);
+        assert_eq!(palettes.rb_data(), 0x56);
+    }
+
+    #[test]
+    fn correction_tests() {
+        for &shade in DMG_SHADES.iter() {
+            assert_eq!(correct(shade), shade);
+        }
+        assert_eq!(correct(rgb555(31, 0, 0)), rgb555(25, 0, 5));
+        assert_eq!(correct(rgb555(0, 0, 31)), rgb555(1, 7, 21));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::cartridge::Cartridge;
+
+/// the colors the CGB gives a cartridge made for the DMG: the four shades of BGP, OBP0 and OBP1
+/// are looked up in these palettes instead of being shown as grays
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatibilityPalettes {
+    pub background: [u16; 4],
+    pub object0: [u16; 4],
+    pub object1: [u16; 4],
+}
+
+/// the palettes the boot rom lets the user choose by holding a direction, and optionally A or
+/// B, while the logo is shown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManualPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ManualPalette {
+    pub const ALL: [ManualPalette; 12] = [
+        ManualPalette::Up,
+        ManualPalette::UpA,
+        ManualPalette::UpB,
+        ManualPalette::Left,
+        ManualPalette::LeftA,
+        ManualPalette::LeftB,
+        ManualPalette::Down,
+        ManualPalette::DownA,
+        ManualPalette::DownB,
+        ManualPalette::Right,
+        ManualPalette::RightA,
+        ManualPalette::RightB,
+    ];
+
+    pub fn palettes(self) -> CompatibilityPalettes {
+        let combination = match self {
+            ManualPalette::Up => 5,
+            ManualPalette::UpA => 43,
+            ManualPalette::UpB => 28,
+            ManualPalette::Left => 48,
+            ManualPalette::LeftA => 40,
+            ManualPalette::LeftB => 7,
+            ManualPalette::Down => 8,
+            ManualPalette::DownA => 3,
+            ManualPalette::DownB => 49,
+            ManualPalette::Right => 1,
+            ManualPalette::RightA => 0,
+            ManualPalette::RightB => 6,
+        };
+        CompatibilityPalettes::combination(combination)
+    }
+}
+
+impl CompatibilityPalettes {
+    /// the palettes the boot rom picks for a cartridge. Games published by Nintendo are
+    /// recognised by the sum of the bytes of their title, the others get the default palettes.
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self {
+        Self::combination(Self::lookup(|addr| cartridge.rb_rom(addr)))
+    }
+
+    fn lookup<F: Fn(u16) -> u8>(rb: F) -> usize {
+        let nintendo = match rb(0x014B) {
+            0x01 => true,
+            0x33 => rb(0x0144) == b'0' && rb(0x0145) == b'1',
+            _ => false,
+        };
+        if !nintendo {
+            return 0;
+        }
+        let checksum = (0x0134..=0x0143).fold(0u8, |sum, addr| sum.wrapping_add(rb(addr)));
+        // some checksums are shared by several titles, the 4th letter tells them apart
+        let fourth_letter = rb(0x0137);
+        TITLE_CHECKSUMS
+            .iter()
+            .enumerate()
+            .position(|(index, &title)| {
+                title == checksum
+                    && (index < FIRST_SHARED_CHECKSUM
+                        || FOURTH_LETTERS[index - FIRST_SHARED_CHECKSUM] == fourth_letter)
+            })
+            .map_or(0, |index| COMBINATION_PER_CHECKSUM[index] as usize)
+    }
+
+    fn combination(index: usize) -> Self {
+        let [object0, object1, background] = COMBINATIONS[index];
+        let palette = |offset: u8| {
+            let mut colors = [0; 4];
+            colors.copy_from_slice(&COLORS[offset as usize..offset as usize + 4]);
+            colors
+        };
+        Self {
+            background: palette(background),
+            object0: palette(object0),
+            object1: palette(object1),
+        }
+    }
+}
+
+/// the palettes of the boot rom, 4 colors each
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+    0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+    0x231F, 0x035F, 0x00F2, 0x0009, // 17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+    0x299F, 0x001A, 0x000C, 0x0000, // 19
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+/// offset in `COLORS` of a palette
+const fn palette(index: u8) -> u8 {
+    index * 4
+}
+
+/// the palettes of the objects 0, the objects 1 and the background, as offsets in `COLORS`.
+/// A few of them start in the middle of a palette, like the boot rom's table.
+const COMBINATIONS: [[u8; 3]; 51] = [
+    [palette(4), palette(4), palette(29)],
+    [palette(18), palette(18), palette(18)],
+    [palette(20), palette(20), palette(20)],
+    [palette(24), palette(24), palette(24)],
+    [palette(9), palette(9), palette(9)],
+    [palette(0), palette(0), palette(0)],
+    [palette(27), palette(27), palette(27)],
+    [palette(5), palette(5), palette(5)],
+    [palette(12), palette(12), palette(12)],
+    [palette(26), palette(26), palette(26)],
+    [palette(16), palette(8), palette(8)],
+    [palette(4), palette(28), palette(28)],
+    [palette(4), palette(2), palette(2)],
+    [palette(3), palette(4), palette(4)],
+    [palette(4), palette(29), palette(29)],
+    [palette(28), palette(4), palette(28)],
+    [palette(2), palette(17), palette(2)],
+    [palette(16), palette(16), palette(8)],
+    [palette(4), palette(4), palette(7)],
+    [palette(4), palette(4), palette(18)],
+    [palette(4), palette(4), palette(20)],
+    [palette(19), palette(19), palette(9)],
+    [palette(4) - 1, palette(4) - 1, palette(11)],
+    [palette(17), palette(17), palette(2)],
+    [palette(4), palette(4), palette(2)],
+    [palette(4), palette(4), palette(3)],
+    [palette(28), palette(28), palette(0)],
+    [palette(3), palette(3), palette(0)],
+    [palette(0), palette(0), palette(1)],
+    [palette(18), palette(22), palette(18)],
+    [palette(20), palette(22), palette(20)],
+    [palette(24), palette(22), palette(24)],
+    [palette(16), palette(22), palette(8)],
+    [palette(17), palette(4), palette(13)],
+    [palette(28) - 1, palette(0), palette(14)],
+    [palette(28) - 1, palette(4), palette(15)],
+    [palette(19), palette(22), palette(9)],
+    [palette(16), palette(28), palette(10)],
+    [palette(4), palette(23), palette(28)],
+    [palette(17), palette(22), palette(2)],
+    [palette(4), palette(0), palette(2)],
+    [palette(4), palette(28), palette(3)],
+    [palette(28), palette(3), palette(0)],
+    [palette(3), palette(28), palette(4)],
+    [palette(21), palette(28), palette(4)],
+    [palette(3), palette(28), palette(0)],
+    [palette(25), palette(3), palette(28)],
+    [palette(0), palette(28), palette(8)],
+    [palette(4), palette(3), palette(28)],
+    [palette(28), palette(3), palette(6)],
+    [palette(4), palette(28), palette(29)],
+];
+
+/// the checksums from here on are only recognised along with the 4th letter of the title
+const FIRST_SHARED_CHECKSUM: usize = 65;
+
+/// sums of the title bytes of the games the boot rom knows
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, // shared checksums
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3, 0x46,
+    0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+/// 4th letter of the titles with a shared checksum
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// index in `COMBINATIONS` of each title checksum
+const COMBINATION_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 14, 16, 25, 42, 42, 5, 0, 39, // shared checksums
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19,
+    34, 23, 18, 29,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::rgb555;
+
+    fn rom(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = licensee;
+        rom
+    }
+
+    fn lookup(rom: &[u8]) -> usize {
+        CompatibilityPalettes::lookup(|addr| rom[addr as usize])
+    }
+
+    #[test]
+    fn title_checksum_tests() {
+        // TETRIS sums to 0xDB
+        assert_eq!(lookup(&rom(b"TETRIS", 0x01)), 3);
+        assert_eq!(lookup(&rom(b"ZELDA", 0x01)), 44);
+        // the new licensee code of Nintendo
+        let mut zelda = rom(b"ZELDA", 0x33);
+        zelda[0x0144..0x0146].copy_from_slice(b"01");
+        assert_eq!(lookup(&zelda), 44);
+        // games of other publishers get the default palettes
+        assert_eq!(lookup(&rom(b"TETRIS", 0x33)), 0);
+        assert_eq!(lookup(&rom(b"TETRIS", 0x08)), 0);
+        assert_eq!(lookup(&rom(b"UNKNOWN", 0x01)), 0);
+    }
+
+    #[test]
+    fn fourth_letter_tests() {
+        assert_eq!(lookup(&rom(b"POKEMON RED", 0x01)), 13);
+        // POKEMON BLUE shares its checksum with VEGAS STAKES, other 4th letters do not match
+        assert_eq!(lookup(&rom(b"POKEMON BLUE", 0x01)), 11);
+        assert_eq!(lookup(&rom(b"VEGAS STAKES", 0x01)), 41);
+        let mut other = rom(b"POKGMON BLUE", 0x01);
+        other[0x0138] -= 2;
+        assert_eq!(lookup(&other), 0);
+        assert_eq!(lookup(&rom(b"SUPER MARIOLAND", 0x01)), 22);
+    }
+
+    #[test]
+    fn combination_tests() {
+        let default = CompatibilityPalettes::combination(0);
+        assert_eq!(default, ManualPalette::RightA.palettes());
+        assert_eq!(default.background[1], rgb555(15, 31, 6));
+        assert_eq!(default.object0[2], rgb555(18, 7, 7));
+
+        // a palette that starts at the last color of the previous one
+        let mario = CompatibilityPalettes::combination(22);
+        assert_eq!(mario.object0, [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+        assert_eq!(mario.background, [0x7ED6, 0x4BFF, 0x2175, 0x0000]);
+
+        let brown = ManualPalette::Up.palettes();
+        assert_eq!(
+            brown.background,
+            [0x7FFF, rgb555(31, 21, 12), rgb555(16, 6, 0), 0]
+        );
+        let left = ManualPalette::Left.palettes();
+        assert_eq!(left.background[3], 0);
+        assert_ne!(left.object0, left.object1);
+        for (index, palette) in ManualPalette::ALL.iter().enumerate() {
+            for other in ManualPalette::ALL[index + 1..].iter() {
+                assert_ne!(palette.palettes(), other.palettes());
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use super::{PPU, SCREEN_WIDTH};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+/// dots the fetcher needs to read a tile index and the two bytes of a tile row
+const FETCH_DOTS: u8 = 6;
+/// dots an object fetch pauses the FIFO for, on top of the wait for the background fetcher
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+/// state of the pixel FIFO renderer during mode 3.
+///
+/// The fetcher reads the background or the window one tile row at a time and pushes its 8
+/// pixels once the FIFO is empty, the FIFO shifts one pixel out to the screen every dot. The
+/// palettes are applied and the objects mixed in as pixels leave the FIFO, so register writes
+/// during mode 3 affect the rest of the line. Mode 3 lasts until 160 pixels were shifted out:
+/// the pixels discarded for the fine scroll, the restart of the fetcher on the window and the
+/// object fetches all make it longer.
+#[derive(Default)]
+pub(super) struct Fifo {
+    /// background or window pixels waiting to be shifted out, see `PPU::map_pixel`
+    pixels: VecDeque<u8>,
+    /// next pixel of the line to draw
+    x: u8,
+    /// pixels to drop before drawing, for the fine scroll of the background or the window
+    discard: u8,
+    /// tile column the fetcher reads next, relative to the background scroll or the window
+    fetch_x: u8,
+    /// dots spent on the current fetch
+    fetch_dots: u8,
+    /// the fetcher switched to the window on this line
+    window: bool,
+    /// dots left during which both the fetcher and the FIFO are paused
+    stall: u8,
+    /// objects of the line already fetched, by position in `PPU::sprites`
+    fetched_sprites: u16,
+    /// background tiles whose fetch an object already waited for
+    waited_tiles: u32,
+}
+
+impl PPU {
+    /// reset the FIFO at the start of mode 3
+    pub(super) fn start_fifo(&mut self) {
+        self.fifo = Fifo {
+            discard: self.scx % 8,
+            // the first tile is fetched twice, the first fetch is thrown away
+            stall: FETCH_DOTS,
+            ..Fifo::default()
+        };
+    }
+
+    /// run the FIFO for one dot of mode 3, returns whether the line is complete
+    pub(super) fn step_fifo(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+        if !self.fifo.window && self.window_starts() {
+            // the fetcher restarts on the window, the pixels already fetched are lost
+            self.fifo.pixels.clear();
+            self.fifo.window = true;
+            self.fifo.fetch_x = 0;
+            self.fifo.fetch_dots = 0;
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+        if let Some(dots) = self.fetch_sprite() {
+            self.fifo.stall = dots - 1;
+            return false;
+        }
+        self.step_fetcher();
+        self.shift_pixel();
+
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            // the window line counter only moves on lines where the window was drawn
+            if self.fifo.window {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn window_starts(&self) -> bool {
+        self.lcdc & 0x20 != 0 && self.window_triggered && self.fifo.x as u16 + 7 >= self.wx as u16
+    }
+
+    /// start the fetch of the next object reaching the current pixel, returns the number of dots
+    /// the FIFO is paused for
+    fn fetch_sprite(&mut self) -> Option<u8> {
+        if self.lcdc & 0x02 == 0 {
+            return None;
+        }
+        let screen_x = self.fifo.x + 8;
+        let fetched = self.fifo.fetched_sprites;
+        let index = (0..self.sprites.len())
+            .find(|&index| fetched & (1 << index) == 0 && self.sprites[index].x <= screen_x)?;
+        self.fifo.fetched_sprites |= 1 << index;
+
+        // the object fetch first waits for the background fetcher to finish the tile under it,
+        // later objects over the same tile do not wait again
+        let sprite_x = self.sprites[index].x;
+        let tile = (sprite_x as u16 + (self.scx % 8) as u16) / 8;
+        let mut dots = SPRITE_FETCH_DOTS;
+        if self.fifo.waited_tiles & (1 << tile) == 0 {
+            self.fifo.waited_tiles |= 1 << tile;
+            dots += 5u8.saturating_sub(sprite_x.wrapping_add(self.scx) % 8);
+        }
+        Some(dots)
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.fetch_dots < FETCH_DOTS {
+            self.fifo.fetch_dots += 1;
+            return;
+        }
+        if !self.fifo.pixels.is_empty() {
+            return;
+        }
+        for pixel in 0..8 {
+            let color = self.fetched_pixel(pixel);
+            self.fifo.pixels.push_back(color);
+        }
+        self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+        self.fifo.fetch_dots = 0;
+    }
+
+    /// pixel of the tile row being fetched, using the registers as they are now
+    fn fetched_pixel(&self, pixel: u8) -> u8 {
+        let x = self.fifo.fetch_x.wrapping_mul(8).wrapping_add(pixel);
+        if self.fifo.window {
+            self.map_pixel(self.window_map(), x, self.window_line)
+        } else {
+            let y = self.ly.wrapping_add(self.scy);
+            self.map_pixel(self.background_map(), x.wrapping_add(self.scx & 0xF8), y)
+        }
+    }
+
+    fn shift_pixel(&mut self) {
+        let pixel = match self.fifo.pixels.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        // on DMG, clearing LCDC bit 0 blanks both the background and the window
+        let pixel = if self.lcdc & 0x01 != 0 || self.cgb {
+            pixel
+        } else {
+            0
+        };
+        self.draw_pixel(self.fifo.x, pixel);
+        self.fifo.x += 1;
+    }
+}
+
+impl Snapshot for Fifo {
+    fn save(&self, state: &mut StateWriter) {
+        let pixels: Vec<u8> = self.pixels.iter().copied().collect();
+        state.write_bytes(&pixels);
+        state.write_u8(self.x);
+        state.write_u8(self.discard);
+        state.write_u8(self.fetch_x);
+        state.write_u8(self.fetch_dots);
+        state.write_bool(self.window);
+        state.write_u8(self.stall);
+        state.write_u16(self.fetched_sprites);
+        state.write_u32(self.waited_tiles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let pixels = state.read_bytes()?;
+        self.x = state.read_u8()?;
+        if pixels.len() > 8 || self.x as usize > SCREEN_WIDTH {
+            return Err(StateError::InvalidData("pixel FIFO out of range"));
+        }
+        self.pixels = pixels.iter().copied().collect();
+        self.discard = state.read_u8()?;
+        self.fetch_x = state.read_u8()?;
+        self.fetch_dots = state.read_u8()?;
+        self.window = state.read_bool()?;
+        self.stall = state.read_u8()?;
+        self.fetched_sprites = state.read_u16()?;
+        self.waited_tiles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Mode, SCREEN_HEIGHT};
+    use super::*;
+    use crate::mmu::{Interrupt, Model};
+
+    fn fifo_ppu(lcdc: u8) -> PPU {
+        let mut ppu = PPU::default();
+        ppu.set_pixel_fifo(true);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF48, 0b11_10_01_00);
+        ppu.wb(0xFF40, lcdc);
+        ppu
+    }
+
+    /// number of dots mode 3 of the first line lasts
+    fn drawing_dots(ppu: &mut PPU) -> u32 {
+        ppu.tick(80);
+        let mut dots = 0;
+        while ppu.mode() == Mode::Drawing {
+            ppu.tick(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn mode_length_tests() {
+        let mut ppu = fifo_ppu(0x91);
+        assert_eq!(drawing_dots(&mut ppu), 172);
+
+        // the pixels dropped for the fine scroll
+        let mut ppu = fifo_ppu(0x91);
+        ppu.wb(0xFF43, 0x13);
+        assert_eq!(drawing_dots(&mut ppu), 175);
+
+        // the fetcher restarts on the window
+        let mut ppu = fifo_ppu(0xB1);
+        ppu.wb(0xFF4B, 87);
+        assert_eq!(drawing_dots(&mut ppu), 178);
+    }
+
+    #[test]
+    fn sprite_penalty_tests() {
+        // an object at X = 0 waits 5 dots for the background fetcher, then takes 6 dots
+        let mut ppu = fifo_ppu(0x93);
+        ppu.wb(0xFE00, 16);
+        ppu.wb(0xFE01, 0);
+        assert_eq!(drawing_dots(&mut ppu), 183);
+
+        // a second object over the same tile only costs its own fetch
+        let mut ppu = fifo_ppu(0x93);
+        ppu.wb(0xFE00, 16);
+        ppu.wb(0xFE01, 0);
+        ppu.wb(0xFE04, 16);
+        ppu.wb(0xFE05, 4);
+        assert_eq!(drawing_dots(&mut ppu), 189);
+
+        // an object further in its tile waits less, objects past the screen are not fetched
+        let mut ppu = fifo_ppu(0x93);
+        ppu.wb(0xFE00, 16);
+        ppu.wb(0xFE01, 44);
+        ppu.wb(0xFE04, 16);
+        ppu.wb(0xFE05, 168);
+        assert_eq!(drawing_dots(&mut ppu), 179);
+
+        // objects are not fetched while they are disabled
+        let mut ppu = fifo_ppu(0x91);
+        ppu.wb(0xFE00, 16);
+        assert_eq!(drawing_dots(&mut ppu), 172);
+    }
+
+    #[test]
+    fn hblank_interrupt_tests() {
+        let mut ppu = fifo_ppu(0x91);
+        ppu.wb(0xFF43, 0x05);
+        ppu.wb(0xFF41, 0x08);
+
+        ppu.tick(80 + 176);
+        assert_eq!(ppu.take_interrupts(), 0);
+        ppu.tick(1);
+        assert_eq!(ppu.take_interrupts(), Interrupt::STAT);
+        assert_eq!(ppu.rb(0xFF41) & 0x03, 0);
+    }
+
+    #[test]
+    fn mid_line_tests() {
+        let mut ppu = fifo_ppu(0x91);
+        for row in 0..8 {
+            ppu.wb(0x8000 + row * 2, 0xFF);
+        }
+
+        // the first pixel leaves the FIFO on the 13th dot of mode 3
+        ppu.tick(80 + 12 + 50);
+        ppu.wb(0xFF47, 0b11_10_10_00);
+        ppu.tick(456 * 2 - 80 - 12 - 50);
+
+        assert!(ppu.framebuffer()[..50].iter().all(|&shade| shade == 1));
+        assert!(ppu.framebuffer()[50..160].iter().all(|&shade| shade == 2));
+        assert!(ppu.framebuffer()[160..320].iter().all(|&shade| shade == 2));
+    }
+
+    #[test]
+    fn scanline_match_tests() {
+        let setup = |ppu: &mut PPU| {
+            for address in 0..0x1800 {
+                ppu.wb(0x8000 + address, (address * 7 + address / 3) as u8);
+            }
+            for address in 0..0x800 {
+                ppu.wb(0x9800 + address, (address * 13) as u8);
+            }
+            for index in 0..40 {
+                ppu.wb(0xFE00 + index * 4, 12 + index as u8 * 4);
+                ppu.wb(0xFE01 + index * 4, index as u8 * 5);
+                ppu.wb(0xFE02 + index * 4, index as u8);
+                ppu.wb(0xFE03 + index * 4, (index * 0x30) as u8 & 0xF0);
+            }
+            // random looking tile attributes and palettes, ignored on DMG
+            ppu.wb(0xFF4F, 0x01);
+            for address in 0..0x800 {
+                ppu.wb(0x9800 + address, (address * 29) as u8);
+            }
+            ppu.wb(0xFF4F, 0x00);
+            ppu.wb(0xFF68, 0x80);
+            ppu.wb(0xFF6A, 0x80);
+            for index in 0..64u16 {
+                ppu.wb(0xFF69, (index * 37) as u8);
+                ppu.wb(0xFF6B, (index * 53) as u8);
+            }
+            ppu.wb(0xFF42, 37);
+            ppu.wb(0xFF43, 93);
+            ppu.wb(0xFF4A, 60);
+            ppu.wb(0xFF4B, 50);
+            ppu.wb(0xFF47, 0b11_10_01_00);
+            ppu.wb(0xFF48, 0b00_01_10_11);
+            ppu.wb(0xFF49, 0b10_11_00_01);
+            ppu.wb(0xFF40, 0xF7);
+            ppu.tick(456 * SCREEN_HEIGHT as u32);
+        };
+
+        for &model in [Model::Dmg, Model::Cgb].iter() {
+            let mut scanline = PPU::new(model);
+            setup(&mut scanline);
+            let mut fifo = PPU::new(model);
+            fifo.set_pixel_fifo(true);
+            setup(&mut fifo);
+
+            assert!(scanline.framebuffer().iter().any(|&shade| shade != 0));
+            assert!(scanline.framebuffer() == fifo.framebuffer());
+            assert!(scanline.rgb_framebuffer() == fifo.rgb_framebuffer());
+        }
+    }
+}
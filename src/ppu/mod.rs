@@ -0,0 +1,578 @@
+use crate::mmu::{Interrupt, Model};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+mod color;
+mod compat;
+mod fifo;
+mod oam_bug;
+mod render;
+mod sprites;
+
+use color::PaletteRam;
+pub use color::{correct, rgb555, DMG_SHADES};
+pub use compat::{CompatibilityPalettes, ManualPalette};
+use fifo::Fifo;
+pub use oam_bug::OamCorruption;
+
+pub use sprites::Sprite;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// dots (clock cycles) per scanline
+pub const DOTS_PER_LINE: u16 = 456;
+/// scanlines per frame, including the 10 lines of VBlank
+pub const LINES_PER_FRAME: u8 = 154;
+/// dots spent in mode 2 at the start of each visible line
+const OAM_SCAN_DOTS: u16 = 80;
+/// clock cycles per frame
+pub const CYCLES_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+/// duration of mode 3 with the scanline renderer, the shortest it can be on hardware
+const DRAWING_DOTS: u16 = 172;
+
+/// the mode reported in the lower bits of STAT
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// the picture processing unit, it owns the video ram, the object attribute memory and the
+/// 0xFF40-0xFF4B registers (except 0xFF46, the OAM DMA), along with VBK and the palette ram of
+/// the CGB
+pub struct PPU {
+    /// the hardware the PPU belongs to, unlike `cgb` it stays the same in compatibility mode
+    model: Model,
+    /// draw in color, with the tile attributes and the palette ram of the CGB
+    cgb: bool,
+    /// a CGB running a DMG cartridge: draw like the DMG, with the shades of BGP, OBP0 and OBP1
+    /// taken from the first background palette and the first two object palettes
+    compatibility: bool,
+    /// two banks of 8 KiB, the second one only exists on CGB
+    vram: Vec<u8>,
+    /// VBK, the bank the cpu sees at 0x8000-0x9FFF
+    vram_bank: u8,
+    oam: Vec<u8>,
+    lcdc: u8,
+    /// only the interrupt selection bits 3-6 are stored, the rest is computed on read
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    /// BCPS/BCPD
+    background_palettes: PaletteRam,
+    /// OCPS/OCPD
+    object_palettes: PaletteRam,
+    mode: Mode,
+    /// dot within the current line
+    dot: u16,
+    /// the STAT interrupt is requested on the rising edge of the OR of all its enabled sources.
+    /// While one source keeps the line high, the others cannot trigger a new interrupt.
+    stat_line: bool,
+    interrupts: u8,
+    /// set once LY matched WY during the frame, the window can only show up after that
+    window_triggered: bool,
+    /// line of the window to draw next
+    window_line: u8,
+    /// objects selected by the OAM scan of the current line
+    sprites: Vec<Sprite>,
+    /// shade (0 is white, 3 is black) of every pixel of the screen, line by line. On CGB the
+    /// color index, before the palette.
+    framebuffer: Vec<u8>,
+    /// RGB555 of every pixel of the screen, line by line
+    rgb_framebuffer: Vec<u16>,
+    /// pass the colors through `color::correct`
+    color_correction: bool,
+    /// set when the PPU enters VBlank, the framebuffer then holds a complete frame
+    frame_ready: bool,
+    /// set when a visible line enters HBlank, which is when the HBlank DMA copies a block. It is
+    /// taken right after each tick, so it is never saved.
+    hblank_started: bool,
+    /// draw with the pixel FIFO instead of a whole line at the start of HBlank
+    pixel_fifo: bool,
+    fifo: Fifo,
+    /// emulate the OAM corruption caused by the cpu during mode 2
+    oam_bug: bool,
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
+}
+
+impl PPU {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            cgb: model == Model::Cgb,
+            compatibility: false,
+            vram: vec![0; 0x4000],
+            vram_bank: 0,
+            oam: vec![0; 0xA0],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            background_palettes: PaletteRam::default(),
+            object_palettes: PaletteRam::default(),
+            mode: Mode::HBlank,
+            dot: 0,
+            stat_line: false,
+            interrupts: 0,
+            window_triggered: false,
+            window_line: 0,
+            sprites: Vec::new(),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb_framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_correction: false,
+            frame_ready: false,
+            hblank_started: false,
+            pixel_fifo: false,
+            fifo: Fifo::default(),
+            oam_bug: false,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// the last frame drawn, as one shade index (0-3) per pixel, line by line
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// the last frame drawn, as one RGB555 color per pixel, line by line
+    pub fn rgb_framebuffer(&self) -> &[u16] {
+        &self.rgb_framebuffer
+    }
+
+    /// pass the colors of `rgb_framebuffer` through a curve that approximates the LCD of the
+    /// CGB, from the next pixel drawn on
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
+
+    pub fn color_correction(&self) -> bool {
+        self.color_correction
+    }
+
+    /// switch a CGB to the compatibility mode the boot rom sets up for DMG cartridges, or just
+    /// replace its palettes if it is already in it. Has no effect on a DMG.
+    pub fn set_compatibility_palettes(&mut self, palettes: &CompatibilityPalettes) {
+        if !self.cgb && !self.compatibility {
+            return;
+        }
+        self.cgb = false;
+        self.compatibility = true;
+        self.background_palettes
+            .set_palette(0, &palettes.background);
+        self.object_palettes.set_palette(0, &palettes.object0);
+        self.object_palettes.set_palette(1, &palettes.object1);
+    }
+
+    /// whether a CGB runs in the compatibility mode for DMG cartridges
+    pub fn compatibility(&self) -> bool {
+        self.compatibility
+    }
+
+    /// whether a visible line entered HBlank since the last call
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    /// whether a frame was completed since the last call
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    /// choose between the scanline renderer, which draws a whole line when mode 3 ends, and the
+    /// pixel FIFO, which draws one pixel per dot. The FIFO is slower but picks up register
+    /// writes in the middle of a line and gives mode 3 its variable length.
+    pub fn set_pixel_fifo(&mut self, enabled: bool) {
+        self.pixel_fifo = enabled;
+    }
+
+    pub fn pixel_fifo(&self) -> bool {
+        self.pixel_fifo
+    }
+
+    /// interrupts requested since the last call, as IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// advance the PPU by a number of dots
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        for _ in 0..cycles {
+            self.dot += 1;
+            if self.dot == DOTS_PER_LINE {
+                self.dot = 0;
+                self.ly = (self.ly + 1) % LINES_PER_FRAME;
+                self.start_line();
+            } else if self.ly < 144 && self.dot == OAM_SCAN_DOTS {
+                self.scan_oam();
+                self.start_fifo();
+                self.mode = Mode::Drawing;
+            } else if self.mode == Mode::Drawing && self.draw() {
+                self.mode = Mode::HBlank;
+                self.hblank_started = true;
+            }
+            self.update_stat_line();
+        }
+    }
+
+    /// advance mode 3 by one dot, returns whether the line is drawn
+    fn draw(&mut self) -> bool {
+        if self.pixel_fifo {
+            self.step_fifo()
+        } else if self.dot >= OAM_SCAN_DOTS + DRAWING_DOTS {
+            self.render_scanline();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn start_line(&mut self) {
+        if self.ly < 144 {
+            self.mode = Mode::OamScan;
+            self.window_triggered |= self.ly == self.wy;
+        } else if self.ly == 144 {
+            self.mode = Mode::VBlank;
+            self.interrupts |= Interrupt::VBLANK;
+            self.frame_ready = true;
+            self.window_triggered = false;
+            self.window_line = 0;
+        }
+    }
+
+    fn update_stat_line(&mut self) {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || match self.mode {
+                Mode::HBlank => self.stat & 0x08 != 0,
+                Mode::VBlank => self.stat & 0x10 != 0,
+                Mode::OamScan => self.stat & 0x20 != 0,
+                Mode::Drawing => false,
+            };
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::STAT;
+        }
+        self.stat_line = line;
+    }
+
+    /// whether the cpu can reach `addr` right now: OAM is locked during modes 2 and 3 and the
+    /// video ram during mode 3, reads then return 0xFF and writes are dropped
+    pub fn cpu_can_access(&self, addr: u16) -> bool {
+        match addr {
+            0x8000..=0x9FFF => self.mode != Mode::Drawing,
+            0xFE00..=0xFE9F => !matches!(self.mode, Mode::OamScan | Mode::Drawing),
+            // so is the palette ram
+            0xFF69 | 0xFF6B => self.mode != Mode::Drawing,
+            _ => true,
+        }
+    }
+
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9FFF => self.vram[self.vram_offset(addr)],
+            0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00],
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = (self.ly == self.lyc) as u8;
+                0x80 | self.stat | coincidence << 2 | self.mode as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF68 => self.background_palettes.rb_index(),
+            0xFF69 => self.background_palettes.rb_data(),
+            0xFF6A => self.object_palettes.rb_index(),
+            0xFF6B => self.object_palettes.rb_data(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => {
+                let offset = self.vram_offset(addr);
+                self.vram[offset] = value
+            }
+            0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00] = value,
+            0xFF40 => self.write_lcdc(value),
+            0xFF41 => self.stat = value & 0x78,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => {}
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F => self.vram_bank = value & 0x01,
+            0xFF68 => self.background_palettes.wb_index(value),
+            0xFF69 => self.background_palettes.wb_data(value),
+            0xFF6A => self.object_palettes.wb_index(value),
+            0xFF6B => self.object_palettes.wb_data(value),
+            _ => unreachable!(),
+        }
+        if self.lcd_enabled() && matches!(addr, 0xFF41 | 0xFF45) {
+            self.update_stat_line();
+        }
+    }
+
+    /// where the cpu lands in the video ram, through the bank selected by VBK
+    fn vram_offset(&self, addr: u16) -> usize {
+        self.vram_bank as usize * 0x2000 + addr as usize - 0x8000
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+        match (was_enabled, self.lcd_enabled()) {
+            (true, false) => {
+                // the screen goes blank, LY stays at 0 and STAT reports HBlank until it is back
+                self.ly = 0;
+                self.dot = 0;
+                self.mode = Mode::HBlank;
+                self.stat_line = false;
+                for pixel in self.framebuffer.iter_mut() {
+                    *pixel = 0;
+                }
+                for pixel in self.rgb_framebuffer.iter_mut() {
+                    *pixel = DMG_SHADES[0];
+                }
+            }
+            (false, true) => {
+                self.ly = 0;
+                self.dot = 0;
+                self.window_triggered = false;
+                self.window_line = 0;
+                self.start_line();
+                self.update_stat_line();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for PPU {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.cgb);
+        state.write_bool(self.compatibility);
+        state.write_bytes(&self.vram);
+        state.write_u8(self.vram_bank);
+        state.write_bytes(&self.oam);
+        for register in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ]
+        .iter()
+        {
+            state.write_u8(*register);
+        }
+        self.background_palettes.save(state);
+        self.object_palettes.save(state);
+        state.write_u8(self.mode as u8);
+        state.write_u16(self.dot);
+        state.write_bool(self.stat_line);
+        state.write_u8(self.interrupts);
+        state.write_bool(self.window_triggered);
+        state.write_u8(self.window_line);
+        self.save_sprites(state);
+        self.fifo.save(state);
+        state.write_bytes(&self.framebuffer);
+        for pixel in self.rgb_framebuffer.iter() {
+            state.write_u16(*pixel);
+        }
+        state.write_bool(self.frame_ready);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cgb = state.read_bool()?;
+        self.compatibility = state.read_bool()?;
+        state.read_bytes_into(&mut self.vram)?;
+        self.vram_bank = state.read_u8()? & 0x01;
+        state.read_bytes_into(&mut self.oam)?;
+        for register in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ]
+        .iter_mut()
+        {
+            **register = state.read_u8()?;
+        }
+        self.background_palettes.load(state)?;
+        self.object_palettes.load(state)?;
+        self.mode = match state.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::InvalidData("PPU mode out of range")),
+        };
+        self.dot = state.read_u16()?;
+        if self.dot >= DOTS_PER_LINE || self.ly >= LINES_PER_FRAME {
+            return Err(StateError::InvalidData("PPU position out of range"));
+        }
+        self.stat_line = state.read_bool()?;
+        self.interrupts = state.read_u8()?;
+        self.window_triggered = state.read_bool()?;
+        self.window_line = state.read_u8()?;
+        self.load_sprites(state)?;
+        self.fifo.load(state)?;
+        state.read_bytes_into(&mut self.framebuffer)?;
+        for pixel in self.rgb_framebuffer.iter_mut() {
+            *pixel = state.read_u16()? & 0x7FFF;
+        }
+        self.frame_ready = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_ppu() -> PPU {
+        let mut ppu = PPU::default();
+        ppu.wb(0xFF40, 0x80);
+        ppu
+    }
+
+    #[test]
+    fn mode_tests() {
+        let mut ppu = enabled_ppu();
+        assert_eq!(ppu.mode(), Mode::OamScan);
+
+        ppu.tick(79);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.tick(1);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.tick(172);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(ppu.rb(0xFF41) & 0x03, 0);
+
+        ppu.tick(204);
+        assert_eq!(ppu.rb(0xFF44), 1);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn vblank_tests() {
+        let mut ppu = enabled_ppu();
+
+        ppu.tick(456 * 144 - 1);
+        assert_eq!(ppu.take_interrupts(), 0);
+        ppu.tick(1);
+        assert_eq!(ppu.rb(0xFF44), 144);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(ppu.take_interrupts(), Interrupt::VBLANK);
+
+        ppu.tick(456 * 10);
+        assert_eq!(ppu.rb(0xFF44), 0);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn lyc_tests() {
+        let mut ppu = enabled_ppu();
+        ppu.wb(0xFF45, 2);
+        ppu.wb(0xFF41, 0x40);
+
+        ppu.tick(456);
+        assert_eq!(ppu.rb(0xFF41) & 0x04, 0);
+        assert_eq!(ppu.take_interrupts(), 0);
+
+        ppu.tick(456);
+        assert_eq!(ppu.rb(0xFF41), 0xC6);
+        assert_eq!(ppu.take_interrupts(), Interrupt::STAT);
+
+        // writing LYC to the current line raises the interrupt right away
+        ppu.tick(456);
+        ppu.wb(0xFF45, 3);
+        assert_eq!(ppu.take_interrupts(), Interrupt::STAT);
+    }
+
+    #[test]
+    fn stat_blocking_tests() {
+        let mut ppu = enabled_ppu();
+        ppu.wb(0xFF45, 1);
+        ppu.wb(0xFF41, 0x48);
+
+        // HBlank of line 0 raises the line
+        ppu.tick(252);
+        assert_eq!(ppu.take_interrupts(), Interrupt::STAT);
+
+        // LY=LYC on line 1 while HBlank still holds the line: no new interrupt
+        ppu.tick(204);
+        assert_eq!(ppu.take_interrupts(), 0);
+
+        // the line stays high through mode 2 and 3 thanks to LY=LYC, then HBlank is blocked too
+        ppu.tick(252);
+        assert_eq!(ppu.take_interrupts(), 0);
+
+        // line 2: both sources drop during mode 2, so HBlank triggers again
+        ppu.tick(456);
+        assert_eq!(ppu.take_interrupts(), Interrupt::STAT);
+    }
+
+    #[test]
+    fn lcd_off_tests() {
+        let mut ppu = enabled_ppu();
+        ppu.tick(456 * 5 + 100);
+
+        ppu.wb(0xFF40, 0x00);
+        assert_eq!(ppu.rb(0xFF44), 0);
+        assert_eq!(ppu.rb(0xFF41) & 0x03, 0);
+
+        ppu.tick(10000);
+        assert_eq!(ppu.rb(0xFF44), 0);
+
+        ppu.wb(0xFF40, 0x80);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.tick(456);
+        assert_eq!(ppu.rb(0xFF44), 1);
+    }
+}
@@ -0,0 +1,187 @@
+use super::{Mode, PPU};
+use crate::mmu::Model;
+
+/// number of 8 bytes rows in OAM, the OAM scan reads one of them every 4 dots
+const OAM_ROWS: usize = 20;
+
+/// the kind of cpu bus access to 0xFE00-0xFEFF that corrupts OAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OamCorruption {
+    /// a read from OAM
+    Read,
+    /// a write to OAM, or a 16 bit increment or decrement of a pointer into it
+    Write,
+    /// a read combined with an increment or decrement of the pointer, like `LD A, (HL+)` or `POP`
+    ReadIncrease,
+}
+
+impl PPU {
+    /// emulate the DMG OAM corruption bug, the cpu touching 0xFE00-0xFEFF during the OAM scan
+    /// garbles the row the PPU is reading
+    pub fn set_oam_bug(&mut self, enabled: bool) {
+        self.oam_bug = enabled;
+    }
+
+    pub fn oam_bug(&self) -> bool {
+        self.oam_bug
+    }
+
+    /// the cpu put an address of 0xFE00-0xFEFF on the bus. Only the DMG has the bug, a CGB does
+    /// not get it back by running a DMG cartridge in compatibility mode.
+    pub fn corrupt_oam(&mut self, corruption: OamCorruption) {
+        if self.model != Model::Dmg
+            || !self.oam_bug
+            || !self.lcd_enabled()
+            || self.mode != Mode::OamScan
+        {
+            return;
+        }
+        // the first row is never corrupted
+        let row = self.dot as usize / 4;
+        if row == 0 || row >= OAM_ROWS {
+            return;
+        }
+        match corruption {
+            OamCorruption::Read => self.corrupt_oam_read(row),
+            OamCorruption::Write => {
+                let a = self.oam_word(row, 0);
+                let b = self.oam_word(row - 1, 0);
+                let c = self.oam_word(row - 1, 2);
+                self.set_oam_word(row, 0, ((a ^ c) & (b ^ c)) ^ c);
+                self.copy_oam_row(row - 1, row, 1);
+            }
+            OamCorruption::ReadIncrease => {
+                // only the rows after the first four and before the last one take the extra hit
+                if (4..OAM_ROWS - 1).contains(&row) {
+                    let a = self.oam_word(row - 2, 0);
+                    let b = self.oam_word(row - 1, 0);
+                    let c = self.oam_word(row, 0);
+                    let d = self.oam_word(row - 1, 2);
+                    self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
+                    self.copy_oam_row(row - 1, row, 0);
+                    self.copy_oam_row(row - 1, row - 2, 0);
+                }
+                self.corrupt_oam_read(row);
+            }
+        }
+    }
+
+    fn corrupt_oam_read(&mut self, row: usize) {
+        let a = self.oam_word(row, 0);
+        let b = self.oam_word(row - 1, 0);
+        let c = self.oam_word(row - 1, 2);
+        self.set_oam_word(row, 0, b | (a & c));
+        self.copy_oam_row(row - 1, row, 1);
+    }
+
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let address = row * 8 + word * 2;
+        u16::from_le_bytes([self.oam[address], self.oam[address + 1]])
+    }
+
+    fn set_oam_word(&mut self, row: usize, word: usize, value: u16) {
+        let address = row * 8 + word * 2;
+        self.oam[address..address + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// copy the words of a row starting at `first_word` to another row
+    fn copy_oam_row(&mut self, from: usize, to: usize, first_word: usize) {
+        self.oam.copy_within(
+            from * 8 + first_word * 2..from * 8 + 8,
+            to * 8 + first_word * 2,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::ManualPalette;
+
+    /// a PPU in mode 2, reading the OAM row `row`, every word of OAM holding its own address
+    fn scanning_ppu(row: u32) -> PPU {
+        scanning_model_ppu(Model::Dmg, row)
+    }
+
+    fn scanning_model_ppu(model: Model, row: u32) -> PPU {
+        let mut ppu = PPU::new(model);
+        for address in (0..0xA0).step_by(2) {
+            ppu.wb(0xFE00 + address, address as u8);
+            ppu.wb(0xFE01 + address, 0xF0 | (address >> 4) as u8);
+        }
+        ppu.set_oam_bug(true);
+        ppu.wb(0xFF40, 0x80);
+        ppu.tick(row * 4);
+        ppu
+    }
+
+    fn row(ppu: &PPU, row: usize) -> [u16; 4] {
+        [0, 1, 2, 3].map(|word| ppu.oam_word(row, word))
+    }
+
+    #[test]
+    fn write_tests() {
+        let mut ppu = scanning_ppu(2);
+        ppu.set_oam_word(1, 0, 0x00FF);
+        ppu.corrupt_oam(OamCorruption::Write);
+
+        // ((0xF110 ^ 0xF00C) & (0x00FF ^ 0xF00C)) ^ 0xF00C
+        assert_eq!(row(&ppu, 2), [0xF01C, 0xF00A, 0xF00C, 0xF00E]);
+        assert_eq!(row(&ppu, 1), [0x00FF, 0xF00A, 0xF00C, 0xF00E]);
+        assert_eq!(row(&ppu, 3), [0xF118, 0xF11A, 0xF11C, 0xF11E]);
+    }
+
+    #[test]
+    fn read_tests() {
+        let mut ppu = scanning_ppu(2);
+        ppu.set_oam_word(1, 0, 0x00FF);
+        ppu.corrupt_oam(OamCorruption::Read);
+
+        // 0x00FF | (0xF110 & 0xF00C)
+        assert_eq!(row(&ppu, 2), [0xF0FF, 0xF00A, 0xF00C, 0xF00E]);
+    }
+
+    #[test]
+    fn read_increase_tests() {
+        let mut ppu = scanning_ppu(5);
+        ppu.corrupt_oam(OamCorruption::ReadIncrease);
+
+        // row 4 becomes (0xF220 & (0xF118 | 0xF228 | 0xF224)) | (0xF118 & 0xF228 & 0xF224) and
+        // is copied over rows 3 and 5, then row 5 goes through the read corruption
+        assert_eq!(row(&ppu, 3), [0xF220, 0xF222, 0xF224, 0xF226]);
+        assert_eq!(row(&ppu, 4), [0xF220, 0xF222, 0xF224, 0xF226]);
+        assert_eq!(row(&ppu, 5), [0xF220, 0xF222, 0xF224, 0xF226]);
+    }
+
+    #[test]
+    fn no_corruption_tests() {
+        // the first row, outside mode 2 or with the bug disabled, nothing happens
+        let mut ppu = scanning_ppu(0);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 0), [0xF000, 0xF002, 0xF004, 0xF006]);
+
+        let mut ppu = scanning_ppu(2);
+        ppu.tick(80);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 2), [0xF110, 0xF112, 0xF114, 0xF116]);
+
+        let mut ppu = scanning_ppu(2);
+        ppu.set_oam_bug(false);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 2), [0xF110, 0xF112, 0xF114, 0xF116]);
+    }
+
+    #[test]
+    fn cgb_tests() {
+        let mut ppu = scanning_model_ppu(Model::Cgb, 2);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 2), [0xF110, 0xF112, 0xF114, 0xF116]);
+
+        // still a CGB when it runs a DMG cartridge
+        let mut ppu = scanning_model_ppu(Model::Cgb, 2);
+        ppu.set_compatibility_palettes(&ManualPalette::Up.palettes());
+        assert!(ppu.compatibility());
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 2), [0xF110, 0xF112, 0xF114, 0xF116]);
+    }
+}
@@ -0,0 +1,425 @@
+use super::color::{self, DMG_SHADES};
+use super::{PPU, SCREEN_WIDTH};
+
+impl PPU {
+    /// draw the current line into the framebuffer, called when the line enters HBlank
+    pub(super) fn render_scanline(&mut self) {
+        // pixels of the background and window, see `map_pixel`
+        let mut colors = [0; SCREEN_WIDTH];
+
+        // on DMG, clearing LCDC bit 0 blanks both the background and the window
+        if self.lcdc & 0x01 != 0 || self.cgb {
+            self.render_background(&mut colors);
+            self.render_window(&mut colors);
+        }
+
+        for (x, pixel) in colors.iter().enumerate() {
+            self.draw_pixel(x as u8, *pixel);
+        }
+    }
+
+    /// write a pixel of the current line to the framebuffers, from the background or window
+    /// pixel at `x` and the objects over it
+    pub(super) fn draw_pixel(&mut self, x: u8, background: u8) {
+        let background_color = background & 0x03;
+        let sprite = self.visible_sprite(x, background);
+        let (shade, rgb) = if self.cgb {
+            match sprite {
+                Some((color, attributes)) => {
+                    (color, self.object_palettes.color(attributes & 0x07, color))
+                }
+                None => (
+                    background_color,
+                    self.background_palettes
+                        .color(background >> 2 & 0x07, background_color),
+                ),
+            }
+        } else {
+            let (shade, palette) = match sprite {
+                Some((color, attributes)) if attributes & 0x10 != 0 => {
+                    (Self::shade(self.obp1, color), Some(1))
+                }
+                Some((color, _)) => (Self::shade(self.obp0, color), Some(0)),
+                None => (Self::shade(self.bgp, background_color), None),
+            };
+            let rgb = match (self.compatibility, palette) {
+                (false, _) => DMG_SHADES[shade as usize],
+                (true, Some(palette)) => self.object_palettes.color(palette, shade),
+                (true, None) => self.background_palettes.color(0, shade),
+            };
+            (shade, rgb)
+        };
+        let index = self.ly as usize * SCREEN_WIDTH + x as usize;
+        self.framebuffer[index] = shade;
+        self.rgb_framebuffer[index] = match self.color_correction {
+            true => color::correct(rgb),
+            false => rgb,
+        };
+    }
+
+    fn render_background(&self, colors: &mut [u8; SCREEN_WIDTH]) {
+        let map = self.background_map();
+        let y = self.ly.wrapping_add(self.scy);
+        for (x, color) in colors.iter_mut().enumerate() {
+            *color = self.map_pixel(map, (x as u8).wrapping_add(self.scx), y);
+        }
+    }
+
+    fn render_window(&mut self, colors: &mut [u8; SCREEN_WIDTH]) {
+        if self.lcdc & 0x20 == 0 || !self.window_triggered || self.wx > 166 {
+            return;
+        }
+        let map = self.window_map();
+        // the window starts at WX - 7, its pixels left of the screen are skipped
+        let start = self.wx.saturating_sub(7) as usize;
+        let skipped = 7u8.saturating_sub(self.wx);
+        for (x, color) in colors.iter_mut().enumerate().skip(start) {
+            let window_x = (x - start) as u8 + skipped;
+            *color = self.map_pixel(map, window_x, self.window_line);
+        }
+        // the window has its own line counter, which only moves on lines where it is drawn
+        self.window_line += 1;
+    }
+
+    /// offset in video ram of the background tile map
+    pub(super) fn background_map(&self) -> usize {
+        if self.lcdc & 0x08 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    /// offset in video ram of the window tile map
+    pub(super) fn window_map(&self) -> usize {
+        if self.lcdc & 0x40 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    /// pixel of a 256x256 tile map: its color index in bits 0-1 and, on CGB, the palette of
+    /// its tile in bits 2-4 and the priority of its tile over the objects in bit 7
+    pub(super) fn map_pixel(&self, map: usize, x: u8, y: u8) -> u8 {
+        let offset = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[offset];
+        // on CGB the attributes of the tile sit at the same place in the second bank
+        let attributes = if self.cgb {
+            self.vram[0x2000 + offset]
+        } else {
+            0
+        };
+        let mut address = self.tile_address(tile);
+        if attributes & 0x08 != 0 {
+            address += 0x2000;
+        }
+        let x = if attributes & 0x20 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let y = if attributes & 0x40 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        self.tile_pixel(address, x, y) | (attributes & 0x07) << 2 | attributes & 0x80
+    }
+
+    /// address in video ram of a background or window tile
+    fn tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            // tiles 0-127 are at 0x9000, tiles 128-255 at 0x8800
+            (0x1000 + tile as i8 as i32 * 16) as usize
+        }
+    }
+
+    /// color index of a pixel of the tile starting at `address`
+    pub(super) fn tile_pixel(&self, address: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[address + y as usize * 2];
+        let high = self.vram[address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 0x01) << 1 | ((low >> bit) & 0x01)
+    }
+
+    /// map a color index through a DMG palette register
+    pub(super) fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0x03
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{rgb555, ManualPalette, SCREEN_HEIGHT};
+    use super::*;
+    use crate::mmu::Model;
+
+    /// tile whose rows are all `low`, `high`
+    fn write_tile(ppu: &mut PPU, address: u16, low: u8, high: u8) {
+        for row in 0..8 {
+            ppu.wb(address + row * 2, low);
+            ppu.wb(address + row * 2 + 1, high);
+        }
+    }
+
+    fn render_frame(ppu: &mut PPU) {
+        ppu.wb(0xFF40, ppu.rb(0xFF40) | 0x80);
+        ppu.tick(456 * SCREEN_HEIGHT as u32);
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn background_tests() {
+        let mut ppu = PPU::default();
+        // tile 1: color 1 on the left half, color 2 on the right half
+        write_tile(&mut ppu, 0x8010, 0xF0, 0x0F);
+        ppu.wb(0x9800, 0x01);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF40, 0x11);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 4, 7), 2);
+        assert_eq!(pixel(&ppu, 8, 0), 0);
+        assert_eq!(pixel(&ppu, 0, 8), 0);
+    }
+
+    #[test]
+    fn palette_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x8000, 0xFF, 0xFF);
+        ppu.wb(0xFF47, 0b01_00_00_00);
+        ppu.wb(0xFF40, 0x11);
+
+        render_frame(&mut ppu);
+
+        assert!(ppu.framebuffer().iter().all(|&shade| shade == 1));
+    }
+
+    #[test]
+    fn signed_addressing_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x9010, 0xFF, 0x00);
+        write_tile(&mut ppu, 0x8800, 0x00, 0xFF);
+        ppu.wb(0x9C00, 0x01);
+        ppu.wb(0x9C01, 0x80);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF40, 0x09);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(pixel(&ppu, 8, 0), 2);
+    }
+
+    #[test]
+    fn scroll_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x8010, 0xFF, 0xFF);
+        ppu.wb(0x9800, 0x01);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF42, 252);
+        ppu.wb(0xFF43, 254);
+        ppu.wb(0xFF40, 0x11);
+
+        render_frame(&mut ppu);
+
+        // the map wraps around, tile (0, 0) shows up at (2, 4)
+        assert_eq!(pixel(&ppu, 1, 4), 0);
+        assert_eq!(pixel(&ppu, 2, 3), 0);
+        assert_eq!(pixel(&ppu, 2, 4), 3);
+        assert_eq!(pixel(&ppu, 9, 11), 3);
+        assert_eq!(pixel(&ppu, 10, 11), 0);
+    }
+
+    #[test]
+    fn window_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x8010, 0xFF, 0xFF);
+        for tile in 0..32 * 32 {
+            ppu.wb(0x9C00 + tile, 0x01);
+        }
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF4A, 100);
+        ppu.wb(0xFF4B, 87);
+        ppu.wb(0xFF40, 0x71);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 80, 99), 0);
+        assert_eq!(pixel(&ppu, 79, 100), 0);
+        assert_eq!(pixel(&ppu, 80, 100), 3);
+        assert_eq!(pixel(&ppu, 159, 143), 3);
+    }
+
+    #[test]
+    fn window_line_counter_tests() {
+        let mut ppu = PPU::default();
+        // the first row of the window map uses tile 1, the second row tile 2
+        write_tile(&mut ppu, 0x8010, 0xFF, 0x00);
+        write_tile(&mut ppu, 0x8020, 0x00, 0xFF);
+        ppu.wb(0x9C00, 0x01);
+        ppu.wb(0x9C20, 0x02);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF4B, 7);
+        ppu.wb(0xFF40, 0xF1);
+
+        // hide the window for lines 4 to 11, it resumes where it stopped
+        ppu.tick(456 * 4);
+        ppu.wb(0xFF40, 0xD1);
+        ppu.tick(456 * 8);
+        ppu.wb(0xFF40, 0xF1);
+        ppu.tick(456 * (SCREEN_HEIGHT as u32 - 12));
+
+        assert_eq!(pixel(&ppu, 0, 3), 1);
+        assert_eq!(pixel(&ppu, 0, 12), 1);
+        assert_eq!(pixel(&ppu, 0, 15), 1);
+        assert_eq!(pixel(&ppu, 0, 16), 2);
+    }
+
+    #[test]
+    fn bg_disabled_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x8000, 0xFF, 0xFF);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF40, 0x10);
+
+        render_frame(&mut ppu);
+
+        assert!(ppu.framebuffer().iter().all(|&shade| shade == 0));
+    }
+
+    /// fill a CGB palette through its data register, starting at palette 0
+    fn write_palettes(ppu: &mut PPU, index_register: u16, colors: &[u16]) {
+        ppu.wb(index_register, 0x80);
+        for color in colors {
+            ppu.wb(index_register + 1, color.to_le_bytes()[0]);
+            ppu.wb(index_register + 1, color.to_le_bytes()[1]);
+        }
+    }
+
+    fn rgb_pixel(ppu: &PPU, x: usize, y: usize) -> u16 {
+        ppu.rgb_framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn dmg_rgb_tests() {
+        let mut ppu = PPU::default();
+        write_tile(&mut ppu, 0x8010, 0xF0, 0x0F);
+        ppu.wb(0x9800, 0x01);
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF40, 0x11);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(rgb_pixel(&ppu, 0, 0), DMG_SHADES[1]);
+        assert_eq!(rgb_pixel(&ppu, 4, 0), DMG_SHADES[2]);
+        assert_eq!(rgb_pixel(&ppu, 8, 0), DMG_SHADES[0]);
+    }
+
+    #[test]
+    fn cgb_attributes_tests() {
+        let mut ppu = PPU::new(Model::Cgb);
+        // palette 0 is grays, palette 2 is red to blue
+        let red = rgb555(31, 0, 0);
+        let blue = rgb555(0, 0, 31);
+        let mut colors = DMG_SHADES.to_vec();
+        colors.extend_from_slice(&[0; 4]);
+        colors.extend_from_slice(&[red, red, blue, blue]);
+        write_palettes(&mut ppu, 0xFF68, &colors);
+
+        // tile 1 of bank 0 has color 1 on its left half, tile 1 of bank 1 has color 2 on its
+        // first row only
+        write_tile(&mut ppu, 0x8010, 0xF0, 0x00);
+        ppu.wb(0xFF4F, 0x01);
+        ppu.wb(0x8010, 0x00);
+        ppu.wb(0x8011, 0xFF);
+        ppu.wb(0x9801, 0x02);
+        ppu.wb(0x9802, 0x08 | 0x20);
+        ppu.wb(0x9803, 0x08 | 0x40);
+        ppu.wb(0xFF4F, 0x00);
+        for tile in 0..4 {
+            ppu.wb(0x9800 + tile, 0x01);
+        }
+        ppu.wb(0xFF40, 0x11);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(rgb_pixel(&ppu, 0, 0), DMG_SHADES[1]);
+        assert_eq!(rgb_pixel(&ppu, 4, 0), DMG_SHADES[0]);
+        // palette 2
+        assert_eq!(rgb_pixel(&ppu, 8, 0), red);
+        assert_eq!(rgb_pixel(&ppu, 12, 0), red);
+        // bank 1, flipped horizontally then vertically
+        assert_eq!(rgb_pixel(&ppu, 16, 0), DMG_SHADES[2]);
+        assert_eq!(rgb_pixel(&ppu, 16, 1), DMG_SHADES[0]);
+        assert_eq!(rgb_pixel(&ppu, 24, 0), DMG_SHADES[0]);
+        assert_eq!(rgb_pixel(&ppu, 24, 7), DMG_SHADES[2]);
+        assert_eq!(pixel(&ppu, 24, 7), 2);
+    }
+
+    #[test]
+    fn cgb_bg_disabled_tests() {
+        // on CGB, LCDC bit 0 does not blank the background
+        let mut ppu = PPU::new(Model::Cgb);
+        write_palettes(&mut ppu, 0xFF68, &DMG_SHADES);
+        write_tile(&mut ppu, 0x8000, 0xFF, 0xFF);
+        ppu.wb(0xFF40, 0x10);
+
+        render_frame(&mut ppu);
+
+        assert!(ppu.rgb_framebuffer().iter().all(|&color| color == 0));
+    }
+
+    #[test]
+    fn color_correction_tests() {
+        let mut ppu = PPU::new(Model::Cgb);
+        write_palettes(&mut ppu, 0xFF68, &[rgb555(31, 0, 0)]);
+        ppu.wb(0xFF40, 0x11);
+        ppu.set_color_correction(true);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(rgb_pixel(&ppu, 0, 0), color::correct(rgb555(31, 0, 0)));
+    }
+
+    #[test]
+    fn compatibility_tests() {
+        let mut ppu = PPU::new(Model::Cgb);
+        let palettes = ManualPalette::Left.palettes();
+        ppu.set_compatibility_palettes(&palettes);
+        // tile 0: color 3, tile 1: color 1, object in OBP1 with color 3 over the first one
+        write_tile(&mut ppu, 0x8000, 0xFF, 0xFF);
+        write_tile(&mut ppu, 0x8010, 0xFF, 0x00);
+        ppu.wb(0x9801, 0x01);
+        for (offset, value) in [16, 8, 0, 0x10].iter().enumerate() {
+            ppu.wb(0xFE00 + offset as u16, *value);
+        }
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF49, 0b01_10_11_00);
+        // the background is blanked by LCDC bit 0 like on the DMG
+        ppu.wb(0xFF40, 0x02);
+        render_frame(&mut ppu);
+        assert_eq!(rgb_pixel(&ppu, 0, 0), palettes.object1[1]);
+        assert_eq!(rgb_pixel(&ppu, 8, 0), palettes.background[0]);
+
+        ppu.wb(0xFF40, 0x13);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 1);
+        assert_eq!(rgb_pixel(&ppu, 0, 0), palettes.object1[1]);
+        assert_eq!(rgb_pixel(&ppu, 8, 0), palettes.background[1]);
+        assert_eq!(rgb_pixel(&ppu, 0, 8), palettes.background[3]);
+
+        // a DMG keeps its grays
+        let mut dmg = PPU::new(Model::Dmg);
+        dmg.set_compatibility_palettes(&palettes);
+        assert!(!dmg.compatibility());
+    }
+}
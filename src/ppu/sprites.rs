@@ -0,0 +1,399 @@
+use super::PPU;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// maximum number of objects the OAM scan selects on a line
+const SPRITES_PER_LINE: usize = 10;
+
+/// an entry of the object attribute memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sprite {
+    /// vertical position plus 16
+    pub y: u8,
+    /// horizontal position plus 8
+    pub x: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    /// position in OAM, used to break ties between sprites at the same X
+    pub index: u8,
+}
+
+impl Sprite {
+    fn behind_background(&self) -> bool {
+        self.attributes & 0x80 != 0
+    }
+
+    fn flip_y(&self) -> bool {
+        self.attributes & 0x40 != 0
+    }
+
+    fn flip_x(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+
+    /// on CGB the tile data of the object comes from the second bank of video ram
+    fn uses_bank1(&self) -> bool {
+        self.attributes & 0x08 != 0
+    }
+}
+
+impl PPU {
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// mode 2: select the first ten objects of OAM overlapping the current line. Their X position
+    /// does not matter, objects off screen still count towards the limit.
+    pub(super) fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        let line = self.ly.wrapping_add(16);
+        self.sprites = self
+            .oam
+            .chunks(4)
+            .enumerate()
+            .map(|(index, entry)| Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attributes: entry[3],
+                index: index as u8,
+            })
+            .filter(|sprite| line >= sprite.y && line < sprite.y.wrapping_add(height))
+            .take(SPRITES_PER_LINE)
+            .collect();
+        // on DMG the object with the smallest X is drawn on top, then the one first in OAM. On
+        // CGB only the position in OAM matters.
+        if !self.cgb {
+            self.sprites.sort_by_key(|sprite| (sprite.x, sprite.index));
+        }
+    }
+
+    /// color index and attributes of the object drawn at `x` over a background pixel, if there
+    /// is one. See `map_pixel` for the background pixel.
+    pub(super) fn visible_sprite(&self, x: u8, background: u8) -> Option<(u8, u8)> {
+        if self.lcdc & 0x02 == 0 {
+            return None;
+        }
+        let background_color = background & 0x03;
+        // on CGB, clearing LCDC bit 0 puts the objects over the background whatever the
+        // priority bits say
+        let background_priority = !self.cgb || self.lcdc & 0x01 != 0;
+        let screen_x = x + 8;
+        for sprite in self.sprites.iter() {
+            if screen_x < sprite.x || screen_x >= sprite.x.saturating_add(8) {
+                continue;
+            }
+            let color = self.sprite_pixel(sprite, screen_x - sprite.x);
+            if color == 0 {
+                continue;
+            }
+            // a hidden object still wins over the objects below it
+            let hidden = sprite.behind_background() || background & 0x80 != 0;
+            if hidden && background_color != 0 && background_priority {
+                return None;
+            }
+            return Some((color, sprite.attributes));
+        }
+        None
+    }
+
+    /// color index of a pixel of a sprite, `x` being relative to its left edge
+    fn sprite_pixel(&self, sprite: &Sprite, x: u8) -> u8 {
+        let height = self.sprite_height();
+        // the OAM scan may have selected the object with the other height, LCDC bit 2 changed
+        // since then: only the rows of the current height are reachable
+        let mut y = self.ly.wrapping_add(16).wrapping_sub(sprite.y) & (height - 1);
+        if sprite.flip_y() {
+            y = height - 1 - y;
+        }
+        let x = if sprite.flip_x() { 7 - x } else { x };
+        // in 8x16 mode the low bit of the tile index is ignored
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let bank = if self.cgb && sprite.uses_bank1() {
+            0x2000
+        } else {
+            0
+        };
+        self.tile_pixel(bank + tile as usize * 16 + (y as usize / 8) * 16, x, y % 8)
+    }
+
+    pub(super) fn save_sprites(&self, state: &mut StateWriter) {
+        state.write_u8(self.sprites.len() as u8);
+        for sprite in self.sprites.iter() {
+            state.write_u8(sprite.y);
+            state.write_u8(sprite.x);
+            state.write_u8(sprite.tile);
+            state.write_u8(sprite.attributes);
+            state.write_u8(sprite.index);
+        }
+    }
+
+    pub(super) fn load_sprites(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let count = state.read_u8()? as usize;
+        if count > SPRITES_PER_LINE {
+            return Err(StateError::InvalidData("too many sprites on a line"));
+        }
+        self.sprites.clear();
+        for _ in 0..count {
+            self.sprites.push(Sprite {
+                y: state.read_u8()?,
+                x: state.read_u8()?,
+                tile: state.read_u8()?,
+                attributes: state.read_u8()?,
+                index: state.read_u8()?,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{rgb555, SCREEN_WIDTH};
+    use super::*;
+    use crate::mmu::Model;
+
+    /// a tile with a 4 pixels wide bar of `color` on its first row
+    fn write_bar_tile(ppu: &mut PPU, tile: u16, color: u8) {
+        let address = 0x8000 + tile * 16;
+        ppu.wb(address, if color & 0x01 != 0 { 0xF0 } else { 0x00 });
+        ppu.wb(address + 1, if color & 0x02 != 0 { 0xF0 } else { 0x00 });
+    }
+
+    /// a tile filled with `color`
+    fn write_solid_tile(ppu: &mut PPU, tile: u16, color: u8) {
+        let address = 0x8000 + tile * 16;
+        for row in 0..8 {
+            ppu.wb(
+                address + row * 2,
+                if color & 0x01 != 0 { 0xFF } else { 0x00 },
+            );
+            ppu.wb(
+                address + row * 2 + 1,
+                if color & 0x02 != 0 { 0xFF } else { 0x00 },
+            );
+        }
+    }
+
+    fn write_sprite(ppu: &mut PPU, index: u16, y: u8, x: u8, tile: u8, attributes: u8) {
+        let address = 0xFE00 + index * 4;
+        ppu.wb(address, y);
+        ppu.wb(address + 1, x);
+        ppu.wb(address + 2, tile);
+        ppu.wb(address + 3, attributes);
+    }
+
+    fn sprite_ppu(lcdc: u8) -> PPU {
+        let mut ppu = PPU::default();
+        ppu.wb(0xFF47, 0b11_10_01_00);
+        ppu.wb(0xFF48, 0b11_10_01_00);
+        ppu.wb(0xFF49, 0b00_01_10_11);
+        ppu.wb(0xFF40, lcdc);
+        ppu
+    }
+
+    /// render a frame and return the first `width` pixels of a line as digits
+    fn render_line(ppu: &mut PPU, y: usize, width: usize) -> String {
+        ppu.wb(0xFF40, ppu.rb(0xFF40) | 0x80);
+        ppu.tick(456 * 144);
+        ppu.framebuffer()[y * SCREEN_WIDTH..y * SCREEN_WIDTH + width]
+            .iter()
+            .map(|shade| (b'0' + shade) as char)
+            .collect()
+    }
+
+    #[test]
+    fn sprite_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_bar_tile(&mut ppu, 1, 3);
+        write_sprite(&mut ppu, 0, 18, 10, 1, 0x00);
+
+        assert_eq!(render_line(&mut ppu, 1, 12), "000000000000");
+        assert_eq!(render_line(&mut ppu, 2, 12), "003333000000");
+        assert_eq!(render_line(&mut ppu, 3, 12), "000000000000");
+    }
+
+    #[test]
+    fn sprites_disabled_tests() {
+        let mut ppu = sprite_ppu(0x11);
+        write_bar_tile(&mut ppu, 1, 3);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0x00);
+
+        assert_eq!(render_line(&mut ppu, 0, 8), "00000000");
+    }
+
+    #[test]
+    fn flip_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_bar_tile(&mut ppu, 1, 2);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0x20);
+        write_sprite(&mut ppu, 1, 16, 16, 1, 0x40);
+        write_sprite(&mut ppu, 2, 16, 24, 1, 0x60);
+
+        assert_eq!(
+            render_line(&mut ppu, 0, 32),
+            "00002222000000000000000000000000"
+        );
+        assert_eq!(
+            render_line(&mut ppu, 7, 32),
+            "00000000222200000000222200000000"
+        );
+    }
+
+    #[test]
+    fn height_change_tests() {
+        // the OAM scan selects an 8x16 object on line 10, then LCDC switches to 8x8 objects
+        // before the line is drawn
+        for &pixel_fifo in [false, true].iter() {
+            let mut ppu = sprite_ppu(0x97);
+            ppu.set_pixel_fifo(pixel_fifo);
+            write_solid_tile(&mut ppu, 3, 2);
+            write_sprite(&mut ppu, 0, 16, 8, 3, 0x40);
+
+            ppu.tick(456 * 10 + 81);
+            ppu.wb(0xFF40, 0x93);
+            ppu.tick(456 - 81);
+
+            let line = &ppu.framebuffer()[10 * SCREEN_WIDTH..10 * SCREEN_WIDTH + 9];
+            assert_eq!(line, &[2, 2, 2, 2, 2, 2, 2, 2, 0]);
+        }
+    }
+
+    #[test]
+    fn tall_sprite_tests() {
+        let mut ppu = sprite_ppu(0x17);
+        write_bar_tile(&mut ppu, 2, 1);
+        write_bar_tile(&mut ppu, 3, 2);
+        write_sprite(&mut ppu, 0, 16, 8, 3, 0x00);
+        write_sprite(&mut ppu, 1, 16, 16, 3, 0x40);
+
+        assert_eq!(render_line(&mut ppu, 0, 16), "1111000000000000");
+        assert_eq!(render_line(&mut ppu, 8, 16), "2222000000000000");
+        assert_eq!(render_line(&mut ppu, 7, 16), "0000000022220000");
+        assert_eq!(render_line(&mut ppu, 15, 16), "0000000011110000");
+    }
+
+    #[test]
+    fn line_limit_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_solid_tile(&mut ppu, 1, 1);
+        // an off screen object still takes one of the ten slots
+        write_sprite(&mut ppu, 0, 16, 0, 1, 0x00);
+        for index in 1..11 {
+            write_sprite(&mut ppu, index, 16, index as u8 * 8, 1, 0x00);
+        }
+
+        let line = render_line(&mut ppu, 0, 80);
+        assert_eq!(&line[..72], "1".repeat(72));
+        assert_eq!(&line[72..], "00000000");
+    }
+
+    #[test]
+    fn x_priority_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_solid_tile(&mut ppu, 1, 1);
+        write_solid_tile(&mut ppu, 2, 2);
+        write_solid_tile(&mut ppu, 3, 3);
+        // the later object with the smaller X is on top
+        write_sprite(&mut ppu, 0, 16, 12, 2, 0x00);
+        write_sprite(&mut ppu, 1, 16, 8, 1, 0x00);
+        // same X: the first object in OAM is on top
+        write_sprite(&mut ppu, 2, 16, 40, 2, 0x00);
+        write_sprite(&mut ppu, 3, 16, 40, 3, 0x00);
+
+        assert_eq!(
+            render_line(&mut ppu, 0, 40),
+            "1111111122220000000000000000000022222222"
+        );
+    }
+
+    #[test]
+    fn transparency_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_bar_tile(&mut ppu, 1, 3);
+        write_solid_tile(&mut ppu, 2, 1);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0x00);
+        write_sprite(&mut ppu, 1, 16, 10, 2, 0x00);
+
+        assert_eq!(render_line(&mut ppu, 0, 12), "333311111100");
+        assert_eq!(render_line(&mut ppu, 1, 12), "001111111100");
+    }
+
+    #[test]
+    fn background_priority_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        // the background shows color 2 on its left half, color 0 on the right half
+        write_bar_tile(&mut ppu, 1, 2);
+        write_solid_tile(&mut ppu, 2, 3);
+        ppu.wb(0x9800, 0x01);
+        write_sprite(&mut ppu, 0, 16, 8, 2, 0x80);
+        // a lower priority object does not show through a hidden one
+        write_sprite(&mut ppu, 1, 16, 9, 2, 0x00);
+
+        assert_eq!(render_line(&mut ppu, 0, 10), "2222333330");
+    }
+
+    #[test]
+    fn palette_tests() {
+        let mut ppu = sprite_ppu(0x13);
+        write_solid_tile(&mut ppu, 1, 1);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0x00);
+        write_sprite(&mut ppu, 1, 16, 16, 1, 0x10);
+
+        assert_eq!(render_line(&mut ppu, 0, 16), "1111111122222222");
+    }
+
+    #[test]
+    fn cgb_priority_tests() {
+        let mut ppu = PPU::new(Model::Cgb);
+        // object palette 1 is green, the background palette gray
+        let green = rgb555(0, 31, 0);
+        ppu.wb(0xFF6A, 0x80 | 0x08);
+        for _ in 0..4 {
+            ppu.wb(0xFF6B, green.to_le_bytes()[0]);
+            ppu.wb(0xFF6B, green.to_le_bytes()[1]);
+        }
+        ppu.wb(0xFF68, 0x80);
+        for _ in 0..4 {
+            ppu.wb(0xFF69, 0x10);
+            ppu.wb(0xFF69, 0x42);
+        }
+        write_solid_tile(&mut ppu, 1, 1);
+        write_solid_tile(&mut ppu, 2, 2);
+        // tile 3 comes from the second bank
+        ppu.wb(0xFF4F, 0x01);
+        write_solid_tile(&mut ppu, 3, 3);
+        ppu.wb(0xFF4F, 0x00);
+
+        // only the position in OAM matters
+        write_sprite(&mut ppu, 0, 16, 12, 2, 0x00);
+        write_sprite(&mut ppu, 1, 16, 8, 1, 0x00);
+        write_sprite(&mut ppu, 2, 16, 24, 3, 0x08 | 0x01);
+        // the tile at 32 has its priority bit set, only color 0 lets objects through
+        ppu.wb(0x9804, 0x01);
+        ppu.wb(0xFF4F, 0x01);
+        ppu.wb(0x9804, 0x80);
+        ppu.wb(0xFF4F, 0x00);
+        write_sprite(&mut ppu, 3, 16, 40, 2, 0x00);
+        ppu.wb(0xFF40, 0x13);
+
+        assert_eq!(
+            render_line(&mut ppu, 0, 40),
+            "1111222222220000333333330000000011111111"
+        );
+        let line = &ppu.rgb_framebuffer()[..40];
+        assert_eq!(line[16], green);
+        assert_eq!(line[12], 0x4210);
+
+        // clearing LCDC bit 0 puts every object on top
+        ppu.wb(0xFF40, 0x12);
+        assert_eq!(render_line(&mut ppu, 0, 40)[32..], *"22222222");
+    }
+}
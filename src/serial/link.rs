@@ -0,0 +1,184 @@
+use super::SerialDevice;
+use crate::cpu::CPU;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// what travels on the wires of the cable, by end
+#[derive(Default)]
+struct Wire {
+    /// byte each end offers while it waits for the other end to drive the clock
+    waiting: [Option<u8>; 2],
+    /// byte clocked into each end by the other end, not yet picked up
+    incoming: [Option<u8>; 2],
+}
+
+/// one end of a link cable, to plug into `Serial::connect`
+pub struct LinkPort {
+    wire: Rc<RefCell<Wire>>,
+    end: usize,
+}
+
+/// a link cable, the two ends can be plugged into two systems of the same process
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let wire = Rc::new(RefCell::new(Wire::default()));
+    (
+        LinkPort {
+            wire: wire.clone(),
+            end: 0,
+        },
+        LinkPort { wire, end: 1 },
+    )
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, data: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.end;
+        // the other side only shifts when it waits on the external clock, otherwise the line
+        // stays high
+        match wire.waiting[other].take() {
+            Some(answer) => {
+                wire.incoming[other] = Some(data);
+                answer
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn external_clock(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        wire.waiting[self.end] = waiting;
+        let incoming = wire.incoming[self.end].take();
+        if incoming.is_some() {
+            wire.waiting[self.end] = None;
+        }
+        incoming
+    }
+}
+
+/// two systems joined by a link cable. They run in lockstep: the one behind always executes
+/// next, so they never drift apart by more than an instruction.
+pub struct LinkedSystems {
+    first: CPU,
+    second: CPU,
+    /// cycles the second system had run more than the first one when they were linked
+    offset: i64,
+}
+
+impl LinkedSystems {
+    pub fn new(mut first: CPU, mut second: CPU) -> Self {
+        let (first_port, second_port) = link_cable();
+        first.mmu_mut().serial_mut().connect(Box::new(first_port));
+        second.mmu_mut().serial_mut().connect(Box::new(second_port));
+        let offset = second.cycles() as i64 - first.cycles() as i64;
+        Self {
+            first,
+            second,
+            offset,
+        }
+    }
+
+    pub fn first(&self) -> &CPU {
+        &self.first
+    }
+
+    pub fn first_mut(&mut self) -> &mut CPU {
+        &mut self.first
+    }
+
+    pub fn second(&self) -> &CPU {
+        &self.second
+    }
+
+    pub fn second_mut(&mut self) -> &mut CPU {
+        &mut self.second
+    }
+
+    /// unplug the cable and give the systems back
+    pub fn unlink(mut self) -> (CPU, CPU) {
+        self.first.mmu_mut().serial_mut().disconnect();
+        self.second.mmu_mut().serial_mut().disconnect();
+        (self.first, self.second)
+    }
+
+    /// execute an instruction on the system that is behind
+    pub fn step(&mut self) {
+        if self.first.cycles() as i64 + self.offset <= self.second.cycles() as i64 {
+            self.first.step();
+        } else {
+            self.second.step();
+        }
+    }
+
+    /// run both systems for a number of clock cycles
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.first.cycles() + cycles;
+        while self.first.cycles() < end || (self.second.cycles() as i64) < end as i64 + self.offset
+        {
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Interrupt;
+
+    /// a system that runs NOPs up to `start`, writes `data` to SB and `control` to SC, then
+    /// runs NOPs again
+    fn transfer_program(start: u16, data: u8, control: u8) -> CPU {
+        let mut cpu = CPU::default();
+        for (offset, byte) in [0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02]
+            .iter()
+            .enumerate()
+        {
+            cpu.mmu_mut().wb(start + offset as u16, *byte);
+        }
+        cpu
+    }
+
+    #[test]
+    fn link_cable_tests() {
+        let master = transfer_program(0x10, 0x42, 0x81);
+        let slave = transfer_program(0x00, 0x99, 0x80);
+        let mut linked = LinkedSystems::new(master, slave);
+
+        // the slave is ready first, then the master clocks 8 bits at 8192 Hz
+        linked.run(4100);
+        assert_eq!(linked.first().mmu().rb(0xFF02), 0xFF);
+        assert_eq!(linked.second().mmu().rb(0xFF02), 0xFE);
+        linked.run(200);
+
+        let (master, slave) = linked.unlink();
+        assert_eq!(master.mmu().rb(0xFF01), 0x99);
+        assert_eq!(slave.mmu().rb(0xFF01), 0x42);
+        for cpu in [&master, &slave].iter() {
+            assert_eq!(cpu.mmu().rb(0xFF02) & 0x80, 0);
+            assert_eq!(cpu.mmu().rb(0xFF0F) & Interrupt::SERIAL, Interrupt::SERIAL);
+        }
+    }
+
+    #[test]
+    fn slave_not_ready_tests() {
+        let master = transfer_program(0x00, 0x42, 0x81);
+        let idle = CPU::default();
+        let mut linked = LinkedSystems::new(master, idle);
+
+        linked.run(4200);
+        assert_eq!(linked.first().mmu().rb(0xFF01), 0xFF);
+        assert_eq!(linked.second().mmu().rb(0xFF01), 0x00);
+        assert_eq!(linked.second().mmu().rb(0xFF0F) & Interrupt::SERIAL, 0);
+    }
+
+    #[test]
+    fn lockstep_tests() {
+        let mut linked = LinkedSystems::new(CPU::default(), CPU::default());
+        for _ in 0..1000 {
+            linked.step();
+            let first = linked.first().cycles() as i64;
+            let second = linked.second().cycles() as i64;
+            assert!((first - second).abs() <= 24);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+mod link;
+mod net;
+mod printer;
+
+pub use link::{link_cable, LinkPort, LinkedSystems};
+pub use net::{LinkMode, LinkStream, NetLink};
+pub use printer::{PrintedImage, Printer};
+
+/// clock cycles per bit shifted with the internal clock, which runs at 8192 Hz
+const CYCLES_PER_BIT: u32 = 512;
+
+/// something plugged into the link port
+pub trait SerialDevice {
+    /// the Game Boy starts a transfer of `data` on its internal clock. Returns the byte the
+    /// device shifts back in during the transfer.
+    fn exchange(&mut self, data: u8) -> u8;
+
+    /// called as time passes with the byte the Game Boy offers while it waits for a transfer on
+    /// the external clock, or `None` when it does not wait. Returns the byte of a transfer the
+    /// device starts by driving the clock.
+    fn external_clock(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let _ = waiting;
+        None
+    }
+
+    /// called as time passes, with the number of clock cycles elapsed
+    fn tick(&mut self, cycles: u32) {
+        let _ = cycles;
+    }
+}
+
+/// the device used when nothing else is plugged in: it records every byte sent, which is how
+/// test roms report their results, and answers like an empty port
+#[derive(Default)]
+pub struct SerialCapture {
+    output: Vec<u8>,
+}
+
+impl SerialCapture {
+    /// every byte sent since the last clear
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// the bytes sent, as text
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    pub fn clear(&mut self) {
+        self.output.clear();
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.output.push(data);
+        0xFF
+    }
+}
+
+/// the serial port registers, 0xFF01 (SB) and 0xFF02 (SC).
+///
+/// Writing SC with bit 7 and bit 0 set starts a transfer on the internal clock: the 8 bits of
+/// SB are shifted out, most significant first, while the bits of the device are shifted in.
+/// Once done bit 7 of SC is cleared and the serial interrupt is requested. On the external clock
+/// the transfer waits for the other side to drive the clock.
+#[derive(Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    /// byte the device sends during the running transfer
+    incoming: u8,
+    /// bits left to shift in the running transfer
+    bits_left: u8,
+    /// clock cycles since the last bit was shifted
+    cycles: u32,
+    interrupt: bool,
+    device: Option<Box<dyn SerialDevice>>,
+    capture: SerialCapture,
+}
+
+impl Serial {
+    /// plug a device into the link port, replacing the capture. Returns the device previously
+    /// plugged in.
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.device.replace(device)
+    }
+
+    /// unplug the device, the bytes sent go to the capture again
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    /// the bytes sent while no device was plugged in
+    pub fn capture(&self) -> &SerialCapture {
+        &self.capture
+    }
+
+    pub fn capture_mut(&mut self) -> &mut SerialCapture {
+        &mut self.capture
+    }
+
+    /// advance the serial port by a number of clock cycles
+    pub fn tick(&mut self, cycles: u32) {
+        self.device().tick(cycles);
+        if self.bits_left == 0 {
+            let waiting = if self.sc & 0x81 == 0x80 {
+                Some(self.sb)
+            } else {
+                None
+            };
+            // the transfer starts with the next bit of the other side
+            if let Some(incoming) = self.device().external_clock(waiting) {
+                self.start_transfer(incoming);
+            }
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_BIT && self.bits_left > 0 {
+            self.cycles -= CYCLES_PER_BIT;
+            self.shift_bit();
+        }
+    }
+
+    /// whether the serial port requested an interrupt since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                self.sc = value & 0x81;
+                if self.sc & 0x81 == 0x81 {
+                    let data = self.sb;
+                    let incoming = self.device().exchange(data);
+                    self.start_transfer(incoming);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn device(&mut self) -> &mut dyn SerialDevice {
+        match &mut self.device {
+            Some(device) => device.as_mut(),
+            None => &mut self.capture,
+        }
+    }
+
+    fn start_transfer(&mut self, incoming: u8) {
+        self.incoming = incoming;
+        self.bits_left = 8;
+        self.cycles = 0;
+    }
+
+    fn shift_bit(&mut self) {
+        self.bits_left -= 1;
+        let bit = (self.incoming >> self.bits_left) & 0x01;
+        self.sb = self.sb << 1 | bit;
+        if self.bits_left == 0 {
+            self.sc &= 0x7F;
+            self.interrupt = true;
+        }
+    }
+}
+
+impl Snapshot for Serial {
+    // the device is not part of the machine, it stays plugged in
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_u8(self.incoming);
+        state.write_u8(self.bits_left);
+        state.write_u32(self.cycles);
+        state.write_bool(self.interrupt);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()? & 0x81;
+        self.incoming = state.read_u8()?;
+        self.bits_left = state.read_u8()?;
+        self.cycles = state.read_u32()?;
+        if self.bits_left > 8 || self.cycles >= CYCLES_PER_BIT {
+            return Err(StateError::InvalidData("serial transfer out of range"));
+        }
+        self.interrupt = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a device answering with a fixed byte and remembering what it received
+    struct Echo {
+        answer: u8,
+        received: Vec<u8>,
+    }
+
+    impl SerialDevice for Echo {
+        fn exchange(&mut self, data: u8) -> u8 {
+            self.received.push(data);
+            self.answer
+        }
+
+        fn external_clock(&mut self, waiting: Option<u8>) -> Option<u8> {
+            let data = waiting?;
+            self.received.push(data);
+            Some(self.answer)
+        }
+    }
+
+    #[test]
+    fn transfer_tests() {
+        let mut serial = Serial::default();
+        serial.wb(0xFF01, 0x42);
+        serial.wb(0xFF02, 0x81);
+        assert_eq!(serial.rb(0xFF02), 0xFF);
+
+        // the bits are shifted at 8192 Hz, the port reads 0xFF when nothing is plugged in
+        serial.tick(512 * 4);
+        assert_eq!(serial.rb(0xFF01), 0x2F);
+        serial.tick(512 * 4 - 4);
+        assert!(!serial.take_interrupt());
+        serial.tick(4);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.rb(0xFF01), 0xFF);
+        assert_eq!(serial.rb(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn capture_tests() {
+        let mut serial = Serial::default();
+        for byte in b"Passed".iter() {
+            serial.wb(0xFF01, *byte);
+            serial.wb(0xFF02, 0x81);
+            serial.tick(512 * 8);
+        }
+
+        assert_eq!(serial.capture().text(), "Passed");
+        serial.capture_mut().clear();
+        assert!(serial.capture().output().is_empty());
+    }
+
+    #[test]
+    fn device_tests() {
+        let mut serial = Serial::default();
+        serial.connect(Box::new(Echo {
+            answer: 0xA5,
+            received: Vec::new(),
+        }));
+        serial.wb(0xFF01, 0x3C);
+        serial.wb(0xFF02, 0x81);
+        serial.tick(512 * 8);
+
+        assert_eq!(serial.rb(0xFF01), 0xA5);
+        assert!(serial.capture().output().is_empty());
+        assert!(serial.disconnect().is_some());
+    }
+
+    #[test]
+    fn external_clock_tests() {
+        let mut serial = Serial::default();
+        serial.wb(0xFF01, 0x42);
+        serial.wb(0xFF02, 0x80);
+
+        // nobody drives the clock, the transfer never ends
+        serial.tick(512 * 100);
+        assert_eq!(serial.rb(0xFF02), 0xFE);
+        assert!(!serial.take_interrupt());
+        assert!(serial.capture().output().is_empty());
+
+        // the device clocks the bits in at its own pace
+        serial.connect(Box::new(Echo {
+            answer: 0xA5,
+            received: Vec::new(),
+        }));
+        serial.tick(4);
+        serial.tick(512 * 8 - 4);
+        assert!(!serial.take_interrupt());
+        serial.tick(4);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.rb(0xFF01), 0xA5);
+        assert_eq!(serial.rb(0xFF02), 0x7E);
+    }
+}
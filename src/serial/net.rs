@@ -0,0 +1,468 @@
+use super::SerialDevice;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+/// clock cycles between two clock messages, in lockstep mode neither side runs further ahead
+/// of the other than this
+const SYNC_INTERVAL: u64 = 1024;
+/// size of a message on the wire: its kind, a data byte and a cycle timestamp
+const MESSAGE_LENGTH: usize = 10;
+/// how long a lockstep link waits on a silent peer before it gives up on the connection
+const DEFAULT_STALL_LIMIT: Duration = Duration::from_secs(5);
+
+/// how the two ends of a networked link keep in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkMode {
+    /// each side stalls whenever it gets ahead of the other, transfers happen at the same cycle
+    /// on both sides as with a real cable
+    Lockstep,
+    /// each side runs at its own pace. Transfers use what is known of the other side when they
+    /// start, and bytes received from a side that is behind are delayed until their timestamp.
+    Relaxed,
+}
+
+/// a connected socket a `NetLink` can run over
+pub trait LinkStream: Read + Write + Send + 'static {
+    /// a second handle to the same socket, the link reads from it on its own thread
+    fn try_clone_stream(&self) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// close both directions of the socket, which unblocks the reading thread
+    fn shutdown_stream(&self);
+
+    /// set the socket up for small and frequent messages
+    fn prepare(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LinkStream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn shutdown_stream(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn prepare(&self) -> io::Result<()> {
+        self.set_nodelay(true)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for std::os::unix::net::UnixStream {
+    fn try_clone_stream(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn shutdown_stream(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    /// the sender reached a cycle
+    Clock,
+    /// the sender waits on the external clock with this byte, or stopped waiting
+    Offer(Option<u8>),
+    /// the sender started a transfer on its internal clock with this byte
+    Transfer(u8),
+}
+
+impl Message {
+    fn encode(self, cycle: u64) -> [u8; MESSAGE_LENGTH] {
+        let (kind, data) = match self {
+            Message::Clock => (0, 0),
+            Message::Offer(None) => (1, 0),
+            Message::Offer(Some(data)) => (2, data),
+            Message::Transfer(data) => (3, data),
+        };
+        let mut bytes = [0; MESSAGE_LENGTH];
+        bytes[0] = kind;
+        bytes[1] = data;
+        bytes[2..].copy_from_slice(&cycle.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; MESSAGE_LENGTH]) -> Option<(Message, u64)> {
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&bytes[2..]);
+        let message = match bytes[0] {
+            0 => Message::Clock,
+            1 => Message::Offer(None),
+            2 => Message::Offer(Some(bytes[1])),
+            3 => Message::Transfer(bytes[1]),
+            _ => return None,
+        };
+        Some((message, u64::from_le_bytes(cycle)))
+    }
+}
+
+/// a link cable to an emulator in another process, over a TCP or Unix socket.
+///
+/// Both sides exchange their serial bytes along with the cycle at which they were sent. If the
+/// connection drops the link behaves like an unplugged cable.
+pub struct NetLink {
+    mode: LinkMode,
+    stream: Box<dyn LinkStream>,
+    messages: Receiver<(Message, u64)>,
+    connected: bool,
+    /// how long to wait on the other side before treating it as gone
+    stall_limit: Duration,
+    /// clock cycles since the link was plugged in
+    cycles: u64,
+    /// last cycle the other side reported
+    peer_cycles: u64,
+    /// when the next clock message is due
+    next_sync: u64,
+    /// byte the other side offers on its external clock, as far as we know
+    peer_offer: Option<u8>,
+    /// byte we last offered to the other side
+    offered: Option<u8>,
+    /// transfers started by the other side, with their timestamp
+    pending: VecDeque<(u64, u8)>,
+}
+
+impl NetLink {
+    /// start a link over a socket connected to the other emulator. Both sides must use the
+    /// same mode.
+    pub fn new<S: LinkStream>(stream: S, mode: LinkMode) -> io::Result<Self> {
+        stream.prepare()?;
+        let mut reader = stream.try_clone_stream()?;
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut bytes = [0; MESSAGE_LENGTH];
+            while reader.read_exact(&mut bytes).is_ok() {
+                match Message::decode(&bytes) {
+                    Some(message) if sender.send(message).is_ok() => {}
+                    _ => break,
+                }
+            }
+            reader.shutdown_stream();
+        });
+        Ok(Self {
+            mode,
+            stream: Box::new(stream),
+            messages,
+            connected: true,
+            stall_limit: DEFAULT_STALL_LIMIT,
+            cycles: 0,
+            peer_cycles: 0,
+            next_sync: SYNC_INTERVAL,
+            peer_offer: None,
+            offered: None,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn mode(&self) -> LinkMode {
+        self.mode
+    }
+
+    /// how long the link waits on the other side in lockstep mode before it unplugs the cable.
+    /// A peer that is paused or hung counts as disconnected after this.
+    pub fn set_stall_limit(&mut self, limit: Duration) {
+        self.stall_limit = limit;
+    }
+
+    /// whether the other side is still there
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, message: Message) {
+        if self.connected && self.stream.write_all(&message.encode(self.cycles)).is_err() {
+            self.connected = false;
+        }
+    }
+
+    /// handle the messages already received
+    fn receive(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.handle(message),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// wait until the other side reported reaching `cycle`, or gave no sign of life for the
+    /// stall limit
+    fn wait_for_peer(&mut self, cycle: u64) {
+        // tell the other side where we are, it may be waiting for us too
+        self.send(Message::Clock);
+        while self.connected && self.peer_cycles < cycle {
+            match self.messages.recv_timeout(self.stall_limit) {
+                Ok(message) => self.handle(message),
+                // a timeout means the other side is paused or hung, either way it is gone
+                Err(_) => self.connected = false,
+            }
+        }
+    }
+
+    fn handle(&mut self, (message, cycle): (Message, u64)) {
+        self.peer_cycles = self.peer_cycles.max(cycle);
+        match message {
+            Message::Clock => {}
+            Message::Offer(offer) => self.peer_offer = offer,
+            Message::Transfer(data) => self.pending.push_back((cycle, data)),
+        }
+    }
+}
+
+impl SerialDevice for NetLink {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.receive();
+        if self.mode == LinkMode::Lockstep {
+            let cycle = self.cycles;
+            self.wait_for_peer(cycle);
+        }
+        match self.peer_offer.take() {
+            Some(answer) if self.connected => {
+                self.send(Message::Transfer(data));
+                answer
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn external_clock(&mut self, waiting: Option<u8>) -> Option<u8> {
+        if waiting != self.offered {
+            self.offered = waiting;
+            self.send(Message::Offer(waiting));
+        }
+        // a transfer from a side ahead of us starts once we reach the cycle it was sent at
+        match self.pending.front() {
+            // the other side only shifts our bits once we wait for it, until then the transfer
+            // stays queued
+            Some(&(cycle, data)) if cycle <= self.cycles && waiting.is_some() => {
+                self.pending.pop_front();
+                self.offered = None;
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+        self.receive();
+        if self.mode == LinkMode::Lockstep && self.connected {
+            if self.cycles >= self.next_sync {
+                self.next_sync += SYNC_INTERVAL;
+                self.send(Message::Clock);
+            }
+            if self.cycles > self.peer_cycles + SYNC_INTERVAL {
+                let cycle = self.cycles - SYNC_INTERVAL;
+                self.wait_for_peer(cycle);
+            }
+        }
+    }
+}
+
+impl Drop for NetLink {
+    fn drop(&mut self) {
+        self.stream.shutdown_stream();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::mmu::Interrupt;
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    /// a system plugged into `link` that runs NOPs up to `start`, writes `data` to SB and
+    /// `control` to SC, then runs NOPs again
+    fn linked_system<D: SerialDevice + 'static>(link: D, start: u16, data: u8, control: u8) -> CPU {
+        let mut cpu = CPU::default();
+        for (offset, byte) in [0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02]
+            .iter()
+            .enumerate()
+        {
+            cpu.mmu_mut().wb(start + offset as u16, *byte);
+        }
+        cpu.mmu_mut().serial_mut().connect(Box::new(link));
+        cpu
+    }
+
+    fn run_until(cpu: &mut CPU, cycles: u64) {
+        while cpu.cycles() < cycles {
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn message_tests() {
+        for message in [
+            Message::Clock,
+            Message::Offer(None),
+            Message::Offer(Some(0x42)),
+            Message::Transfer(0x99),
+        ]
+        .iter()
+        {
+            let bytes = message.encode(0x0123_4567_89AB);
+            assert_eq!(Message::decode(&bytes), Some((*message, 0x0123_4567_89AB)));
+        }
+        assert_eq!(Message::decode(&[4; MESSAGE_LENGTH]), None);
+    }
+
+    #[test]
+    fn lockstep_tests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // the slave is ready long before the master starts, whatever the thread scheduling
+        let slave = thread::spawn(move || {
+            let link = NetLink::new(TcpStream::connect(address).unwrap(), LinkMode::Lockstep);
+            let mut cpu = linked_system(link.unwrap(), 0x00, 0x99, 0x80);
+            run_until(&mut cpu, 20_000);
+            (cpu.mmu().rb(0xFF01), cpu.mmu().rb(0xFF0F))
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let link = NetLink::new(stream, LinkMode::Lockstep).unwrap();
+        let mut master = linked_system(link, 0x800, 0x42, 0x81);
+        run_until(&mut master, 20_000);
+
+        assert_eq!(master.mmu().rb(0xFF01), 0x99);
+        assert_eq!(
+            master.mmu().rb(0xFF0F) & Interrupt::SERIAL,
+            Interrupt::SERIAL
+        );
+        let (slave_data, slave_interrupts) = slave.join().unwrap();
+        assert_eq!(slave_data, 0x42);
+        assert_eq!(slave_interrupts & Interrupt::SERIAL, Interrupt::SERIAL);
+    }
+
+    /// a link the test keeps a handle on while the system uses it
+    struct SharedLink(Rc<RefCell<NetLink>>);
+
+    impl SerialDevice for SharedLink {
+        fn exchange(&mut self, data: u8) -> u8 {
+            self.0.borrow_mut().exchange(data)
+        }
+
+        fn external_clock(&mut self, waiting: Option<u8>) -> Option<u8> {
+            self.0.borrow_mut().external_clock(waiting)
+        }
+
+        fn tick(&mut self, cycles: u32) {
+            self.0.borrow_mut().tick(cycles)
+        }
+    }
+
+    /// block on the messages of `link` until `done` holds, panics if it takes unreasonably long
+    fn wait_for(link: &RefCell<NetLink>, done: impl Fn(&NetLink) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut link = link.borrow_mut();
+        while !done(&link) {
+            let left = deadline
+                .checked_duration_since(Instant::now())
+                .expect("no message from the other side");
+            let message = link.messages.recv_timeout(left).unwrap();
+            link.handle(message);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relaxed_tests() {
+        use std::os::unix::net::UnixStream;
+
+        let (first, second) = UnixStream::pair().unwrap();
+        let master_link = Rc::new(RefCell::new(
+            NetLink::new(first, LinkMode::Relaxed).unwrap(),
+        ));
+        let slave_link = Rc::new(RefCell::new(
+            NetLink::new(second, LinkMode::Relaxed).unwrap(),
+        ));
+        let mut master = linked_system(SharedLink(Rc::clone(&master_link)), 0x20, 0x42, 0x81);
+        let mut slave = linked_system(SharedLink(Rc::clone(&slave_link)), 0x00, 0x99, 0x80);
+
+        // neither side waits for the other, they can run one after the other
+        run_until(&mut slave, 100);
+        wait_for(&master_link, |link| link.peer_offer == Some(0x99));
+        run_until(&mut master, 5_000);
+        assert_eq!(master.mmu().rb(0xFF01), 0x99);
+
+        // the master started its transfer around cycle 170, the slave is further behind: the
+        // transfer is held until it catches up with the timestamp
+        wait_for(&slave_link, |link| !link.pending.is_empty());
+        run_until(&mut slave, 4_000);
+        assert_eq!(slave.mmu().rb(0xFF02), 0xFE);
+        run_until(&mut slave, 4_400);
+        assert_eq!(slave.mmu().rb(0xFF01), 0x42);
+        assert_eq!(slave.mmu().rb(0xFF02), 0x7E);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queued_transfer_tests() {
+        use std::os::unix::net::UnixStream;
+
+        let (first, _second) = UnixStream::pair().unwrap();
+        let mut link = NetLink::new(first, LinkMode::Relaxed).unwrap();
+        link.handle((Message::Transfer(0x42), 100));
+        link.tick(200);
+
+        // the transfer is due but nothing waits on the external clock yet
+        assert_eq!(link.external_clock(None), None);
+        assert_eq!(link.pending.len(), 1);
+
+        assert_eq!(link.external_clock(Some(0x99)), Some(0x42));
+        assert!(link.pending.is_empty());
+        assert_eq!(link.external_clock(Some(0x99)), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disconnect_tests() {
+        use std::os::unix::net::UnixStream;
+
+        let (first, second) = UnixStream::pair().unwrap();
+        let link = NetLink::new(first, LinkMode::Lockstep).unwrap();
+        drop(second);
+
+        // nobody on the other end: the master does not stall and reads an empty port
+        let mut master = linked_system(link, 0x00, 0x42, 0x81);
+        run_until(&mut master, 5_000);
+        assert_eq!(master.mmu().rb(0xFF01), 0xFF);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stall_tests() {
+        use std::os::unix::net::UnixStream;
+
+        // the other end stays open but never says anything, as a hung emulator would
+        let (first, _second) = UnixStream::pair().unwrap();
+        let link = Rc::new(RefCell::new(
+            NetLink::new(first, LinkMode::Lockstep).unwrap(),
+        ));
+        link.borrow_mut().set_stall_limit(Duration::from_millis(50));
+        let mut master = linked_system(SharedLink(Rc::clone(&link)), 0x00, 0x42, 0x81);
+
+        let start = Instant::now();
+        run_until(&mut master, 5_000);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!link.borrow().is_connected());
+        assert_eq!(master.mmu().rb(0xFF01), 0xFF);
+    }
+}
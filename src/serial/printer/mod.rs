@@ -0,0 +1,444 @@
+use super::SerialDevice;
+use crate::cpu::CLOCK_SPEED;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+mod png;
+
+/// bytes every packet starts with
+const MAGIC: [u8; 2] = [0x88, 0x33];
+/// what the printer answers to the first of the two bytes following the checksum
+const DEVICE_ID: u8 = 0x81;
+/// size of the image memory of the printer, 9 packets of 640 bytes make a 160x144 picture
+const BUFFER_SIZE: usize = 9 * 640;
+/// width of the paper, in tiles
+const TILES_PER_LINE: usize = 20;
+/// how long the printer stays busy printing, in clock cycles
+const PRINT_CYCLES: u32 = CLOCK_SPEED / 2;
+
+/// bits of the status byte
+struct Status;
+impl Status {
+    const CHECKSUM_ERROR: u8 = 1 << 0;
+    const PRINTING: u8 = 1 << 1;
+    const IMAGE_DATA_FULL: u8 = 1 << 2;
+    const UNPROCESSED_DATA: u8 = 1 << 3;
+    const PACKET_ERROR: u8 = 1 << 4;
+}
+
+/// commands of the printer protocol
+struct Command;
+impl Command {
+    const INIT: u8 = 0x01;
+    const PRINT: u8 = 0x02;
+    const DATA: u8 = 0x04;
+    const STATUS: u8 = 0x0F;
+}
+
+/// where the printer is in the packet being received
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Receiving {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    /// the two bytes after the checksum, during which the printer answers
+    Acknowledge,
+    Status,
+}
+
+/// a completed print
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    /// shade of every pixel, from 0 (white) to 3 (black), line by line
+    pub pixels: Vec<u8>,
+    /// paper fed before and after the image, in the units of the printer (0-15)
+    pub margin_before: u8,
+    pub margin_after: u8,
+    /// darkness requested by the game, 0x40 being the default
+    pub exposure: u8,
+}
+
+impl PrintedImage {
+    /// one byte per pixel, 0 being black and 255 white
+    pub fn grayscale(&self) -> Vec<u8> {
+        self.pixels.iter().map(|shade| 255 - shade * 85).collect()
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode_grayscale(self.width, self.height, &self.grayscale())
+    }
+
+    pub fn write_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_png())
+    }
+}
+
+/// the Game Boy Printer, plugged into the link port.
+///
+/// Games talk to it with packets: the magic bytes 0x88 0x33, a command, a compression flag, the
+/// length of the data, the data itself, then a checksum of everything from the command on.
+/// The Game Boy sends two more bytes, the printer answers the device id to the first one and its
+/// status to the second one. DATA packets fill the image memory, PRINT prints it with a palette
+/// and margins and hands the image to `on_print`.
+pub struct Printer {
+    on_print: Box<dyn FnMut(PrintedImage)>,
+    receiving: Receiving,
+    command: u8,
+    compression: u8,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    status: u8,
+    /// tile data received since the last print
+    image: Vec<u8>,
+    /// cycles left before the print is done
+    printing: u32,
+}
+
+impl Printer {
+    /// a printer calling `on_print` with every image it prints
+    pub fn new<F: FnMut(PrintedImage) + 'static>(on_print: F) -> Self {
+        Self {
+            on_print: Box::new(on_print),
+            receiving: Receiving::Magic(0),
+            command: 0,
+            compression: 0,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            status: 0,
+            image: Vec::new(),
+            printing: 0,
+        }
+    }
+
+    /// receive a byte of a packet, returns the byte shifted back to the Game Boy
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut answer = 0x00;
+        self.receiving = match self.receiving {
+            Receiving::Magic(index) if byte != MAGIC[index] => {
+                Receiving::Magic((byte == MAGIC[0]) as usize)
+            }
+            Receiving::Magic(0) => Receiving::Magic(1),
+            Receiving::Magic(_) => Receiving::Command,
+            Receiving::Command => {
+                self.command = byte;
+                Receiving::Compression
+            }
+            Receiving::Compression => {
+                self.compression = byte;
+                Receiving::Length(0)
+            }
+            Receiving::Length(0) => {
+                self.length = byte as usize;
+                Receiving::Length(1)
+            }
+            Receiving::Length(_) => {
+                self.length |= (byte as usize) << 8;
+                self.data.clear();
+                if self.length == 0 {
+                    Receiving::Checksum(0)
+                } else {
+                    Receiving::Data
+                }
+            }
+            Receiving::Data => {
+                self.data.push(byte);
+                if self.data.len() == self.length {
+                    Receiving::Checksum(0)
+                } else {
+                    Receiving::Data
+                }
+            }
+            Receiving::Checksum(0) => {
+                self.checksum = byte as u16;
+                Receiving::Checksum(1)
+            }
+            Receiving::Checksum(_) => {
+                self.checksum |= (byte as u16) << 8;
+                Receiving::Acknowledge
+            }
+            Receiving::Acknowledge => {
+                answer = DEVICE_ID;
+                self.run_command();
+                Receiving::Status
+            }
+            Receiving::Status => {
+                answer = self.status;
+                Receiving::Magic(0)
+            }
+        };
+        answer
+    }
+
+    /// sum of the bytes from the command to the end of the data
+    fn computed_checksum(&self) -> u16 {
+        let length = (self.length as u16).to_le_bytes();
+        [self.command, self.compression, length[0], length[1]]
+            .iter()
+            .chain(self.data.iter())
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.computed_checksum() {
+            self.status |= Status::CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !(Status::CHECKSUM_ERROR | Status::PACKET_ERROR);
+        match self.command {
+            Command::INIT => {
+                self.image.clear();
+                self.status = 0;
+                self.printing = 0;
+            }
+            Command::DATA => {
+                let data = if self.compression & 0x01 != 0 {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                let room = BUFFER_SIZE - self.image.len();
+                self.image.extend_from_slice(&data[..data.len().min(room)]);
+                if !self.image.is_empty() {
+                    self.status |= Status::UNPROCESSED_DATA;
+                }
+                if self.image.len() == BUFFER_SIZE {
+                    self.status |= Status::IMAGE_DATA_FULL;
+                }
+            }
+            Command::PRINT if self.data.len() == 4 => self.print(),
+            Command::STATUS => {}
+            _ => self.status |= Status::PACKET_ERROR,
+        }
+    }
+
+    fn print(&mut self) {
+        let sheets = self.data[0];
+        let palette = match self.data[2] {
+            // some games leave the palette out and get the usual one
+            0x00 => 0xE4,
+            palette => palette,
+        };
+        if sheets > 0 {
+            let image = self.render(palette);
+            (self.on_print)(image);
+        }
+        self.image.clear();
+        self.status &= !(Status::UNPROCESSED_DATA | Status::IMAGE_DATA_FULL);
+        self.status |= Status::PRINTING;
+        self.printing = PRINT_CYCLES;
+    }
+
+    /// lay the tiles of the image memory out 20 per line
+    fn render(&self, palette: u8) -> PrintedImage {
+        let tile_lines = self.image.len() / (TILES_PER_LINE * 16);
+        let width = TILES_PER_LINE * 8;
+        let height = tile_lines * 8;
+        let mut pixels = vec![0; width * height];
+        for (index, tile) in self
+            .image
+            .chunks_exact(16)
+            .enumerate()
+            .take(tile_lines * 20)
+        {
+            let (tile_x, tile_y) = (index % TILES_PER_LINE, index / TILES_PER_LINE);
+            for y in 0..8 {
+                let (low, high) = (tile[y * 2], tile[y * 2 + 1]);
+                for x in 0..8 {
+                    let bit = 7 - x;
+                    let color = ((high >> bit) & 0x01) << 1 | ((low >> bit) & 0x01);
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    pixels[(tile_y * 8 + y) * width + tile_x * 8 + x] = shade;
+                }
+            }
+        }
+        PrintedImage {
+            width,
+            height,
+            pixels,
+            margin_before: self.data[1] >> 4,
+            margin_after: self.data[1] & 0x0F,
+            exposure: self.data[3] & 0x7F,
+        }
+    }
+}
+
+/// the run length encoding of DATA packets: a byte with bit 7 clear is followed by that many
+/// plus one literal bytes, a byte with bit 7 set by a single byte repeated its low bits plus two
+/// times
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            if let Some(&byte) = bytes.next() {
+                let count = (control & 0x7F) as usize + 2;
+                output.extend(std::iter::repeat_n(byte, count));
+            }
+        } else {
+            output.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    output
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.receive(data)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.printing > 0 {
+            self.printing = self.printing.saturating_sub(cycles);
+            if self.printing == 0 {
+                self.status &= !Status::PRINTING;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// a printer and the images it printed
+    fn printer() -> (Printer, Rc<RefCell<Vec<PrintedImage>>>) {
+        let prints = Rc::new(RefCell::new(Vec::new()));
+        let output = prints.clone();
+        let printer = Printer::new(move |image| output.borrow_mut().push(image));
+        (printer, prints)
+    }
+
+    /// send a packet, returns the two bytes the printer answered at its end
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut body = vec![command, compressed as u8, length[0], length[1]];
+        body.extend_from_slice(data);
+        let checksum = body.iter().map(|&byte| byte as u16).sum::<u16>();
+
+        let mut packet = MAGIC.to_vec();
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        for byte in packet.iter() {
+            assert_eq!(printer.exchange(*byte), 0x00);
+        }
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    #[test]
+    fn protocol_tests() {
+        let (mut printer, prints) = printer();
+
+        assert_eq!(send(&mut printer, Command::INIT, false, &[]), (0x81, 0x00));
+        assert_eq!(
+            send(&mut printer, Command::STATUS, false, &[]),
+            (0x81, 0x00)
+        );
+        assert_eq!(
+            send(&mut printer, Command::DATA, false, &[0; 640]),
+            (0x81, Status::UNPROCESSED_DATA)
+        );
+
+        // a bad checksum is reported and the packet ignored
+        let mut packet = vec![0x88, 0x33, Command::PRINT, 0, 4, 0, 1, 0, 0xE4, 0x40, 0, 0];
+        packet.push(0);
+        for byte in packet.iter() {
+            printer.exchange(*byte);
+        }
+        assert_eq!(
+            printer.exchange(0x00),
+            Status::CHECKSUM_ERROR | Status::UNPROCESSED_DATA
+        );
+        assert!(prints.borrow().is_empty());
+
+        // an unknown command
+        let (_, status) = send(&mut printer, 0x08, false, &[]);
+        assert_eq!(status, Status::PACKET_ERROR | Status::UNPROCESSED_DATA);
+
+        // garbage before the magic bytes is skipped
+        printer.exchange(0x12);
+        printer.exchange(0x88);
+        assert_eq!(send(&mut printer, Command::INIT, false, &[]), (0x81, 0x00));
+    }
+
+    #[test]
+    fn print_tests() {
+        let (mut printer, prints) = printer();
+        send(&mut printer, Command::INIT, false, &[]);
+
+        // 40 tiles: the first of color 1, the last one of color 3
+        let mut data = vec![0; 640];
+        for row in 0..8 {
+            data[row * 2] = 0xFF;
+            data[624 + row * 2] = 0xFF;
+            data[624 + row * 2 + 1] = 0xFF;
+        }
+        send(&mut printer, Command::DATA, false, &data);
+        send(&mut printer, Command::DATA, false, &[]);
+        let (_, status) = send(&mut printer, Command::PRINT, false, &[1, 0x13, 0x1B, 0x50]);
+        assert_eq!(status, Status::PRINTING);
+
+        let prints = prints.borrow();
+        let image = &prints[0];
+        assert_eq!((image.width, image.height), (160, 16));
+        assert_eq!((image.margin_before, image.margin_after), (1, 3));
+        assert_eq!(image.exposure, 0x50);
+        // palette 0x1B reverses the shades
+        assert_eq!(&image.pixels[..9], &[2, 2, 2, 2, 2, 2, 2, 2, 3]);
+        assert_eq!(image.pixels[15 * 160 + 159], 0);
+        assert_eq!(image.grayscale()[0], 85);
+        assert_eq!(&image.to_png()[1..4], b"PNG");
+    }
+
+    #[test]
+    fn compression_tests() {
+        assert_eq!(
+            decompress(&[0x02, 1, 2, 3, 0x81, 0xAA, 0x00, 4]),
+            vec![1, 2, 3, 0xAA, 0xAA, 0xAA, 4]
+        );
+
+        let (mut printer, prints) = printer();
+        send(&mut printer, Command::INIT, false, &[]);
+        // 320 bytes of 0xFF, a line of 20 black tiles
+        send(
+            &mut printer,
+            Command::DATA,
+            true,
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF],
+        );
+        send(&mut printer, Command::PRINT, false, &[1, 0x00, 0x00, 0x40]);
+
+        let prints = prints.borrow();
+        assert_eq!(prints[0].height, 8);
+        assert!(prints[0].pixels.iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn busy_tests() {
+        let (mut printer, prints) = printer();
+        send(&mut printer, Command::INIT, false, &[]);
+        for _ in 0..9 {
+            send(&mut printer, Command::DATA, false, &[0; 640]);
+        }
+        let (_, status) = send(&mut printer, Command::STATUS, false, &[]);
+        assert_eq!(status, Status::UNPROCESSED_DATA | Status::IMAGE_DATA_FULL);
+
+        send(&mut printer, Command::PRINT, false, &[1, 0, 0xE4, 0x40]);
+        printer.tick(PRINT_CYCLES - 4);
+        let (_, status) = send(&mut printer, Command::STATUS, false, &[]);
+        assert_eq!(status, Status::PRINTING);
+        printer.tick(4);
+        let (_, status) = send(&mut printer, Command::STATUS, false, &[]);
+        assert_eq!(status, 0);
+        assert_eq!(prints.borrow()[0].height, 144);
+    }
+}
@@ -0,0 +1,126 @@
+//! just enough of PNG to write grayscale images, the pixel data is stored without compression
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// largest payload of a deflate stored block
+const STORED_BLOCK_LENGTH: usize = 0xFFFF;
+
+/// encode an image with one byte per pixel, 0 being black and 255 white
+pub fn encode_grayscale(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height);
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per pixel, grayscale, deflate, standard filters, no interlacing
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // every line starts with its filter type, 0 for none
+    let mut lines = Vec::with_capacity((width + 1) * height);
+    for line in pixels.chunks(width.max(1)).take(height) {
+        lines.push(0);
+        lines.extend_from_slice(line);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&lines));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// a zlib stream made of deflate stored blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, no preset dictionary, the check bits make it a multiple of 31
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_LENGTH).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        stream.push(last as u8);
+        let length = block.len() as u16;
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data.iter() {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_tests() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn zlib_tests() {
+        let data = vec![0x5A; 70_000];
+        let stream = zlib_stored(&data);
+
+        assert_eq!(&stream[..2], &[0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([stream[0], stream[1]]) % 31, 0);
+        // a first block of 65535 bytes, then the last one
+        assert_eq!(&stream[2..7], &[0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        assert_eq!(
+            &stream[7 + 0xFFFF..12 + 0xFFFF],
+            &[0x01, 0x71, 0x11, 0x8E, 0xEE]
+        );
+        assert_eq!(stream.len(), 2 + 5 * 2 + 70_000 + 4);
+    }
+
+    #[test]
+    fn png_tests() {
+        let png = encode_grayscale(2, 2, &[0x00, 0xFF, 0x80, 0x40]);
+
+        assert_eq!(&png[..8], &SIGNATURE);
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0DIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 0, 0, 0, 0]);
+        let idat = &png[33..];
+        assert_eq!(&idat[..8], b"\x00\x00\x00\x11IDAT");
+        // the image lines, each after its filter byte, inside a single stored block
+        assert_eq!(&idat[15..21], &[0x00, 0x00, 0xFF, 0x00, 0x80, 0x40]);
+        assert_eq!(
+            &png[png.len() - 12..],
+            b"\x00\x00\x00\x00IEND\xAE\x42\x60\x82"
+        );
+    }
+}
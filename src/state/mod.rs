@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::fmt;
+
+mod rewind;
+
+pub use rewind::Rewind;
+
+/// identifies a save state file
+pub const STATE_MAGIC: [u8; 8] = *b"DMG01SST";
+
+/// bumped every time the layout of a save state changes, states from another version are refused
+pub const STATE_VERSION: u16 = 15;
+
+/// implemented by every piece of hardware whose state ends up in a save state
+pub trait Snapshot {
+    /// append the state of the component
+    fn save(&self, state: &mut StateWriter);
+
+    /// restore the state of the component, reading it in the order it was saved
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    /// the data does not start with the save state magic bytes
+    InvalidMagic,
+    /// the state was saved by an incompatible version of the emulator
+    UnsupportedVersion(u16),
+    /// the state was saved while running another rom
+    RomMismatch {
+        expected_title: String,
+        expected_checksum: u16,
+        found_title: String,
+        found_checksum: u16,
+    },
+    /// the data ends before the whole state could be read
+    UnexpectedEnd,
+    /// a field holds a value the hardware cannot be in
+    InvalidData(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported, expected version {}",
+                version, STATE_VERSION
+            ),
+            StateError::RomMismatch {
+                expected_title,
+                expected_checksum,
+                found_title,
+                found_checksum,
+            } => write!(
+                f,
+                "save state belongs to \"{}\" (checksum {:04X}) but \"{}\" (checksum {:04X}) is running",
+                found_title, found_checksum, expected_title, expected_checksum
+            ),
+            StateError::UnexpectedEnd => write!(f, "save state is truncated"),
+            StateError::InvalidData(what) => write!(f, "save state is corrupted: {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// serializes a save state, all values are stored little endian
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// write a length prefixed block of bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// reads back a save state written by a `StateWriter`
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// whether the whole state has been read
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or(StateError::UnexpectedEnd)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidData("boolean out of range")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// read a length prefixed block of bytes
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// read a length prefixed block of bytes into a buffer of the exact same size
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::InvalidData("memory region size mismatch"));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_tests() {
+        let mut writer = StateWriter::default();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_inner();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        let mut buffer = [0; 3];
+        assert_eq!(reader.read_bytes_into(&mut buffer), Ok(()));
+        assert_eq!(buffer, [1, 2, 3]);
+        assert!(reader.is_empty());
+        assert_eq!(reader.read_u8(), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn invalid_data_tests() {
+        let mut reader = StateReader::new(&[2]);
+        assert!(matches!(
+            reader.read_bool(),
+            Err(StateError::InvalidData(_))
+        ));
+
+        let mut writer = StateWriter::default();
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_inner();
+        let mut buffer = [0; 4];
+        assert!(matches!(
+            StateReader::new(&data).read_bytes_into(&mut buffer),
+            Err(StateError::InvalidData(_))
+        ));
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+
+use super::StateError;
+use crate::cpu::CPU;
+
+/// a history of save states the player can step back through.
+///
+/// Only the most recent state is kept whole. Every older state is stored as the difference with
+/// the state captured right after it: the two states are xor-ed together, which leaves long runs
+/// of zeroes wherever memory did not change, and those runs are then compressed away. Going back
+/// one step xors the newest state with the last difference to rebuild the previous one.
+pub struct Rewind {
+    /// a state is captured every `interval` frames
+    interval: u32,
+    frames_since_capture: u32,
+    /// the most recent state was captured at the end of the last frame, the machine is still in it
+    just_captured: bool,
+    /// maximum number of bytes used to store the history
+    capacity: usize,
+    current: Option<Vec<u8>>,
+    /// oldest difference first
+    deltas: VecDeque<Vec<u8>>,
+    /// bytes used by the differences
+    deltas_size: usize,
+}
+
+impl Rewind {
+    /// keep a state every `interval` frames, using at most `capacity` bytes of memory
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            frames_since_capture: 0,
+            just_captured: false,
+            capacity,
+            current: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    /// to be called once per frame, captures the state of the machine when the interval elapsed
+    pub fn capture(&mut self, cpu: &CPU) {
+        self.frames_since_capture += 1;
+        if self.frames_since_capture >= self.interval {
+            self.frames_since_capture = 0;
+            self.push(cpu.save_state());
+            self.just_captured = true;
+        } else {
+            self.just_captured = false;
+        }
+    }
+
+    /// restore the most recent state in the history and remove it. A state captured at the end
+    /// of the last frame is the one the machine is already in, unless it is the only one left it
+    /// is skipped so that every step goes visibly back. Returns `false` when the history is empty.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result<bool, StateError> {
+        self.frames_since_capture = 0;
+        if self.just_captured && self.len() > 1 {
+            self.pop()?;
+        }
+        self.just_captured = false;
+        match self.pop()? {
+            Some(state) => cpu.load_state(&state).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// add a state at the front of the history
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.just_captured = false;
+        if let Some(previous) = self.current.take() {
+            let delta = compress(&xor(&previous, &state));
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.current = Some(state);
+
+        while self.memory_usage() > self.capacity {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// remove the most recent state from the history. The history is left as it was if the
+    /// state before it cannot be rebuilt.
+    pub fn pop(&mut self) -> Result<Option<Vec<u8>>, StateError> {
+        self.just_captured = false;
+        let previous = match (&self.current, self.deltas.back()) {
+            (Some(state), Some(delta)) => Some(xor(&decompress(delta)?, state)),
+            _ => None,
+        };
+        if let Some(delta) = self.deltas.pop_back() {
+            self.deltas_size -= delta.len();
+        }
+        Ok(std::mem::replace(&mut self.current, previous))
+    }
+
+    /// number of states that can be stepped back through
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.current.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_none()
+    }
+
+    /// bytes currently used by the history
+    pub fn memory_usage(&self) -> usize {
+        self.deltas_size + self.current.as_ref().map_or(0, |state| state.len())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// change the memory budget, dropping the oldest states if the history no longer fits
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.memory_usage() > self.capacity && !self.deltas.is_empty() {
+            if let Some(delta) = self.deltas.pop_front() {
+                self.deltas_size -= delta.len();
+            }
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.frames_since_capture = 0;
+        self.just_captured = false;
+    }
+}
+
+/// xor two states together. The result is as long as `a` so that xor-ing it with `b` gives `a`
+/// back, the shorter state is padded with zeroes.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    (0..a.len())
+        .map(|i| a[i] ^ b.get(i).copied().unwrap_or(0))
+        .collect()
+}
+
+/// encode the data as a sequence of (zero run length, literal length, literal bytes), with the
+/// lengths stored as LEB128 varints
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let zeroes = data[position..].iter().take_while(|&&b| b == 0).count();
+        position += zeroes;
+        // short runs of zeroes are cheaper to keep as literals than to start a new run
+        let literals = data[position..]
+            .windows(2)
+            .position(|pair| pair == [0, 0])
+            .unwrap_or(data.len() - position);
+        write_varint(&mut compressed, zeroes);
+        write_varint(&mut compressed, literals);
+        compressed.extend_from_slice(&data[position..position + literals]);
+        position += literals;
+    }
+    compressed
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut data = Vec::new();
+    let mut position = 0;
+    while position < compressed.len() {
+        let zeroes = read_varint(compressed, &mut position)?;
+        let literals = read_varint(compressed, &mut position)?;
+        let end = position
+            .checked_add(literals)
+            .filter(|&end| end <= compressed.len())
+            .ok_or(StateError::UnexpectedEnd)?;
+        data.resize(data.len() + zeroes, 0);
+        data.extend_from_slice(&compressed[position..end]);
+        position = end;
+    }
+    Ok(data)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> Result<usize, StateError> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *buffer.get(*position).ok_or(StateError::UnexpectedEnd)?;
+        *position += 1;
+        // no state comes anywhere near 32 bits of length, the delta is corrupt
+        if shift >= 32 {
+            return Err(StateError::InvalidData("rewind length out of range"));
+        }
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_tests() {
+        let mut data = vec![0; 1000];
+        data[10] = 1;
+        data[11] = 2;
+        data[500] = 3;
+        data.extend_from_slice(&[4, 0, 5]);
+
+        let compressed = compress(&data);
+
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), Ok(data));
+        assert_eq!(decompress(&compress(&[])), Ok(Vec::new()));
+        assert_eq!(decompress(&compress(&[7])), Ok(vec![7]));
+    }
+
+    #[test]
+    fn corrupt_delta_tests() {
+        let compressed = compress(&[0, 0, 0, 1, 2, 3]);
+
+        // cut in the middle of the literals, or of a length
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 1]),
+            Err(StateError::UnexpectedEnd)
+        );
+        assert_eq!(decompress(&[0x80]), Err(StateError::UnexpectedEnd));
+        assert_eq!(
+            decompress(&[0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]),
+            Err(StateError::InvalidData("rewind length out of range"))
+        );
+
+        // a corrupt delta leaves the history alone
+        let mut rewind = Rewind::new(1, 1 << 20);
+        rewind.push(vec![1, 2, 3, 4]);
+        rewind.push(vec![1, 2, 9, 4]);
+        rewind.deltas[0].pop();
+        assert_eq!(rewind.pop(), Err(StateError::UnexpectedEnd));
+        assert_eq!(rewind.len(), 2);
+    }
+
+    #[test]
+    fn push_pop_tests() {
+        let mut rewind = Rewind::new(1, 1 << 20);
+        rewind.push(vec![1, 2, 3, 4]);
+        rewind.push(vec![1, 2, 9, 4]);
+        rewind.push(vec![5, 2, 9, 4, 8]);
+
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Ok(Some(vec![5, 2, 9, 4, 8])));
+        assert_eq!(rewind.pop(), Ok(Some(vec![1, 2, 9, 4])));
+        assert_eq!(rewind.pop(), Ok(Some(vec![1, 2, 3, 4])));
+        assert_eq!(rewind.pop(), Ok(None));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn capacity_tests() {
+        let mut rewind = Rewind::new(1, 0x1000);
+        for i in 0..100 {
+            let mut state = vec![0; 0x800];
+            state[i] = 0xFF;
+            rewind.push(state);
+        }
+
+        assert!(rewind.memory_usage() <= 0x1000);
+        assert!(rewind.len() > 50);
+
+        rewind.set_capacity(0x900);
+        assert!(rewind.memory_usage() <= 0x900);
+        let mut expected = vec![0; 0x800];
+        expected[99] = 0xFF;
+        assert_eq!(rewind.pop(), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn step_back_tests() {
+        let mut cpu = CPU::default();
+        let mut rewind = Rewind::new(2, 1 << 20);
+
+        for frame in 0..6 {
+            cpu.mmu_mut().wb(0xC000, frame);
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.len(), 3);
+
+        // the state of frame 5 was just captured, the first step goes back to frame 3
+        assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+        assert_eq!(cpu.mmu().rb(0xC000), 3);
+        assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+        assert_eq!(cpu.mmu().rb(0xC000), 1);
+        assert_eq!(rewind.step_back(&mut cpu), Ok(false));
+
+        // once a frame ran past the last capture, that capture is where the first step goes
+        rewind.clear();
+        for frame in 0..7 {
+            cpu.mmu_mut().wb(0xC000, frame);
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+        assert_eq!(cpu.mmu().rb(0xC000), 5);
+        assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+        assert_eq!(cpu.mmu().rb(0xC000), 3);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// the divider and timer registers, 0xFF04-0xFF07.
+///
+/// DIV is the upper byte of a 16 bit counter incremented every clock cycle. TIMA does not have
+/// its own clock: it is incremented whenever the bit of that counter selected by TAC goes from 1
+/// to 0 while the timer is enabled. Resetting DIV or changing TAC can produce such a falling edge
+/// too, which increments TIMA at unexpected times.
+#[derive(Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    /// TIMA overflowed during the last machine cycle. It reads 0x00 for one cycle, then TMA is
+    /// loaded into it and the interrupt is requested.
+    overflow: bool,
+    /// TMA was loaded into TIMA during the last machine cycle, writes to TIMA are ignored
+    reloading: bool,
+    interrupt: bool,
+    /// falling edges of bit 4 of DIV (bit 5 in double speed) not yet passed on to the frame
+    /// sequencer of the APU
+    frame_sequencer_clocks: u8,
+    /// CGB double speed, set by the MMU which saves it
+    double_speed: bool,
+}
+
+impl Timer {
+    /// advance the timer by a number of clock cycles
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            self.reloading = false;
+            if self.overflow {
+                self.overflow = false;
+                self.tima = self.tma;
+                self.reloading = true;
+                self.interrupt = true;
+            }
+
+            let signal = self.signal();
+            let div_bit = self.div_bit();
+            self.counter = self.counter.wrapping_add(4);
+            self.detect_falling_edge(signal);
+            if div_bit && !self.div_bit() {
+                self.frame_sequencer_clocks += 1;
+            }
+        }
+    }
+
+    /// in double speed the timer runs twice as fast, the frame sequencer then follows bit 5 of
+    /// DIV to keep its pace
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    /// whether the timer requested an interrupt since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    /// how many times the frame sequencer of the APU must be clocked since the last call
+    pub fn take_frame_sequencer_clocks(&mut self) -> u8 {
+        std::mem::take(&mut self.frame_sequencer_clocks)
+    }
+
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF04 => {
+                let signal = self.signal();
+                if self.div_bit() {
+                    self.frame_sequencer_clocks += 1;
+                }
+                self.counter = 0;
+                self.detect_falling_edge(signal);
+            }
+            0xFF05 => {
+                // a write on the cycle TMA is reloaded is lost, a write on the cycle before it
+                // cancels the reload and the interrupt altogether
+                if !self.reloading {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                let signal = self.signal();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(signal);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// the counter bit selected by TAC, masked by the timer enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    /// bit 4 of DIV, or bit 5 in double speed, which clocks the frame sequencer of the APU
+    fn div_bit(&self) -> bool {
+        match self.double_speed {
+            true => self.counter & 0x2000 != 0,
+            false => self.counter & 0x1000 != 0,
+        }
+    }
+
+    fn detect_falling_edge(&mut self, previous_signal: bool) {
+        if previous_signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow |= overflow;
+        }
+    }
+}
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_bool(self.overflow);
+        state.write_bool(self.reloading);
+        state.write_bool(self.interrupt);
+        state.write_u8(self.frame_sequencer_clocks);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        self.overflow = state.read_bool()?;
+        self.reloading = state.read_bool()?;
+        self.interrupt = state.read_bool()?;
+        self.frame_sequencer_clocks = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn div_tests() {
+        let mut timer = Timer::default();
+
+        timer.tick(252);
+        assert_eq!(timer.rb(0xFF04), 0x00);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF04), 0x01);
+
+        timer.wb(0xFF04, 0x42);
+        assert_eq!(timer.rb(0xFF04), 0x00);
+    }
+
+    #[test]
+    fn frame_sequencer_tests() {
+        let mut timer = Timer::default();
+        timer.tick(8188);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 0);
+        timer.tick(4);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 1);
+        timer.tick(8192 * 3);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 3);
+
+        // resetting DIV while bit 4 is set clocks the frame sequencer early
+        timer.tick(4096);
+        timer.wb(0xFF04, 0x00);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 1);
+        timer.wb(0xFF04, 0x00);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 0);
+
+        // in double speed it takes twice as many cycles, the same time
+        timer.set_double_speed(true);
+        timer.tick(16380);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 0);
+        timer.tick(4);
+        assert_eq!(timer.take_frame_sequencer_clocks(), 1);
+    }
+
+    #[test]
+    fn tima_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+
+        timer.tick(12);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x01);
+        timer.tick(16 * 9);
+        assert_eq!(timer.rb(0xFF05), 0x0A);
+
+        timer.wb(0xFF07, 0x01);
+        timer.tick(64);
+        assert_eq!(timer.rb(0xFF05), 0x0A);
+    }
+
+    #[test]
+    fn overflow_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0x23);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        timer.tick(16);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        assert!(!timer.take_interrupt());
+
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x23);
+        assert!(timer.take_interrupt());
+        assert!(!timer.take_interrupt());
+    }
+
+    #[test]
+    fn write_during_overflow_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0x23);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        // writing TIMA on the cycle after the overflow cancels the reload
+        timer.tick(16);
+        timer.wb(0xFF05, 0x42);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+        assert!(!timer.take_interrupt());
+    }
+
+    #[test]
+    fn write_during_reload_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0x23);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        // writing TIMA on the reload cycle is ignored, writing TMA goes through to TIMA
+        timer.tick(20);
+        timer.wb(0xFF05, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x23);
+        timer.wb(0xFF06, 0x56);
+        assert_eq!(timer.rb(0xFF05), 0x56);
+
+        timer.tick(4);
+        timer.wb(0xFF05, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn div_reset_increment_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+
+        // bit 3 of the counter is set, resetting it is a falling edge
+        timer.tick(8);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        timer.wb(0xFF04, 0x00);
+        assert_eq!(timer.rb(0xFF05), 0x01);
+
+        // bit 3 is clear, nothing happens
+        timer.tick(4);
+        timer.wb(0xFF04, 0x00);
+        assert_eq!(timer.rb(0xFF05), 0x01);
+    }
+
+    #[test]
+    fn tac_change_increment_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+        timer.tick(8);
+
+        // disabling the timer while the selected bit is set increments TIMA
+        timer.wb(0xFF07, 0x01);
+        assert_eq!(timer.rb(0xFF05), 0x01);
+
+        // so does switching to a frequency whose bit is clear
+        timer.wb(0xFF07, 0x05);
+        timer.wb(0xFF07, 0x06);
+        assert_eq!(timer.rb(0xFF05), 0x02);
+        assert_eq!(timer.rb(0xFF07), 0xFE);
+    }
+
+    // the tests below follow the mooneye-gb acceptance/timer roms one machine cycle at a time
+
+    #[test]
+    fn div_write_tests() {
+        let mut timer = Timer::default();
+        timer.tick(1020);
+        assert_eq!(timer.rb(0xFF04), 0x03);
+
+        // DIV never gets to increment if it is written more often than every 256 cycles
+        for _ in 0..100 {
+            timer.wb(0xFF04, 0x00);
+            timer.tick(252);
+            assert_eq!(timer.rb(0xFF04), 0x00);
+        }
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF04), 0x01);
+    }
+
+    #[test]
+    fn rapid_toggle_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, 0x05);
+
+        // disabling the timer while bit 3 is set increments TIMA, enabling it again before bit 3
+        // falls gets the regular increment as well: each period counts twice
+        for period in 1..=10 {
+            timer.tick(8);
+            timer.wb(0xFF07, 0x01);
+            timer.wb(0xFF07, 0x05);
+            assert_eq!(timer.rb(0xFF05), period * 2 - 1);
+            timer.tick(8);
+            assert_eq!(timer.rb(0xFF05), period * 2);
+        }
+
+        // enabling it while bit 3 is clear does not
+        timer.wb(0xFF07, 0x01);
+        timer.tick(8);
+        timer.wb(0xFF07, 0x05);
+        timer.tick(8);
+        assert_eq!(timer.rb(0xFF05), 21);
+    }
+
+    #[test]
+    fn tim_frequency_tests() {
+        // tim00, tim01, tim10 and tim11: TIMA increments on the exact cycle its period elapses
+        for &(tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)].iter() {
+            let mut timer = Timer::default();
+            timer.wb(0xFF07, tac);
+
+            timer.tick(period - 4);
+            assert_eq!(timer.rb(0xFF05), 0x00, "TAC {:02X}", tac);
+            timer.tick(4);
+            assert_eq!(timer.rb(0xFF05), 0x01, "TAC {:02X}", tac);
+            timer.tick(period * 4 - 4);
+            assert_eq!(timer.rb(0xFF05), 0x04, "TAC {:02X}", tac);
+            timer.tick(4);
+            assert_eq!(timer.rb(0xFF05), 0x05, "TAC {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn tim_div_trigger_tests() {
+        // tim00_div_trigger to tim11_div_trigger: writing DIV increments TIMA only once the
+        // selected bit is set, half way through the period
+        for &(tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)].iter() {
+            let mut timer = Timer::default();
+            timer.wb(0xFF07, tac);
+            timer.tick(period / 2 - 4);
+            timer.wb(0xFF04, 0x00);
+            assert_eq!(timer.rb(0xFF05), 0x00, "TAC {:02X}", tac);
+
+            timer.tick(period / 2);
+            timer.wb(0xFF04, 0x00);
+            assert_eq!(timer.rb(0xFF05), 0x01, "TAC {:02X}", tac);
+
+            // the count starts over from the reset
+            timer.tick(period - 4);
+            assert_eq!(timer.rb(0xFF05), 0x01, "TAC {:02X}", tac);
+            timer.tick(4);
+            assert_eq!(timer.rb(0xFF05), 0x02, "TAC {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn tima_reload_tests() {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0xFE);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        timer.tick(12);
+        assert_eq!(timer.rb(0xFF05), 0xFF);
+        // TIMA reads 0x00 for the machine cycle after the overflow, then TMA
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        assert!(!timer.take_interrupt());
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0xFE);
+        assert!(timer.take_interrupt());
+
+        // the period is not stretched by the reload
+        timer.tick(12);
+        assert_eq!(timer.rb(0xFF05), 0xFF);
+        timer.tick(12);
+        assert_eq!(timer.rb(0xFF05), 0xFF);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0xFE);
+        assert!(timer.take_interrupt());
+    }
+
+    /// a timer with 0x23 in TMA whose TIMA just overflowed and reads 0x00
+    fn overflowing_timer() -> Timer {
+        let mut timer = Timer::default();
+        timer.wb(0xFF06, 0x23);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+        timer.tick(16);
+        assert_eq!(timer.rb(0xFF05), 0x00);
+        timer
+    }
+
+    #[test]
+    fn tma_write_reloading_tests() {
+        // a TMA write on the cycle TIMA reads 0x00 is the value reloaded
+        let mut timer = overflowing_timer();
+        timer.wb(0xFF06, 0x42);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+
+        // on the reload cycle it goes straight through to TIMA
+        let mut timer = overflowing_timer();
+        timer.tick(4);
+        timer.wb(0xFF06, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+
+        // after it, TIMA keeps the old TMA until the next overflow
+        let mut timer = overflowing_timer();
+        timer.tick(8);
+        timer.wb(0xFF06, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x23);
+    }
+
+    #[test]
+    fn tima_write_reloading_tests() {
+        // a TIMA write on the cycle it reads 0x00 cancels the reload and the interrupt
+        let mut timer = overflowing_timer();
+        timer.wb(0xFF05, 0x42);
+        timer.tick(4);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+        assert!(!timer.take_interrupt());
+
+        // on the reload cycle it is lost
+        let mut timer = overflowing_timer();
+        timer.tick(4);
+        timer.wb(0xFF05, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x23);
+        assert!(timer.take_interrupt());
+
+        // after it, it goes through
+        let mut timer = overflowing_timer();
+        timer.tick(8);
+        timer.wb(0xFF05, 0x42);
+        assert_eq!(timer.rb(0xFF05), 0x42);
+    }
+}